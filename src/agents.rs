@@ -1,6 +1,8 @@
-use crate::llm::LLMClient;
+use crate::llm::{GenerationParams, LLMClient};
 use anyhow::Result;
+use futures::FutureExt;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use tokio::task::JoinHandle;
 use uuid::Uuid;
@@ -13,13 +15,28 @@ pub enum AgentStatus {
     Failed(String),
 }
 
+// Where an agent's task leaves its output
+#[derive(Debug)]
+enum AgentOutput {
+    // Set once when the task finishes
+    Final(Arc<Mutex<Option<String>>>),
+    // Grows as deltas arrive; `done` is set when the stream ends
+    #[allow(dead_code)]
+    Streaming {
+        text: Arc<Mutex<String>>,
+        done: Arc<AtomicBool>,
+    },
+}
+
 #[derive(Debug)]
 pub struct Agent {
     pub id: Uuid,
+    #[allow(dead_code)]
     pub name: String,
     pub status: AgentStatus,
-    result: Arc<Mutex<Option<String>>>,
-    handle: Option<JoinHandle<()>>,
+    output: AgentOutput,
+    // Ends with the error message the agent failed with
+    handle: Option<JoinHandle<std::result::Result<(), String>>>,
 }
 
 impl Agent {
@@ -28,13 +45,29 @@ impl Agent {
             id: Uuid::new_v4(),
             name: name.into(),
             status: AgentStatus::Pending,
-            result: Arc::new(Mutex::new(None)),
+            output: AgentOutput::Final(Arc::new(Mutex::new(None))),
             handle: None,
         }
     }
 
+    /// The finished result, or `None` while the agent is still working.
     pub fn result(&self) -> Option<String> {
-        self.result.lock().unwrap().clone()
+        match &self.output {
+            AgentOutput::Final(result) => result.lock().unwrap().clone(),
+            AgentOutput::Streaming { text, done } => done
+                .load(Ordering::Acquire)
+                .then(|| text.lock().unwrap().clone()),
+        }
+    }
+
+    /// The output so far: the text streamed in up to now, or the result
+    /// once a non-streaming agent finishes.
+    #[allow(dead_code)]
+    pub fn partial(&self) -> Option<String> {
+        match &self.output {
+            AgentOutput::Final(result) => result.lock().unwrap().clone(),
+            AgentOutput::Streaming { text, .. } => Some(text.lock().unwrap().clone()),
+        }
     }
 }
 
@@ -59,20 +92,75 @@ impl AgentManager {
         let mut agent = Agent::new(name);
         let agent_id = agent.id;
         agent.status = AgentStatus::Running;
-        let result_handle = agent.result.clone();
+        let result_handle = Arc::new(Mutex::new(None));
+        agent.output = AgentOutput::Final(result_handle.clone());
         let handle = tokio::spawn(async move {
             let resp = client.send_message(&messages, &model).await;
             let mut lock = result_handle.lock().unwrap();
-            *lock = Some(match resp {
-                Ok(r) => r,
-                Err(e) => format!("Error: {}", e),
+            match resp {
+                Ok(r) => {
+                    *lock = Some(r);
+                    Ok(())
+                }
+                Err(e) => {
+                    *lock = Some(format!("Error: {}", e));
+                    Err(e.to_string())
+                }
+            }
+        });
+        agent.handle = Some(handle);
+        self.agents.insert(agent_id, agent);
+        agent_id
+    }
+
+    /// Like `spawn_llm_agent`, but the reply is streamed: `agent_partial`
+    /// returns the text received so far while the agent runs.
+    #[allow(dead_code)]
+    pub fn spawn_streaming_llm_agent(
+        &mut self,
+        name: impl Into<String>,
+        client: Arc<dyn LLMClient>,
+        messages: Vec<crate::models::Message>,
+        model: String,
+    ) -> Uuid {
+        let mut agent = Agent::new(name);
+        let agent_id = agent.id;
+        agent.status = AgentStatus::Running;
+        let text = Arc::new(Mutex::new(String::new()));
+        let done = Arc::new(AtomicBool::new(false));
+        agent.output = AgentOutput::Streaming {
+            text: text.clone(),
+            done: done.clone(),
+        };
+        let handle = tokio::spawn(async move {
+            let deltas = text.clone();
+            let mut on_delta = move |delta: &str| deltas.lock().unwrap().push_str(delta);
+            let resp = client
+                .stream_message_with_params(
+                    &messages,
+                    &model,
+                    &GenerationParams::default(),
+                    &mut on_delta,
+                )
+                .await;
+            let resp = resp.map_err(|e| {
+                let mut text = text.lock().unwrap();
+                if !text.is_empty() {
+                    text.push_str("\n\n");
+                }
+                text.push_str(&format!("Error: {}", e));
+                e.to_string()
             });
+            done.store(true, Ordering::Release);
+            resp.map(|_| ())
         });
         agent.handle = Some(handle);
         self.agents.insert(agent_id, agent);
         agent_id
     }
 
+    /// Run `fut` as an agent. It ends `Failed` with the error's message if
+    /// `fut` returns an error, and `Completed` otherwise.
     pub fn spawn_command_agent<F>(&mut self, name: impl Into<String>, fut: F) -> Uuid
     where
         F: std::future::Future<Output = Result<()>> + Send + 'static,
@@ -80,8 +168,21 @@ impl AgentManager {
         let mut agent = Agent::new(name);
         let id = agent.id;
         agent.status = AgentStatus::Running;
+        let result_handle = Arc::new(Mutex::new(None));
+        agent.output = AgentOutput::Final(result_handle.clone());
         let handle = tokio::spawn(async move {
-            let _ = fut.await;
+            let resp = fut.await;
+            let mut lock = result_handle.lock().unwrap();
+            match resp {
+                Ok(()) => {
+                    *lock = Some(String::new());
+                    Ok(())
+                }
+                Err(e) => {
+                    *lock = Some(format!("Error: {}", e));
+                    Err(e.to_string())
+                }
+            }
         });
         agent.handle = Some(handle);
         self.agents.insert(id, agent);
@@ -90,16 +191,28 @@ impl AgentManager {
 
     pub fn cleanup_finished(&mut self) {
         for agent in self.agents.values_mut() {
-            if let Some(handle) = agent.handle.as_mut() {
-                if handle.is_finished() {
-                    agent.status = AgentStatus::Completed;
-                    // Remove handle to avoid polling again
-                    let _ = agent.handle.take();
-                }
+            if agent.handle.as_ref().is_some_and(|h| h.is_finished()) {
+                // Remove handle to avoid polling again
+                let handle = agent.handle.take().unwrap();
+                agent.status = match handle.now_or_never() {
+                    Some(Ok(Ok(()))) => AgentStatus::Completed,
+                    Some(Ok(Err(e))) => AgentStatus::Failed(e),
+                    Some(Err(e)) => AgentStatus::Failed(e.to_string()),
+                    // Finished tasks are always ready
+                    None => AgentStatus::Completed,
+                };
             }
         }
     }
 
+    /// Number of agents whose task hasn't finished yet.
+    pub fn running_count(&self) -> usize {
+        self.agents
+            .values()
+            .filter(|a| a.handle.as_ref().is_some_and(|h| !h.is_finished()))
+            .count()
+    }
+
     pub fn agent_status(&self, id: &Uuid) -> Option<AgentStatus> {
         self.agents.get(id).map(|a| a.status.clone())
     }
@@ -107,6 +220,12 @@ impl AgentManager {
     pub fn agent_result(&self, id: &Uuid) -> Option<Option<String>> {
         self.agents.get(id).map(|a| a.result())
     }
+
+    /// The output an agent has produced so far; see `Agent::partial`.
+    #[allow(dead_code)]
+    pub fn agent_partial(&self, id: &Uuid) -> Option<String> {
+        self.agents.get(id).and_then(|a| a.partial())
+    }
 }
 
 impl Default for AgentManager {
@@ -118,6 +237,7 @@ impl Default for AgentManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::llm::client::Completion;
 
     struct DummyClient;
     #[async_trait::async_trait]
@@ -134,6 +254,109 @@ mod tests {
         }
     }
 
+    struct DownClient;
+    #[async_trait::async_trait]
+    impl LLMClient for DownClient {
+        async fn send_message(
+            &self,
+            _m: &[crate::models::Message],
+            _model: &str,
+        ) -> Result<String> {
+            Err(anyhow::anyhow!("Service unavailable"))
+        }
+        fn provider(&self) -> crate::models::LLMProvider {
+            crate::models::LLMProvider::OpenAI
+        }
+    }
+
+    // Streams fixed deltas, pausing until the test releases each one
+    struct SteppedClient {
+        deltas: Vec<&'static str>,
+        step: Arc<tokio::sync::Semaphore>,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for SteppedClient {
+        async fn send_message(
+            &self,
+            _m: &[crate::models::Message],
+            _model: &str,
+        ) -> Result<String> {
+            Ok(self.deltas.concat())
+        }
+
+        async fn stream_message_with_params(
+            &self,
+            _m: &[crate::models::Message],
+            _model: &str,
+            _params: &GenerationParams,
+            on_delta: &mut (dyn for<'s> FnMut(&'s str) + Send),
+        ) -> Result<Completion> {
+            for delta in &self.deltas {
+                self.step.acquire().await?.forget();
+                on_delta(delta);
+            }
+            Ok(Completion {
+                text: self.deltas.concat(),
+                truncated: false,
+            })
+        }
+
+        fn provider(&self) -> crate::models::LLMProvider {
+            crate::models::LLMProvider::OpenAI
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streaming_agent_partial_grows() {
+        let step = Arc::new(tokio::sync::Semaphore::new(0));
+        let client = Arc::new(SteppedClient {
+            deltas: vec!["Hel", "lo, ", "world"],
+            step: step.clone(),
+        });
+        let mut mgr = AgentManager::new();
+        let id = mgr.spawn_streaming_llm_agent("stream", client, vec![], "model".into());
+
+        let wait_for = |expected: &'static str| {
+            let mgr = &mgr;
+            async move {
+                for _ in 0..100 {
+                    if mgr.agent_partial(&id).as_deref() == Some(expected) {
+                        return;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+                panic!("partial never became {:?}", expected);
+            }
+        };
+
+        assert_eq!(mgr.agent_partial(&id), Some(String::new()));
+        step.add_permits(1);
+        wait_for("Hel").await;
+        assert_eq!(mgr.agent_result(&id), Some(None));
+        step.add_permits(1);
+        wait_for("Hello, ").await;
+        step.add_permits(1);
+        wait_for("Hello, world").await;
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        mgr.cleanup_finished();
+        assert_eq!(mgr.agent_status(&id), Some(AgentStatus::Completed));
+        assert_eq!(mgr.agent_result(&id), Some(Some("Hello, world".into())));
+
+        let id = mgr.spawn_streaming_llm_agent("down", Arc::new(DownClient), vec![], "m".into());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        mgr.cleanup_finished();
+        assert_eq!(
+            mgr.agent_status(&id),
+            Some(AgentStatus::Failed("Service unavailable".into()))
+        );
+        assert_eq!(
+            mgr.agent_result(&id),
+            Some(Some("Error: Service unavailable".into()))
+        );
+    }
+
     #[tokio::test]
     async fn test_spawn_llm_agent() {
         let mut mgr = AgentManager::new();
@@ -141,8 +364,47 @@ mod tests {
         let id = mgr.spawn_llm_agent("test", client, vec![], "model".into());
         // Allow spawned task to complete
         tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(mgr.running_count(), 0);
         mgr.cleanup_finished();
         assert_eq!(mgr.agent_status(&id), Some(AgentStatus::Completed));
         assert_eq!(mgr.agent_result(&id), Some(Some("ok".into())));
+
+        let id = mgr.spawn_llm_agent("test", Arc::new(DownClient), vec![], "model".into());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        mgr.cleanup_finished();
+        assert_eq!(
+            mgr.agent_status(&id),
+            Some(AgentStatus::Failed("Service unavailable".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_agent_outcomes() {
+        let mut mgr = AgentManager::new();
+        let ok = mgr.spawn_command_agent("true", async { Ok(()) });
+        let failed = mgr.spawn_command_agent("false", async {
+            Err(anyhow::anyhow!("command channel closed"))
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(mgr.running_count(), 0);
+        mgr.cleanup_finished();
+
+        assert_eq!(mgr.agent_status(&ok), Some(AgentStatus::Completed));
+        assert_eq!(mgr.agent_result(&ok), Some(Some(String::new())));
+        assert_eq!(
+            mgr.agent_status(&failed),
+            Some(AgentStatus::Failed("command channel closed".into()))
+        );
+        assert_eq!(
+            mgr.agent_result(&failed),
+            Some(Some("Error: command channel closed".into()))
+        );
+
+        // Cleaning up again keeps the failure
+        mgr.cleanup_finished();
+        assert!(matches!(
+            mgr.agent_status(&failed),
+            Some(AgentStatus::Failed(_))
+        ));
     }
 }
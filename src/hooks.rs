@@ -0,0 +1,58 @@
+use crate::models::{ChatTab, Message};
+
+/// A transform run on every assistant reply once it has been added to a
+/// chat, before it is shown (see `AppState::register_response_hook`).
+pub trait ResponseHook: Send + Sync {
+    /// Change `message`, a reply in `tab`. The tab still holds the reply as
+    /// it was before this and any later hooks ran.
+    fn process(&self, message: &mut Message, tab: &ChatTab);
+}
+
+/// Tidies replies: trailing whitespace and blank lines are trimmed, code
+/// fences lose the space some models put before the language, and a code
+/// block left open is closed.
+pub struct NormalizeReply;
+
+impl ResponseHook for NormalizeReply {
+    fn process(&self, message: &mut Message, _tab: &ChatTab) {
+        message.content = normalize_reply(&message.content);
+    }
+}
+
+pub fn normalize_reply(text: &str) -> String {
+    let mut lines = Vec::new();
+    let mut in_code = false;
+    for line in text.lines() {
+        let line = line.trim_end();
+        match line.trim_start().strip_prefix("```") {
+            Some(tag) => {
+                in_code = !in_code;
+                let indent = &line[..line.len() - line.trim_start().len()];
+                lines.push(format!("{}```{}", indent, tag.trim()));
+            }
+            None => lines.push(line.to_string()),
+        }
+    }
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    if in_code {
+        lines.push("```".to_string());
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_reply() {
+        let reply = "Run this:  \n``` bash\ncargo test   \n```\n\n  ```  \nindented\n  ```\nThen:\n```sh\nls\n\n";
+        assert_eq!(
+            normalize_reply(reply),
+            "Run this:\n```bash\ncargo test\n```\n\n  ```\nindented\n  ```\nThen:\n```sh\nls\n```"
+        );
+        assert_eq!(normalize_reply("Done."), "Done.");
+    }
+}
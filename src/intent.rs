@@ -0,0 +1,341 @@
+/// Shell tasks a chat message can ask for in plain language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intent {
+    ListFiles,
+    CurrentDirectory,
+    CreateDirectory,
+    CreateFile,
+    SystemInfo,
+    CheckInstalled(Tool),
+    GitStatus,
+    GitLog,
+    GitBranches,
+    DiskUsage,
+    ProcessList,
+}
+
+/// Tools whose installation can be checked with `--version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Python,
+    Node,
+    Git,
+    Cargo,
+}
+
+// Words that turn a request around ("don't list the files")
+const NEGATIONS: &[&str] = &[
+    "not",
+    "no",
+    "never",
+    "without",
+    "don't",
+    "dont",
+    "doesn't",
+    "didn't",
+    "can't",
+    "cannot",
+    "won't",
+    "shouldn't",
+    "instead",
+];
+
+// A clause of a message as lowercase words
+struct Clause {
+    words: Vec<String>,
+}
+
+impl Clause {
+    fn has(&self, word: &str) -> bool {
+        self.words.iter().any(|w| w == word)
+    }
+
+    fn has_any(&self, words: &[&str]) -> bool {
+        words.iter().any(|word| self.has(word))
+    }
+
+    // `phrase` as consecutive words
+    fn has_phrase(&self, phrase: &[&str]) -> bool {
+        self.words
+            .windows(phrase.len())
+            .any(|window| window.iter().zip(phrase).all(|(w, p)| w == p))
+    }
+
+    fn is_negated(&self) -> bool {
+        self.has_any(NEGATIONS)
+    }
+
+    fn intent(&self) -> Option<Intent> {
+        const FILES: &[&str] = &["file", "files"];
+        const FOLDERS: &[&str] = &["folder", "folders", "directory", "directories"];
+        const CREATE: &[&str] = &["create", "make"];
+
+        let listing = self.has("list") && (self.has_any(FILES) || self.has_any(FOLDERS))
+            || self.has("what") && (self.has_any(FILES) || self.has_any(FOLDERS))
+            || self.has("show") && (self.has_any(FILES) || self.has_any(&["content", "contents"]));
+        if listing {
+            return Some(Intent::ListFiles);
+        }
+        if self.has("current") && self.has_any(&["directory", "folder"])
+            || self.has_phrase(&["where", "am", "i"])
+            || self.has_phrase(&["working", "directory"])
+        {
+            return Some(Intent::CurrentDirectory);
+        }
+        if self.has_any(CREATE) && self.has_any(FOLDERS) {
+            return Some(Intent::CreateDirectory);
+        }
+        if self.has_any(CREATE) && self.has_any(FILES) {
+            return Some(Intent::CreateFile);
+        }
+        if self.has_any(&["system", "computer"]) && self.has_any(&["info", "information"]) {
+            return Some(Intent::SystemInfo);
+        }
+        if self.has("check") && self.has_any(&["installed", "available"]) {
+            let tool = if self.has("python") {
+                Tool::Python
+            } else if self.has_any(&["node", "nodejs"]) {
+                Tool::Node
+            } else if self.has("git") {
+                Tool::Git
+            } else if self.has_any(&["cargo", "rust"]) {
+                Tool::Cargo
+            } else {
+                return None;
+            };
+            return Some(Intent::CheckInstalled(tool));
+        }
+        if self.has("git") {
+            if self.has("status") {
+                return Some(Intent::GitStatus);
+            } else if self.has_any(&["log", "history", "commits"]) {
+                return Some(Intent::GitLog);
+            } else if self.has_any(&["branch", "branches"]) {
+                return Some(Intent::GitBranches);
+            }
+        }
+        if self.has("disk") && self.has_any(&["space", "usage"]) {
+            return Some(Intent::DiskUsage);
+        }
+        if self.has_any(&["process", "processes"]) && self.has_any(&["list", "running"]) {
+            return Some(Intent::ProcessList);
+        }
+        None
+    }
+}
+
+// Split a message into clauses at punctuation and "but", as lowercase words.
+// Apostrophes stay so "don't" is one word.
+fn clauses(message: &str) -> Vec<Clause> {
+    let mut clauses = Vec::new();
+    for part in message
+        .to_lowercase()
+        .split([',', '.', ';', '!', '?', '\n'])
+    {
+        let words = part
+            .split(|c: char| !(c.is_alphanumeric() || c == '\'' || c == '’'))
+            .filter(|w| !w.is_empty())
+            .map(|w| w.replace('’', "'"));
+        let mut clause = Vec::new();
+        for word in words {
+            if word == "but" {
+                clauses.push(Clause {
+                    words: std::mem::take(&mut clause),
+                });
+            } else {
+                clause.push(word);
+            }
+        }
+        clauses.push(Clause { words: clause });
+    }
+    clauses
+}
+
+/// The shell tasks `message` asks for, in order and without repeats.
+/// Keywords only count as whole words (so "gitignore" isn't a git request),
+/// and clauses with a negation ("I don't want to list files") are ignored.
+pub fn detect_intents(message: &str) -> Vec<Intent> {
+    let mut intents = Vec::new();
+    for clause in clauses(message) {
+        if clause.is_negated() {
+            continue;
+        }
+        if let Some(intent) = clause.intent() {
+            if !intents.contains(&intent) {
+                intents.push(intent);
+            }
+        }
+    }
+    intents
+}
+
+/// Operating systems with their own command catalog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Windows,
+    MacOs,
+    Unix,
+}
+
+impl Platform {
+    pub fn current() -> Self {
+        if cfg!(target_os = "windows") {
+            Platform::Windows
+        } else if cfg!(target_os = "macos") {
+            Platform::MacOs
+        } else {
+            Platform::Unix
+        }
+    }
+}
+
+/// Default instructions for asking a model which commands a plain-language
+/// request needs (see `Settings::translate_intents`).
+pub const INTENT_PROMPT: &str =
+    "Translate the user's request into the exact shell commands that carry it out. \
+Reply with only a JSON object of the form {\"commands\": [\"first command\", \"second command\"]}, \
+in the order they should run. If the request doesn't call for a command, reply {\"commands\": []}.";
+
+#[derive(serde::Deserialize)]
+struct CommandPlan {
+    commands: Vec<String>,
+}
+
+/// The commands in a model's reply to `INTENT_PROMPT`, which may be wrapped
+/// in a code fence or surrounded by prose. `None` when the reply holds no
+/// command plan.
+pub fn parse_command_plan(reply: &str) -> Option<Vec<String>> {
+    let start = reply.find('{')?;
+    let end = reply.rfind('}')?;
+    let plan: CommandPlan = serde_json::from_str(reply.get(start..=end)?).ok()?;
+    Some(
+        plan.commands
+            .iter()
+            .map(|command| command.trim())
+            .filter(|command| !command.is_empty())
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// The shell command carrying out `intent` on `platform`. `name` is the
+/// folder or file to create, and creating one without a name has no command.
+pub fn command_for(intent: Intent, platform: Platform, name: Option<&str>) -> Option<String> {
+    use Platform::*;
+    let command = match (intent, platform) {
+        (Intent::ListFiles, Windows) => "dir".to_string(),
+        (Intent::ListFiles, _) => "ls -la".to_string(),
+        (Intent::CurrentDirectory, Windows) => "cd".to_string(),
+        (Intent::CurrentDirectory, _) => "pwd".to_string(),
+        (Intent::CreateDirectory, _) => format!("mkdir {}", name?),
+        (Intent::CreateFile, Windows) => format!("New-Item -ItemType File -Name {}", name?),
+        (Intent::CreateFile, _) => format!("touch {}", name?),
+        (Intent::SystemInfo, Windows) => {
+            "systeminfo | Select-String 'OS Name', 'OS Version', 'System Type'".to_string()
+        }
+        (Intent::SystemInfo, MacOs) => {
+            "sw_vers && system_profiler SPHardwareDataType".to_string()
+        }
+        (Intent::SystemInfo, Unix) => "uname -a".to_string(),
+        (Intent::CheckInstalled(tool), _) => match tool {
+            Tool::Python => "python --version".to_string(),
+            Tool::Node => "node --version".to_string(),
+            Tool::Git => "git --version".to_string(),
+            Tool::Cargo => "cargo --version".to_string(),
+        },
+        (Intent::GitStatus, _) => "git status".to_string(),
+        (Intent::GitLog, _) => "git log --oneline -10".to_string(),
+        (Intent::GitBranches, _) => "git branch -a".to_string(),
+        (Intent::DiskUsage, Windows) => {
+            "Get-WmiObject -Class Win32_LogicalDisk | Select-Object DeviceID,Size,FreeSpace"
+                .to_string()
+        }
+        (Intent::DiskUsage, _) => "df -h".to_string(),
+        (Intent::ProcessList, Windows) => "Get-Process | Select-Object ProcessName, Id, CPU | Sort-Object CPU -Descending | Select-Object -First 10".to_string(),
+        // BSD `ps` sorts by CPU usage with -r
+        (Intent::ProcessList, MacOs) => "ps aux -r | head -10".to_string(),
+        (Intent::ProcessList, Unix) => "ps aux | head -10".to_string(),
+    };
+    Some(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands_follow_platform() {
+        let system_info = |platform| command_for(Intent::SystemInfo, platform, None).unwrap();
+        let mac = system_info(Platform::MacOs);
+        assert!(mac.starts_with("sw_vers"));
+        assert!(!mac.contains("uname -a") && !mac.contains("systeminfo"));
+        assert_eq!(system_info(Platform::Unix), "uname -a");
+        assert!(system_info(Platform::Windows).starts_with("systeminfo"));
+
+        assert_eq!(
+            command_for(Intent::DiskUsage, Platform::MacOs, None).as_deref(),
+            Some("df -h")
+        );
+        assert_eq!(command_for(Intent::CreateFile, Platform::MacOs, None), None);
+        assert_eq!(
+            command_for(Intent::CreateFile, Platform::MacOs, Some("notes.txt")).as_deref(),
+            Some("touch notes.txt")
+        );
+    }
+
+    #[test]
+    fn test_parse_command_plan() {
+        let reply =
+            "Here you go:\n```json\n{\"commands\": [\"git fetch\", \" git status \", \"\"]}\n```";
+        assert_eq!(
+            parse_command_plan(reply),
+            Some(vec!["git fetch".to_string(), "git status".to_string()])
+        );
+        assert_eq!(parse_command_plan("{\"commands\": []}"), Some(vec![]));
+        assert_eq!(parse_command_plan("Run `git status`."), None);
+        assert_eq!(parse_command_plan("{\"command\": \"ls\"}"), None);
+    }
+
+    #[test]
+    fn test_genuine_requests() {
+        assert_eq!(
+            detect_intents("What files are in this directory?"),
+            vec![Intent::ListFiles]
+        );
+        assert_eq!(
+            detect_intents("Show me the current directory"),
+            vec![Intent::CurrentDirectory]
+        );
+        assert_eq!(
+            detect_intents("Check if Python is installed"),
+            vec![Intent::CheckInstalled(Tool::Python)]
+        );
+        assert_eq!(
+            detect_intents("Show git status, then list running processes"),
+            vec![Intent::GitStatus, Intent::ProcessList]
+        );
+    }
+
+    #[test]
+    fn test_negated_requests_are_ignored() {
+        assert_eq!(
+            detect_intents("I don't want to list files, just explain"),
+            vec![]
+        );
+        assert_eq!(detect_intents("Do not show the file contents"), vec![]);
+        assert_eq!(
+            detect_intents("Don’t list files but show the git log"),
+            vec![Intent::GitLog]
+        );
+    }
+
+    #[test]
+    fn test_substrings_do_not_match() {
+        assert_eq!(detect_intents("Update the gitignore status notes"), vec![]);
+        assert_eq!(detect_intents("What is a profile?"), vec![]);
+        assert_eq!(
+            detect_intents("Explain the diskspace usage formula"),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    NewTab,
+    CloseTab,
+    Quit,
+    ToggleMode,
+    NextTab,
+    PrevTab,
+    CommandPalette,
+    ToggleCodeExecution,
+    ToggleFocusMode,
+}
+
+impl Action {
+    pub const ALL: [Action; 9] = [
+        Action::NewTab,
+        Action::CloseTab,
+        Action::Quit,
+        Action::ToggleMode,
+        Action::NextTab,
+        Action::PrevTab,
+        Action::CommandPalette,
+        Action::ToggleCodeExecution,
+        Action::ToggleFocusMode,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Action::NewTab => "New tab",
+            Action::CloseTab => "Close tab",
+            Action::Quit => "Quit",
+            Action::ToggleMode => "Toggle mode",
+            Action::NextTab => "Next tab",
+            Action::PrevTab => "Previous tab",
+            Action::CommandPalette => "Command palette",
+            Action::ToggleCodeExecution => "Toggle code execution",
+            Action::ToggleFocusMode => "Toggle focus mode",
+        }
+    }
+}
+
+/// A key plus modifiers, written as e.g. `Ctrl+T` or `Shift+Tab` in config.
+/// Single-character keys are stored uppercase so `ctrl+t` and `Ctrl+T` match.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct KeyCombo {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub key: String,
+}
+
+impl KeyCombo {
+    pub fn new(ctrl: bool, alt: bool, shift: bool, key: &str) -> Self {
+        Self {
+            ctrl,
+            alt,
+            shift,
+            key: normalize_key(key),
+        }
+    }
+
+    pub fn has_modifier(&self) -> bool {
+        self.ctrl || self.alt
+    }
+
+    pub fn from_key_event(key: &KeyEvent) -> Option<Self> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        let combo = match key.code {
+            KeyCode::Char(c) => Self::new(ctrl, alt, shift, &c.to_string()),
+            KeyCode::Tab => Self::new(ctrl, alt, shift, "Tab"),
+            KeyCode::BackTab => Self::new(ctrl, alt, true, "Tab"),
+            KeyCode::Enter => Self::new(ctrl, alt, shift, "Enter"),
+            KeyCode::Esc => Self::new(ctrl, alt, shift, "Escape"),
+            KeyCode::F(n) => Self::new(ctrl, alt, shift, &format!("F{}", n)),
+            _ => return None,
+        };
+        Some(combo)
+    }
+}
+
+// Canonical spellings of named keys, matching egui's key names
+const NAMED_KEYS: [&str; 15] = [
+    "Tab",
+    "Enter",
+    "Escape",
+    "Space",
+    "Backspace",
+    "Delete",
+    "Insert",
+    "Home",
+    "End",
+    "PageUp",
+    "PageDown",
+    "Up",
+    "Down",
+    "Left",
+    "Right",
+];
+
+fn normalize_key(key: &str) -> String {
+    if key.chars().count() == 1 {
+        key.to_uppercase()
+    } else if key.eq_ignore_ascii_case("esc") {
+        "Escape".to_string()
+    } else if let Some(name) = NAMED_KEYS.iter().find(|n| n.eq_ignore_ascii_case(key)) {
+        name.to_string()
+    } else {
+        // Function keys and anything else: "f5" -> "F5"
+        key.to_uppercase()
+    }
+}
+
+impl fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.alt {
+            write!(f, "Alt+")?;
+        }
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+impl FromStr for KeyCombo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        // A trailing "++" means the key itself is '+'
+        let (mods, key) = match s.strip_suffix("++") {
+            Some(rest) => (rest, "+"),
+            None => match s.rsplit_once('+') {
+                Some((mods, key)) => (mods, key),
+                None => ("", s),
+            },
+        };
+        if key.trim().is_empty() {
+            return Err(format!("Missing key in \"{}\"", s));
+        }
+
+        let (mut ctrl, mut alt, mut shift) = (false, false, false);
+        for modifier in mods.split('+').filter(|m| !m.trim().is_empty()) {
+            match modifier.trim().to_lowercase().as_str() {
+                "ctrl" | "control" => ctrl = true,
+                "alt" => alt = true,
+                "shift" => shift = true,
+                other => return Err(format!("Unknown modifier \"{}\"", other)),
+            }
+        }
+
+        Ok(Self::new(ctrl, alt, shift, key.trim()))
+    }
+}
+
+impl TryFrom<String> for KeyCombo {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<KeyCombo> for String {
+    fn from(combo: KeyCombo) -> Self {
+        combo.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Keybindings {
+    pub new_tab: KeyCombo,
+    pub close_tab: KeyCombo,
+    pub quit: KeyCombo,
+    pub toggle_mode: KeyCombo,
+    pub next_tab: KeyCombo,
+    pub prev_tab: KeyCombo,
+    pub command_palette: KeyCombo,
+    pub toggle_code_execution: KeyCombo,
+    pub toggle_focus_mode: KeyCombo,
+}
+
+impl Default for Keybindings {
+    fn default() -> Self {
+        Self {
+            new_tab: KeyCombo::new(true, false, false, "T"),
+            close_tab: KeyCombo::new(true, false, false, "W"),
+            quit: KeyCombo::new(true, false, false, "Q"),
+            toggle_mode: KeyCombo::new(true, false, false, ","),
+            next_tab: KeyCombo::new(false, false, false, "Tab"),
+            prev_tab: KeyCombo::new(false, false, true, "Tab"),
+            command_palette: KeyCombo::new(true, false, false, "P"),
+            toggle_code_execution: KeyCombo::new(true, false, false, "E"),
+            toggle_focus_mode: KeyCombo::new(true, false, true, "F"),
+        }
+    }
+}
+
+impl Keybindings {
+    pub fn get(&self, action: Action) -> &KeyCombo {
+        match action {
+            Action::NewTab => &self.new_tab,
+            Action::CloseTab => &self.close_tab,
+            Action::Quit => &self.quit,
+            Action::ToggleMode => &self.toggle_mode,
+            Action::NextTab => &self.next_tab,
+            Action::PrevTab => &self.prev_tab,
+            Action::CommandPalette => &self.command_palette,
+            Action::ToggleCodeExecution => &self.toggle_code_execution,
+            Action::ToggleFocusMode => &self.toggle_focus_mode,
+        }
+    }
+
+    pub fn set(&mut self, action: Action, combo: KeyCombo) {
+        let slot = match action {
+            Action::NewTab => &mut self.new_tab,
+            Action::CloseTab => &mut self.close_tab,
+            Action::Quit => &mut self.quit,
+            Action::ToggleMode => &mut self.toggle_mode,
+            Action::NextTab => &mut self.next_tab,
+            Action::PrevTab => &mut self.prev_tab,
+            Action::CommandPalette => &mut self.command_palette,
+            Action::ToggleCodeExecution => &mut self.toggle_code_execution,
+            Action::ToggleFocusMode => &mut self.toggle_focus_mode,
+        };
+        *slot = combo;
+    }
+
+    pub fn action_for(&self, combo: &KeyCombo) -> Option<Action> {
+        Action::ALL
+            .into_iter()
+            .find(|action| self.get(*action) == combo)
+    }
+
+    /// Pairs of actions bound to the same key combo.
+    pub fn conflicts(&self) -> Vec<(Action, Action)> {
+        let mut conflicts = Vec::new();
+        for (i, a) in Action::ALL.iter().enumerate() {
+            for b in &Action::ALL[i + 1..] {
+                if self.get(*a) == self.get(*b) {
+                    conflicts.push((*a, *b));
+                }
+            }
+        }
+        conflicts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display_roundtrip() {
+        let combo: KeyCombo = "ctrl+shift+tab".parse().unwrap();
+        assert_eq!(combo, KeyCombo::new(true, false, true, "Tab"));
+        assert_eq!(combo.to_string(), "Ctrl+Shift+Tab");
+        assert_eq!("Ctrl++".parse::<KeyCombo>().unwrap().key, "+");
+        assert!("Hyper+T".parse::<KeyCombo>().is_err());
+    }
+
+    #[test]
+    fn test_conflict_detection() {
+        let mut bindings = Keybindings::default();
+        assert!(bindings.conflicts().is_empty());
+
+        bindings.set(Action::Quit, "Ctrl+T".parse().unwrap());
+        assert_eq!(bindings.conflicts(), vec![(Action::NewTab, Action::Quit)]);
+    }
+
+    #[test]
+    fn test_from_key_event_matches_config_form() {
+        let event = KeyEvent::new(KeyCode::Char('t'), KeyModifiers::CONTROL);
+        let combo = KeyCombo::from_key_event(&event).unwrap();
+        assert_eq!(
+            Keybindings::default().action_for(&combo),
+            Some(Action::NewTab)
+        );
+
+        let event = KeyEvent::new(KeyCode::BackTab, KeyModifiers::SHIFT);
+        let combo = KeyCombo::from_key_event(&event).unwrap();
+        assert_eq!(
+            Keybindings::default().action_for(&combo),
+            Some(Action::PrevTab)
+        );
+    }
+}
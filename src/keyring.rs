@@ -0,0 +1,374 @@
+use crate::models::{LLMProvider, Settings};
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::sync::{Mutex, OnceLock};
+
+// Service name the keys are filed under in the platform store
+const SERVICE: &str = "llm-terminal";
+
+/// Somewhere to keep API keys other than the config file.
+pub trait KeyStore {
+    /// The key stored for `provider`, or `None` if there isn't one.
+    fn get_key(&self, provider: &LLMProvider) -> Result<Option<String>>;
+
+    fn set_key(&self, provider: &LLMProvider, key: &str) -> Result<()>;
+
+    /// Remove the key stored for `provider`, if any.
+    fn delete_key(&self, provider: &LLMProvider) -> Result<()>;
+}
+
+// The command-line tools behind `SystemKeyring`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    // `security`, for the macOS Keychain
+    Keychain,
+    // `secret-tool`, for the Secret Service on Linux
+    SecretService,
+    // PowerShell and the Windows Credential Manager's PasswordVault
+    PasswordVault,
+}
+
+// Loads the WinRT PasswordVault into a PowerShell session as `$vault`
+const LOAD_VAULT: &str = "[void][Windows.Security.Credentials.PasswordVault,Windows.Security.Credentials,ContentType=WindowsRuntime]; \
+     $vault = New-Object Windows.Security.Credentials.PasswordVault";
+
+/// The platform's secure store, reached through its command-line tool:
+/// `security` (macOS Keychain), `secret-tool` (Secret Service on Linux) or
+/// PowerShell (Credential Manager on Windows). Keys are passed to the tool
+/// on stdin, never as arguments, and what's stored is remembered so
+/// unchanged keys aren't written again.
+pub struct SystemKeyring;
+
+impl SystemKeyring {
+    // Tool for this platform, if it has one and it is installed (looked up
+    // once, since the settings panel asks every frame)
+    fn backend() -> Option<Backend> {
+        static BACKEND: OnceLock<Option<Backend>> = OnceLock::new();
+        *BACKEND.get_or_init(|| {
+            let (backend, tool) = if cfg!(target_os = "macos") {
+                (Backend::Keychain, "security")
+            } else if cfg!(windows) {
+                (Backend::PasswordVault, "powershell")
+            } else if cfg!(unix) {
+                (Backend::SecretService, "secret-tool")
+            } else {
+                return None;
+            };
+            which::which(tool).ok().map(|_| backend)
+        })
+    }
+
+    pub fn is_available() -> bool {
+        Self::backend().is_some()
+    }
+
+    fn account(provider: &LLMProvider) -> String {
+        provider.as_str().to_lowercase()
+    }
+
+    // What each provider has in the store, as last read or written
+    fn cache() -> &'static Mutex<HashMap<LLMProvider, Option<String>>> {
+        static CACHE: OnceLock<Mutex<HashMap<LLMProvider, Option<String>>>> = OnceLock::new();
+        CACHE.get_or_init(Default::default)
+    }
+
+    fn query(backend: Backend, account: &str) -> Result<Option<String>> {
+        let output = match backend {
+            Backend::Keychain => Command::new("security")
+                .args(["find-generic-password", "-s", SERVICE, "-a", account, "-w"])
+                .output(),
+            Backend::SecretService => Command::new("secret-tool")
+                .args(["lookup", "service", SERVICE, "account", account])
+                .output(),
+            Backend::PasswordVault => powershell(&format!(
+                "{}; try {{ $c = $vault.Retrieve('{}', '{}'); $c.RetrievePassword(); $c.Password }} catch {{}}",
+                LOAD_VAULT, SERVICE, account
+            ))
+            .output(),
+        }
+        .context("Failed to query the keyring")?;
+
+        // The tools exit with an error or print nothing when nothing is stored
+        let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok((output.status.success() && !key.is_empty()).then_some(key))
+    }
+
+    fn store(backend: Backend, provider: &LLMProvider, key: &str) -> Result<Output> {
+        let account = Self::account(provider);
+        match backend {
+            // `security` only takes the secret as an argument, so the command
+            // is given to its interactive mode on stdin instead
+            Backend::Keychain => run_with_input(
+                Command::new("security").arg("-i"),
+                &keychain_add_command(&account, key),
+            ),
+            Backend::SecretService => {
+                let label = format!("{} API key ({})", provider.as_str(), SERVICE);
+                run_with_input(
+                    Command::new("secret-tool")
+                        .args(["store", "--label", &label])
+                        .args(["service", SERVICE, "account", &account]),
+                    key,
+                )
+            }
+            Backend::PasswordVault => run_with_input(
+                &mut powershell(&format!(
+                    "{}; $key = [Console]::In.ReadToEnd(); \
+                     $vault.Add((New-Object Windows.Security.Credentials.PasswordCredential('{}', '{}', $key)))",
+                    LOAD_VAULT, SERVICE, account
+                )),
+                key,
+            ),
+        }
+    }
+
+    fn remove(backend: Backend, account: &str) -> Result<Output> {
+        let output = match backend {
+            Backend::Keychain => Command::new("security")
+                .args(["delete-generic-password", "-s", SERVICE, "-a", account])
+                .output(),
+            Backend::SecretService => Command::new("secret-tool")
+                .args(["clear", "service", SERVICE, "account", account])
+                .output(),
+            Backend::PasswordVault => powershell(&format!(
+                "{}; try {{ $vault.Remove($vault.Retrieve('{}', '{}')) }} catch {{}}",
+                LOAD_VAULT, SERVICE, account
+            ))
+            .output(),
+        };
+        output.context("Failed to remove the key from the keyring")
+    }
+}
+
+fn powershell(script: &str) -> Command {
+    let mut command = Command::new("powershell");
+    command.args(["-NoProfile", "-NonInteractive", "-Command", script]);
+    command
+}
+
+// Run `command` with `input` written to its stdin
+fn run_with_input(command: &mut Command, input: &str) -> Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to write to the keyring")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    Ok(child.wait_with_output()?)
+}
+
+// A line for `security -i` storing `key`, quoted so it stays one argument
+fn keychain_add_command(account: &str, key: &str) -> String {
+    let quoted = key.replace('\\', "\\\\").replace('"', "\\\"");
+    format!(
+        "add-generic-password -U -s {} -a {} -w \"{}\"\n",
+        SERVICE, account, quoted
+    )
+}
+
+impl KeyStore for SystemKeyring {
+    fn get_key(&self, provider: &LLMProvider) -> Result<Option<String>> {
+        if let Some(key) = Self::cache().lock().unwrap().get(provider) {
+            return Ok(key.clone());
+        }
+        let backend = Self::backend().ok_or_else(|| anyhow!("No keyring available"))?;
+        let key = Self::query(backend, &Self::account(provider))?;
+        Self::cache()
+            .lock()
+            .unwrap()
+            .insert(provider.clone(), key.clone());
+        Ok(key)
+    }
+
+    fn set_key(&self, provider: &LLMProvider, key: &str) -> Result<()> {
+        let backend = Self::backend().ok_or_else(|| anyhow!("No keyring available"))?;
+        let output = Self::store(backend, provider, key)?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "The keyring failed to store the key ({})",
+                output.status
+            ));
+        }
+        Self::cache()
+            .lock()
+            .unwrap()
+            .insert(provider.clone(), Some(key.to_string()));
+        Ok(())
+    }
+
+    fn delete_key(&self, provider: &LLMProvider) -> Result<()> {
+        let backend = Self::backend().ok_or_else(|| anyhow!("No keyring available"))?;
+        // The tools fail when there was nothing to remove, which is fine
+        Self::remove(backend, &Self::account(provider))?;
+        Self::cache().lock().unwrap().insert(provider.clone(), None);
+        Ok(())
+    }
+}
+
+const PROVIDERS: [LLMProvider; 3] = [
+    LLMProvider::Claude,
+    LLMProvider::OpenAI,
+    LLMProvider::Custom,
+];
+
+// The API key `settings` holds for `provider`, if any
+fn key_of(settings: &Settings, provider: &LLMProvider) -> Option<String> {
+    match provider {
+        LLMProvider::Claude => settings.claude_api_key.clone(),
+        LLMProvider::OpenAI => settings.openai_api_key.clone(),
+        LLMProvider::Custom => settings
+            .custom_provider
+            .as_ref()
+            .map(|custom| custom.api_key.clone())
+            .filter(|key| !key.is_empty()),
+    }
+}
+
+fn set_key_of(settings: &mut Settings, provider: &LLMProvider, key: Option<String>) {
+    match provider {
+        LLMProvider::Claude => settings.claude_api_key = key,
+        LLMProvider::OpenAI => settings.openai_api_key = key,
+        LLMProvider::Custom => {
+            if let Some(custom) = settings.custom_provider.as_mut() {
+                custom.api_key = key.unwrap_or_default();
+            }
+        }
+    }
+}
+
+/// Fill in API keys from `store`, replacing any from the config file.
+pub fn load_keys(settings: &mut Settings, store: &dyn KeyStore) -> Result<()> {
+    for provider in &PROVIDERS {
+        if let Some(key) = store.get_key(provider)? {
+            set_key_of(settings, provider, Some(key));
+        }
+    }
+    Ok(())
+}
+
+/// Move the API keys in `settings` into `store`, leaving `settings` without
+/// them so they stay out of the config file. Only keys that changed are
+/// written, and a key cleared from `settings` is removed from `store`.
+/// Providers in `external` get their key from elsewhere (the environment),
+/// so what `store` holds for them is left alone.
+pub fn store_keys(
+    settings: &mut Settings,
+    store: &dyn KeyStore,
+    external: &[LLMProvider],
+) -> Result<()> {
+    for provider in PROVIDERS.iter().filter(|p| !external.contains(p)) {
+        let stored = store.get_key(provider)?;
+        match key_of(settings, provider) {
+            Some(key) => {
+                if stored.as_deref() != Some(key.as_str()) {
+                    store.set_key(provider, &key)?;
+                }
+                set_key_of(settings, provider, None);
+            }
+            None if stored.is_some() => store.delete_key(provider)?,
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CustomProviderConfig;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    // Keys by provider, and how many times one was written or removed
+    #[derive(Default)]
+    struct MemoryKeyring(Mutex<HashMap<LLMProvider, String>>, Mutex<usize>);
+
+    impl KeyStore for MemoryKeyring {
+        fn get_key(&self, provider: &LLMProvider) -> Result<Option<String>> {
+            Ok(self.0.lock().unwrap().get(provider).cloned())
+        }
+
+        fn set_key(&self, provider: &LLMProvider, key: &str) -> Result<()> {
+            *self.1.lock().unwrap() += 1;
+            self.0
+                .lock()
+                .unwrap()
+                .insert(provider.clone(), key.to_string());
+            Ok(())
+        }
+
+        fn delete_key(&self, provider: &LLMProvider) -> Result<()> {
+            *self.1.lock().unwrap() += 1;
+            self.0.lock().unwrap().remove(provider);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_store_writes_only_changes() {
+        let store = MemoryKeyring::default();
+        let with_key = |key: Option<&str>| Settings {
+            claude_api_key: key.map(String::from),
+            ..Default::default()
+        };
+        let writes = || *store.1.lock().unwrap();
+
+        store_keys(&mut with_key(Some("sk-ant-1")), &store, &[]).unwrap();
+        store_keys(&mut with_key(Some("sk-ant-1")), &store, &[]).unwrap();
+        assert_eq!(writes(), 1);
+
+        // A key from the environment leaves the stored one be
+        store_keys(&mut with_key(None), &store, &[LLMProvider::Claude]).unwrap();
+        assert_eq!(writes(), 1);
+
+        // Clearing the key removes it, so it doesn't come back on load
+        store_keys(&mut with_key(None), &store, &[]).unwrap();
+        assert_eq!(store.get_key(&LLMProvider::Claude).unwrap(), None);
+        assert_eq!(writes(), 2);
+    }
+
+    #[test]
+    fn test_keychain_command_quotes_key() {
+        assert_eq!(
+            keychain_add_command("claude", "a\"b\\c d"),
+            "add-generic-password -U -s llm-terminal -a claude -w \"a\\\"b\\\\c d\"\n"
+        );
+    }
+
+    #[test]
+    fn test_keys_round_trip_through_store() {
+        let store = MemoryKeyring::default();
+        let mut settings = Settings {
+            claude_api_key: Some("sk-ant-1".to_string()),
+            custom_provider: Some(CustomProviderConfig {
+                name: "Local".to_string(),
+                base_url: "http://localhost:8080/v1".to_string(),
+                api_key: "local-key".to_string(),
+                auth_style: Default::default(),
+                model: "llama3".to_string(),
+            }),
+            ..Default::default()
+        };
+
+        store_keys(&mut settings, &store, &[]).unwrap();
+        assert_eq!(settings.claude_api_key, None);
+        assert_eq!(settings.custom_provider.as_ref().unwrap().api_key, "");
+        assert_eq!(
+            store.get_key(&LLMProvider::Claude).unwrap().as_deref(),
+            Some("sk-ant-1")
+        );
+        assert_eq!(store.get_key(&LLMProvider::OpenAI).unwrap(), None);
+
+        // Keys in the store win over the config file's
+        settings.claude_api_key = Some("stale".to_string());
+        load_keys(&mut settings, &store).unwrap();
+        assert_eq!(settings.claude_api_key.as_deref(), Some("sk-ant-1"));
+        assert_eq!(settings.openai_api_key, None);
+        assert_eq!(settings.custom_provider.unwrap().api_key, "local-key");
+    }
+}
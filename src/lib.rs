@@ -2,7 +2,17 @@ pub mod agents;
 pub mod app;
 pub mod config;
 pub mod context;
+pub mod headless;
+pub mod hooks;
+pub mod intent;
+pub mod keybindings;
+pub mod keyring;
 pub mod llm;
+pub mod markdown;
 pub mod models;
+pub mod palette;
+pub mod redact;
+pub mod sessions;
 pub mod terminal;
+pub mod theme;
 pub mod workflows;
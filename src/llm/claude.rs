@@ -1,12 +1,21 @@
-use super::client::{messages_to_api_format, HttpLLMClient, LLMClient};
+use super::client::{
+    alternate_roles, messages_to_api_format, split_system_messages, Completion, GenerationParams,
+    HttpLLMClient, LLMClient,
+};
+use super::error::{map_request_error, LLMError};
+use super::sse::SseDecoder;
 use crate::models::{LLMProvider, Message};
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use serde_json::json;
 
+const MESSAGES_URL: &str = "https://api.anthropic.com/v1/messages";
+
 pub struct ClaudeClient {
     http_client: HttpLLMClient,
     api_key: String,
+    endpoint: String,
 }
 
 impl ClaudeClient {
@@ -14,9 +23,22 @@ impl ClaudeClient {
         Self {
             http_client: HttpLLMClient::new(),
             api_key,
+            endpoint: MESSAGES_URL.to_string(),
         }
     }
 
+    #[cfg(test)]
+    fn with_endpoint(mut self, endpoint: String) -> Self {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// Use `http_client` (e.g. with custom timeouts or a proxy).
+    pub fn with_http_client(mut self, http_client: HttpLLMClient) -> Self {
+        self.http_client = http_client;
+        self
+    }
+
     fn create_headers(&self) -> Result<HeaderMap> {
         let mut headers = HeaderMap::new();
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
@@ -25,60 +47,203 @@ impl ClaudeClient {
         Ok(headers)
     }
 
-    async fn make_request(&self, messages: &[Message], model: &str) -> Result<String> {
-        let headers = self.create_headers()?;
-        let api_messages = messages_to_api_format(messages);
+    fn request_body(
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> serde_json::Value {
+        let (system, messages) = split_system_messages(messages);
+        let api_messages = messages_to_api_format(&alternate_roles(messages));
 
-        let request_body = json!({
+        let mut request_body = json!({
             "model": model,
             "max_tokens": 4096,
             "messages": api_messages
         });
+        if let Some(system) = system {
+            request_body["system"] = json!(system);
+        }
+        let stop = params.stop_for(&LLMProvider::Claude);
+        if !stop.is_empty() {
+            request_body["stop_sequences"] = json!(stop);
+        }
+        request_body
+    }
 
+    async fn send(&self, request_body: &serde_json::Value) -> Result<reqwest::Response> {
+        let headers = self.create_headers()?;
         let response = self
             .http_client
             .client()
-            .post("https://api.anthropic.com/v1/messages")
+            .post(&self.endpoint)
             .headers(headers)
-            .json(&request_body)
+            .json(request_body)
             .send()
             .await
-            .context("Failed to send request to Claude API")?;
+            .map_err(|e| map_request_error("Claude", e, "Failed to send request to Claude API"))?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow!(
-                "Claude API request failed with status {}: {}",
-                status,
-                error_text
-            ));
+            return Err(self
+                .http_client
+                .api_error(&LLMProvider::Claude, "Claude", request_body, response)
+                .await);
         }
+        Ok(response)
+    }
 
-        let response_json: serde_json::Value = response
-            .json()
+    async fn make_request(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> Result<Completion> {
+        let request_body = Self::request_body(messages, model, params);
+        let response = self.send(&request_body).await?;
+        let status = response.status().as_u16();
+        let text = response
+            .text()
             .await
-            .context("Failed to parse Claude API response")?;
+            .map_err(|e| map_request_error("Claude", e, "Failed to read Claude API response"))?;
+        self.http_client
+            .inspector()
+            .record(&LLMProvider::Claude, &request_body, status, &text);
+        let response_json: serde_json::Value = serde_json::from_str(&text)
+            .map_err(|e| anyhow!("Failed to parse Claude API response: {}", e))?;
 
-        // Extract the content from Claude's response format
-        let content = response_json["content"]
-            .as_array()
-            .and_then(|arr| arr.first())
-            .and_then(|obj| obj["text"].as_str())
+        let content = response_text(&response_json)
             .ok_or_else(|| anyhow!("Invalid response format from Claude API"))?;
 
-        Ok(content.to_string())
+        Ok(Completion {
+            text: content,
+            truncated: response_json["stop_reason"] == "max_tokens",
+        })
+    }
+
+    async fn make_streaming_request(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+        on_delta: &mut (dyn for<'s> FnMut(&'s str) + Send),
+    ) -> Result<Completion> {
+        let mut request_body = Self::request_body(messages, model, params);
+        request_body["stream"] = json!(true);
+        let response = self.send(&request_body).await?;
+
+        // Server-sent events: text arrives in `content_block_delta` events,
+        // `message_delta` gives the stop reason and the reply ends with
+        // `message_stop`. A body that ends or errors before then was cut off.
+        let interrupted = || {
+            anyhow::Error::new(LLMError::StreamInterrupted {
+                provider: "Claude".to_string(),
+            })
+        };
+        let mut body = response.bytes_stream();
+        let mut decoder = SseDecoder::default();
+        let mut content = String::new();
+        let mut truncated = false;
+        while let Some(chunk) = body.next().await {
+            for event in decoder.feed(&chunk.map_err(|_| interrupted())?) {
+                let data: serde_json::Value = serde_json::from_str(&event.data)
+                    .map_err(|e| anyhow!("Invalid stream event from Claude API: {}", e))?;
+                match data["type"].as_str() {
+                    Some("content_block_delta") => {
+                        if let Some(delta) = data["delta"]["text"].as_str() {
+                            content.push_str(delta);
+                            on_delta(delta);
+                        }
+                    }
+                    Some("message_delta") => {
+                        truncated = data["delta"]["stop_reason"] == "max_tokens";
+                    }
+                    Some("message_stop") => {
+                        self.http_client.inspector().record(
+                            &LLMProvider::Claude,
+                            &request_body,
+                            200,
+                            &content,
+                        );
+                        return Ok(Completion {
+                            text: content,
+                            truncated,
+                        });
+                    }
+                    Some("error") => {
+                        self.http_client.inspector().record(
+                            &LLMProvider::Claude,
+                            &request_body,
+                            200,
+                            &event.data,
+                        );
+                        return Err(anyhow!(
+                            "Claude API error during stream: {}",
+                            data["error"]["message"].as_str().unwrap_or("unknown error")
+                        ));
+                    }
+                    // message_start, content_block_start, ping, ...
+                    _ => {}
+                }
+            }
+        }
+        Err(interrupted())
     }
 }
 
+// The reply's text: every `text` block in `content`, in order. Other blocks
+// (such as `tool_use`) are skipped. An empty `content` is an empty reply (so
+// it can be retried); `None` if there are blocks but none is text
+fn response_text(response: &serde_json::Value) -> Option<String> {
+    let blocks = response["content"].as_array()?;
+    let texts: Vec<&str> = blocks
+        .iter()
+        .filter(|block| block["type"] == "text")
+        .filter_map(|block| block["text"].as_str())
+        .collect();
+    (blocks.is_empty() || !texts.is_empty()).then(|| texts.concat())
+}
+
 #[async_trait::async_trait]
 impl LLMClient for ClaudeClient {
     async fn send_message(&self, messages: &[Message], model: &str) -> Result<String> {
+        self.send_message_with_params(messages, model, &GenerationParams::default())
+            .await
+    }
+
+    async fn send_message_with_params(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> Result<String> {
+        self.complete_with_params(messages, model, params)
+            .await
+            .map(|completion| completion.text)
+    }
+
+    async fn complete_with_params(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> Result<Completion> {
         if messages.is_empty() {
             return Err(anyhow!("No messages to send"));
         }
+        self.make_request(messages, model, params).await
+    }
 
-        self.make_request(messages, model).await
+    async fn stream_message_with_params(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+        on_delta: &mut (dyn for<'s> FnMut(&'s str) + Send),
+    ) -> Result<Completion> {
+        if messages.is_empty() {
+            return Err(anyhow!("No messages to send"));
+        }
+        self.make_streaming_request(messages, model, params, on_delta)
+            .await
     }
 
     fn provider(&self) -> LLMProvider {
@@ -89,6 +254,216 @@ impl LLMClient for ClaudeClient {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use wiremock::matchers::{body_partial_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn event_stream(deltas: &[&str], stop: bool) -> ResponseTemplate {
+        let mut events = vec![("message_start", json!({ "type": "message_start" }))];
+        events.extend(deltas.iter().map(|text| {
+            (
+                "content_block_delta",
+                json!({
+                    "type": "content_block_delta",
+                    "index": 0,
+                    "delta": { "type": "text_delta", "text": text }
+                }),
+            )
+        }));
+        if stop {
+            events.push(("message_stop", json!({ "type": "message_stop" })));
+        }
+        let body: String = events
+            .iter()
+            .map(|(name, data)| format!("event: {}\ndata: {}\n\n", name, data))
+            .collect();
+        ResponseTemplate::new(200)
+            .insert_header("content-type", "text/event-stream")
+            .set_body_string(body)
+    }
+
+    #[tokio::test]
+    async fn test_streamed_reply() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "stream": true })))
+            .respond_with(event_stream(&["Hel", "lo"], true))
+            .mount(&server)
+            .await;
+        let client =
+            ClaudeClient::new("test-key".to_string()).with_endpoint(server.uri().to_string());
+        let messages = [Message::user("Hi".to_string())];
+
+        let mut deltas = Vec::new();
+        let reply = client
+            .stream_message_with_params(
+                &messages,
+                "claude-3-5-haiku-20241022",
+                &GenerationParams::default(),
+                &mut |delta| deltas.push(delta.to_string()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply.text, "Hello");
+        assert!(!reply.truncated);
+        assert_eq!(deltas, vec!["Hel", "lo"]);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_reply_at_token_limit_is_truncated() {
+        let body: String = [
+            json!({ "type": "content_block_delta", "index": 0,
+                    "delta": { "type": "text_delta", "text": "Hel" } }),
+            json!({ "type": "message_delta", "delta": { "stop_reason": "max_tokens" } }),
+            json!({ "type": "message_stop" }),
+        ]
+        .iter()
+        .map(|data| {
+            format!(
+                "event: {}\ndata: {}\n\n",
+                data["type"].as_str().unwrap(),
+                data
+            )
+        })
+        .collect();
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", "text/event-stream")
+                    .set_body_string(body),
+            )
+            .mount(&server)
+            .await;
+        let client =
+            ClaudeClient::new("test-key".to_string()).with_endpoint(server.uri().to_string());
+        let messages = [Message::user("Hi".to_string())];
+
+        let reply = client
+            .stream_message_with_params(
+                &messages,
+                "claude-3-5-haiku-20241022",
+                &GenerationParams::default(),
+                &mut |_| {},
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply.text, "Hel");
+        assert!(reply.truncated);
+    }
+
+    #[tokio::test]
+    async fn test_stream_without_stop_is_interrupted() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(event_stream(&["Hel"], false))
+            .mount(&server)
+            .await;
+        let client =
+            ClaudeClient::new("test-key".to_string()).with_endpoint(server.uri().to_string());
+        let messages = [Message::user("Hi".to_string())];
+
+        let err = client
+            .stream_message_with_params(
+                &messages,
+                "claude-3-5-haiku-20241022",
+                &GenerationParams::default(),
+                &mut |_| {},
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LLMError>(),
+            Some(LLMError::StreamInterrupted { .. })
+        ));
+    }
+
+    #[test]
+    fn test_response_text_joins_text_blocks() {
+        let response = json!({
+            "content": [
+                { "type": "text", "text": "First part. " },
+                { "type": "text", "text": "Second part." }
+            ]
+        });
+        assert_eq!(
+            response_text(&response).as_deref(),
+            Some("First part. Second part.")
+        );
+
+        let response = json!({
+            "content": [
+                { "type": "tool_use", "id": "toolu_1", "name": "ls", "input": {} },
+                { "type": "text", "text": "Listing files" }
+            ]
+        });
+        assert_eq!(response_text(&response).as_deref(), Some("Listing files"));
+
+        let response = json!({ "content": [{ "type": "tool_use", "id": "toolu_1" }] });
+        assert_eq!(response_text(&response), None);
+
+        let response = json!({ "content": [], "stop_reason": "end_turn" });
+        assert_eq!(response_text(&response).as_deref(), Some(""));
+    }
+
+    #[tokio::test]
+    async fn test_stop_sequences() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(
+                json!({ "stop_sequences": ["</answer>"] }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "content": [{ "type": "text", "text": "42" }],
+                "stop_reason": "stop_sequence"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client =
+            ClaudeClient::new("test-key".to_string()).with_endpoint(server.uri().to_string());
+        let messages = [Message::user("Hi".to_string())];
+        let params = |stop: &[&str]| GenerationParams {
+            stop: stop.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+
+        let body = ClaudeClient::request_body(&messages, "m", &params(&["</answer>"]));
+        assert!(body.get("stop").is_none());
+        let reply = client
+            .send_message_with_params(&messages, "m", &params(&["</answer>"]))
+            .await
+            .unwrap();
+        assert_eq!(reply, "42");
+
+        // Claude has no limit, so none are dropped
+        let body = ClaudeClient::request_body(&messages, "m", &params(&["x"; 9]));
+        assert_eq!(body["stop_sequences"].as_array().unwrap().len(), 9);
+    }
+
+    #[tokio::test]
+    async fn test_max_tokens_stop_is_truncated() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "content": [{ "type": "text", "text": "The first half" }],
+                "stop_reason": "max_tokens"
+            })))
+            .mount(&server)
+            .await;
+        let client =
+            ClaudeClient::new("test-key".to_string()).with_endpoint(server.uri().to_string());
+
+        let completion = client
+            .complete_with_params(
+                &[Message::user("Hi".to_string())],
+                "claude-3-5-haiku-20241022",
+                &GenerationParams::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(completion.text, "The first half");
+        assert!(completion.truncated);
+    }
 
     #[test]
     fn test_claude_client_creation() {
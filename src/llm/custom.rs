@@ -0,0 +1,275 @@
+use super::client::{
+    messages_to_api_format, parse_chat_completion, Completion, GenerationParams, HttpLLMClient,
+    LLMClient,
+};
+use super::error::map_request_error;
+use crate::models::{AuthStyle, CustomProviderConfig, LLMProvider, Message};
+use anyhow::{anyhow, Result};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use serde_json::json;
+
+/// Client for any endpoint speaking the OpenAI chat-completions protocol
+/// (Mistral, Together, OpenRouter, DeepSeek, local servers, ...).
+pub struct CustomOpenAICompatibleClient {
+    http_client: HttpLLMClient,
+    config: CustomProviderConfig,
+}
+
+impl CustomOpenAICompatibleClient {
+    pub fn new(config: CustomProviderConfig) -> Self {
+        Self {
+            http_client: HttpLLMClient::new(),
+            config,
+        }
+    }
+
+    /// Use `http_client` (e.g. with custom timeouts or a proxy).
+    pub fn with_http_client(mut self, http_client: HttpLLMClient) -> Self {
+        self.http_client = http_client;
+        self
+    }
+
+    fn endpoint(&self) -> String {
+        format!(
+            "{}/chat/completions",
+            self.config.base_url.trim_end_matches('/')
+        )
+    }
+
+    fn create_headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        if !self.config.api_key.is_empty() {
+            match self.config.auth_style {
+                AuthStyle::Bearer => {
+                    headers.insert(
+                        AUTHORIZATION,
+                        HeaderValue::from_str(&format!("Bearer {}", self.config.api_key))?,
+                    );
+                }
+                AuthStyle::ApiKey => {
+                    headers.insert("api-key", HeaderValue::from_str(&self.config.api_key)?);
+                }
+            }
+        }
+        Ok(headers)
+    }
+
+    async fn make_request(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> Result<Completion> {
+        let headers = self.create_headers()?;
+        let api_messages = messages_to_api_format(messages);
+
+        let mut request_body = json!({
+            "model": model,
+            "messages": api_messages,
+            "max_tokens": 4096,
+            "temperature": 0.7
+        });
+        let stop = params.stop_for(&LLMProvider::Custom);
+        if !stop.is_empty() {
+            request_body["stop"] = json!(stop);
+        }
+
+        let response = self
+            .http_client
+            .client()
+            .post(self.endpoint())
+            .headers(headers)
+            .json(&request_body)
+            .send()
+            .await
+            .map_err(|e| {
+                let context = format!("Failed to send request to {}", self.config.name);
+                map_request_error(&self.config.name, e, &context)
+            })?;
+
+        if !response.status().is_success() {
+            return Err(self
+                .http_client
+                .api_error(
+                    &LLMProvider::Custom,
+                    &self.config.name,
+                    &request_body,
+                    response,
+                )
+                .await);
+        }
+
+        let status = response.status().as_u16();
+        let text = response.text().await.map_err(|e| {
+            let context = format!("Failed to read {} API response", self.config.name);
+            map_request_error(&self.config.name, e, &context)
+        })?;
+        self.http_client
+            .inspector()
+            .record(&LLMProvider::Custom, &request_body, status, &text);
+        let response_json: serde_json::Value = serde_json::from_str(&text)
+            .map_err(|e| anyhow!("Failed to parse {} API response: {}", self.config.name, e))?;
+
+        parse_chat_completion(&response_json)
+            .ok_or_else(|| anyhow!("Invalid response format from {} API", self.config.name))
+    }
+}
+
+#[async_trait::async_trait]
+impl LLMClient for CustomOpenAICompatibleClient {
+    async fn send_message(&self, messages: &[Message], model: &str) -> Result<String> {
+        self.send_message_with_params(messages, model, &GenerationParams::default())
+            .await
+    }
+
+    async fn send_message_with_params(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> Result<String> {
+        self.complete_with_params(messages, model, params)
+            .await
+            .map(|completion| completion.text)
+    }
+
+    async fn complete_with_params(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> Result<Completion> {
+        if messages.is_empty() {
+            return Err(anyhow!("No messages to send"));
+        }
+        self.make_request(messages, model, params).await
+    }
+
+    fn provider(&self) -> LLMProvider {
+        LLMProvider::Custom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::LLMError;
+    use crate::models::RequestTimeouts;
+    use std::time::Duration;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn config(base_url: String, auth_style: AuthStyle) -> CustomProviderConfig {
+        CustomProviderConfig {
+            name: "Mistral".into(),
+            base_url,
+            api_key: "secret".into(),
+            auth_style,
+            model: "mistral-small".into(),
+        }
+    }
+
+    fn completion() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{ "message": { "content": "bonjour" } }]
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_posts_to_configured_url_with_bearer() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("authorization", "Bearer secret"))
+            .respond_with(completion())
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = CustomOpenAICompatibleClient::new(config(
+            format!("{}/v1/", server.uri()),
+            AuthStyle::Bearer,
+        ));
+        let reply = client
+            .send_message(&[Message::user("hi".into())], "mistral-small")
+            .await
+            .unwrap();
+        assert_eq!(reply, "bonjour");
+    }
+
+    #[tokio::test]
+    async fn test_api_key_header_style() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(header("api-key", "secret"))
+            .respond_with(completion())
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = CustomOpenAICompatibleClient::new(config(server.uri(), AuthStyle::ApiKey));
+        let headers = client.create_headers().unwrap();
+        assert!(!headers.contains_key(AUTHORIZATION));
+
+        let reply = client
+            .send_message(&[Message::user("hi".into())], "mistral-small")
+            .await
+            .unwrap();
+        assert_eq!(reply, "bonjour");
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_server_is_network_error() {
+        // A port nothing listens on refuses the connection straight away
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = CustomOpenAICompatibleClient::new(config(
+            format!("http://127.0.0.1:{}/v1", port),
+            AuthStyle::Bearer,
+        ));
+
+        let started = std::time::Instant::now();
+        let err = client
+            .send_message(&[Message::user("hi".into())], "mistral-small")
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            err.downcast_ref::<LLMError>(),
+            Some(&LLMError::Network {
+                provider: "Mistral".into()
+            })
+        );
+        assert!(crate::llm::error::is_retriable(&err));
+    }
+
+    #[tokio::test]
+    async fn test_slow_server_times_out() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(completion().set_delay(Duration::from_secs(5)))
+            .mount(&server)
+            .await;
+
+        let client = CustomOpenAICompatibleClient::new(config(server.uri(), AuthStyle::Bearer))
+            .with_http_client(HttpLLMClient::with_timeouts(RequestTimeouts {
+                connect_secs: None,
+                request_secs: 0.2,
+            }));
+        let err = client
+            .send_message(&[Message::user("hi".into())], "mistral-small")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LLMError>(),
+            Some(&LLMError::Timeout {
+                provider: "Mistral".into()
+            })
+        );
+    }
+}
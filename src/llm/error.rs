@@ -0,0 +1,219 @@
+use std::fmt;
+
+/// Failures callers may want to react to specifically. These travel inside
+/// `anyhow::Error`; use `err.downcast_ref::<LLMError>()` to inspect them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LLMError {
+    // The request exceeded the configured connect or total timeout
+    Timeout {
+        provider: String,
+    },
+    // No connection could be made: DNS lookup failed, the connection was
+    // refused or the connect timeout passed. Usually means no network
+    Network {
+        provider: String,
+    },
+    // A streamed reply ended (connection dropped, server closed early)
+    // before the provider signalled the end of the response
+    StreamInterrupted {
+        provider: String,
+    },
+    // The reply was empty or only whitespace, even after asking again
+    EmptyReply {
+        provider: String,
+    },
+    // The API rejected the request; `message` is a readable summary of the
+    // provider's error body
+    Api {
+        provider: String,
+        status: u16,
+        message: String,
+    },
+}
+
+impl fmt::Display for LLMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LLMError::Timeout { provider } => write!(f, "{} request timed out", provider),
+            LLMError::Network { provider } => write!(f, "Couldn't connect to {}", provider),
+            LLMError::StreamInterrupted { provider } => {
+                write!(f, "{} response stream ended unexpectedly", provider)
+            }
+            LLMError::EmptyReply { provider } => write!(f, "{} sent an empty reply", provider),
+            LLMError::Api {
+                provider,
+                status,
+                message,
+            } => write!(f, "{} API error ({}): {}", provider, status, message),
+        }
+    }
+}
+
+impl std::error::Error for LLMError {}
+
+/// Convert a reqwest failure into an `anyhow::Error`, surfacing failures to
+/// connect as `LLMError::Network`, timeouts as `LLMError::Timeout` and
+/// attaching `context` to anything else.
+pub fn map_request_error(provider: &str, err: reqwest::Error, context: &str) -> anyhow::Error {
+    if err.is_connect() {
+        anyhow::Error::new(LLMError::Network {
+            provider: provider.to_string(),
+        })
+    } else if err.is_timeout() {
+        anyhow::Error::new(LLMError::Timeout {
+            provider: provider.to_string(),
+        })
+    } else {
+        anyhow::Error::new(err).context(context.to_string())
+    }
+}
+
+/// Whether `err` looks like a provider outage rather than a problem with the
+/// request: timeouts, dropped connections, empty replies, rate limits and
+/// 5xx responses.
+/// Another provider may well succeed where this one failed.
+pub fn is_retriable(err: &anyhow::Error) -> bool {
+    if let Some(error) = err.downcast_ref::<LLMError>() {
+        return match error {
+            LLMError::Timeout { .. }
+            | LLMError::Network { .. }
+            | LLMError::StreamInterrupted { .. }
+            | LLMError::EmptyReply { .. } => true,
+            LLMError::Api { status, .. } => matches!(status, 408 | 429 | 500..),
+        };
+    }
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|error| error.is_connect() || error.is_timeout() || error.is_request())
+}
+
+/// Turn an unsuccessful API response into `LLMError::Api`, passing its
+/// status and body to `on_body` (see `HttpLLMClient::api_error`).
+pub async fn error_from_response(
+    provider: &str,
+    response: reqwest::Response,
+    on_body: impl FnOnce(u16, &str),
+) -> anyhow::Error {
+    let status = response.status().as_u16();
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let body = response.text().await.unwrap_or_default();
+    on_body(status, &body);
+
+    anyhow::Error::new(LLMError::Api {
+        provider: provider.to_string(),
+        status,
+        message: describe_error_body(status, &body, retry_after),
+    })
+}
+
+/// A readable message for an API error body. OpenAI sends
+/// `{"error": {"message", "type", "code"}}` and Claude sends
+/// `{"type": "error", "error": {"type", "message"}}`; common error types
+/// get a short explanation, others use the provider's message. Bodies that
+/// aren't either shape are returned as-is.
+pub fn describe_error_body(status: u16, body: &str, retry_after: Option<u64>) -> String {
+    let parsed: Option<serde_json::Value> = serde_json::from_str(body).ok();
+    let Some(error) = parsed
+        .as_ref()
+        .map(|json| &json["error"])
+        .filter(|e| e.is_object())
+    else {
+        return match body.trim() {
+            "" => format!("request failed with status {}", status),
+            text => text.to_string(),
+        };
+    };
+
+    let message = error["message"].as_str().unwrap_or_default().trim();
+    let kind = error["code"]
+        .as_str()
+        .filter(|code| !code.is_empty())
+        .or_else(|| error["type"].as_str())
+        .unwrap_or_default();
+
+    let rate_limited = || match retry_after {
+        Some(secs) => format!("Rate limit exceeded, retry in {}s", secs),
+        None => "Rate limit exceeded, retry shortly".to_string(),
+    };
+    match kind {
+        "invalid_api_key" | "authentication_error" => "Invalid API key".to_string(),
+        "permission_error" => "This API key doesn't have access to this resource".to_string(),
+        "insufficient_quota" => "Quota exceeded; check your plan and billing details".to_string(),
+        "rate_limit_exceeded" | "rate_limit_error" => rate_limited(),
+        "overloaded_error" => "The API is overloaded, try again shortly".to_string(),
+        "model_not_found" | "not_found_error" if !message.is_empty() => {
+            format!("Not found: {}", message)
+        }
+        _ if status == 429 => rate_limited(),
+        _ if !message.is_empty() => message.to_string(),
+        _ => body.trim().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openai_error_bodies() {
+        let invalid_key = r#"{"error": {"message": "Incorrect API key provided: sk-abc***.", "type": "invalid_request_error", "param": null, "code": "invalid_api_key"}}"#;
+        assert_eq!(
+            describe_error_body(401, invalid_key, None),
+            "Invalid API key"
+        );
+
+        let rate_limit = r#"{"error": {"message": "Rate limit reached for gpt-4o.", "type": "requests", "code": "rate_limit_exceeded"}}"#;
+        assert_eq!(
+            describe_error_body(429, rate_limit, Some(20)),
+            "Rate limit exceeded, retry in 20s"
+        );
+
+        let bad_request = r#"{"error": {"message": "'messages' must not be empty.", "type": "invalid_request_error", "code": null}}"#;
+        assert_eq!(
+            describe_error_body(400, bad_request, None),
+            "'messages' must not be empty."
+        );
+    }
+
+    #[test]
+    fn test_claude_error_bodies() {
+        let auth = r#"{"type": "error", "error": {"type": "authentication_error", "message": "invalid x-api-key"}}"#;
+        assert_eq!(describe_error_body(401, auth, None), "Invalid API key");
+
+        let overloaded =
+            r#"{"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}"#;
+        assert_eq!(
+            describe_error_body(529, overloaded, None),
+            "The API is overloaded, try again shortly"
+        );
+
+        let rate_limit = r#"{"type": "error", "error": {"type": "rate_limit_error", "message": "Number of requests has exceeded your rate limit"}}"#;
+        assert_eq!(
+            describe_error_body(429, rate_limit, None),
+            "Rate limit exceeded, retry shortly"
+        );
+    }
+
+    #[test]
+    fn test_unparseable_error_body_falls_back_to_text() {
+        assert_eq!(
+            describe_error_body(502, "<html>Bad Gateway</html>", None),
+            "<html>Bad Gateway</html>"
+        );
+        assert_eq!(
+            describe_error_body(500, "", None),
+            "request failed with status 500"
+        );
+
+        let err = LLMError::Api {
+            provider: "OpenAI".into(),
+            status: 401,
+            message: "Invalid API key".into(),
+        };
+        assert_eq!(err.to_string(), "OpenAI API error (401): Invalid API key");
+    }
+}
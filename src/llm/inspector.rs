@@ -0,0 +1,142 @@
+use crate::models::LLMProvider;
+use crate::redact::redact_keys;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// A request body sent to a provider and the body that came back, both
+/// pretty-printed and with anything shaped like a key masked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub id: Uuid,
+    pub at: DateTime<Utc>,
+    pub status: u16,
+    pub request: String,
+    pub response: String,
+}
+
+/// The last few exchanges with each provider, for the Inspector panel.
+/// Records nothing until enabled. Clones share the same buffer, so the
+/// clients and the UI can each hold one.
+#[derive(Debug, Clone, Default)]
+pub struct Inspector {
+    state: Arc<Mutex<InspectorState>>,
+}
+
+#[derive(Debug, Default)]
+struct InspectorState {
+    enabled: bool,
+    capacity: usize,
+    exchanges: HashMap<LLMProvider, VecDeque<Exchange>>,
+}
+
+impl Inspector {
+    /// Turn recording on or off, keeping at most `capacity` exchanges per
+    /// provider. Turning it off drops what was recorded.
+    pub fn configure(&self, enabled: bool, capacity: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.enabled = enabled;
+        state.capacity = capacity;
+        if !enabled {
+            state.exchanges.clear();
+        }
+        for exchanges in state.exchanges.values_mut() {
+            while exchanges.len() > capacity {
+                exchanges.pop_front();
+            }
+        }
+    }
+
+    /// Record a request to `provider` and its response, successful or not.
+    /// Only bodies are recorded: headers, and with them the API key, never
+    /// are.
+    pub fn record(
+        &self,
+        provider: &LLMProvider,
+        request: &serde_json::Value,
+        status: u16,
+        response: &str,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if !state.enabled || state.capacity == 0 {
+            return;
+        }
+        let request = serde_json::to_string_pretty(request).unwrap_or_default();
+        // Streamed replies are recorded as their text
+        let response = serde_json::from_str::<serde_json::Value>(response)
+            .and_then(|json| serde_json::to_string_pretty(&json))
+            .unwrap_or_else(|_| response.to_string());
+        let exchange = Exchange {
+            id: Uuid::new_v4(),
+            at: Utc::now(),
+            status,
+            request: redact_keys(&request).unwrap_or(request),
+            response: redact_keys(&response).unwrap_or(response),
+        };
+
+        let capacity = state.capacity;
+        let exchanges = state.exchanges.entry(provider.clone()).or_default();
+        exchanges.push_back(exchange);
+        while exchanges.len() > capacity {
+            exchanges.pop_front();
+        }
+    }
+
+    /// `provider`'s recorded exchanges, oldest first.
+    pub fn exchanges(&self, provider: &LLMProvider) -> Vec<Exchange> {
+        let state = self.state.lock().unwrap();
+        state
+            .exchanges
+            .get(provider)
+            .map(|exchanges| exchanges.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn clear(&self) {
+        self.state.lock().unwrap().exchanges.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(content: &str) -> serde_json::Value {
+        json!({ "model": "gpt-4o", "messages": [{ "role": "user", "content": content }] })
+    }
+
+    #[test]
+    fn test_records_redacted_exchanges_when_enabled() {
+        let inspector = Inspector::default();
+        inspector.record(&LLMProvider::OpenAI, &request("Hi"), 200, "{}");
+        assert!(inspector.exchanges(&LLMProvider::OpenAI).is_empty());
+
+        inspector.configure(true, 2);
+        let key = "sk-proj-Abc123def456GHI789jkl";
+        inspector.record(
+            &LLMProvider::OpenAI,
+            &request(&format!("Why is {} rejected?", key)),
+            200,
+            r#"{"choices":[{"message":{"content":"It was revoked"}}]}"#,
+        );
+        let exchanges = inspector.exchanges(&LLMProvider::OpenAI);
+        assert_eq!(exchanges.len(), 1);
+        assert!(!exchanges[0].request.contains(key));
+        assert!(exchanges[0].request.contains("Why is *** rejected?"));
+        // Pretty-printed
+        assert!(exchanges[0].response.contains("\n"));
+        assert!(inspector.exchanges(&LLMProvider::Claude).is_empty());
+
+        for n in 0..3 {
+            inspector.record(&LLMProvider::OpenAI, &request(&n.to_string()), 200, "ok");
+        }
+        let exchanges = inspector.exchanges(&LLMProvider::OpenAI);
+        assert_eq!(exchanges.len(), 2);
+        assert!(exchanges[0].request.contains("\"1\""));
+
+        inspector.configure(false, 2);
+        assert!(inspector.exchanges(&LLMProvider::OpenAI).is_empty());
+    }
+}
@@ -1,7 +1,18 @@
 pub mod client;
 pub mod claude;
+pub mod custom;
+pub mod error;
+pub mod inspector;
 pub mod openai;
+pub mod registry;
+pub mod sse;
+pub mod stream;
 
-pub use client::LLMClient;
+pub use client::{GenerationParams, LLMClient, ResponseFormat};
 pub use claude::ClaudeClient;
+pub use custom::CustomOpenAICompatibleClient;
+pub use error::LLMError;
+pub use inspector::Inspector;
 pub use openai::OpenAIClient;
+pub use registry::ClientRegistry;
+pub use stream::DeltaBuffer;
@@ -1,12 +1,25 @@
-use super::client::{messages_to_api_format, HttpLLMClient, LLMClient};
-use crate::models::{LLMProvider, Message};
-use anyhow::{anyhow, Context, Result};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use super::client::{
+    messages_to_api_format, parse_chat_completion, Completion, GenerationParams, HttpLLMClient,
+    LLMClient, ResponseFormat,
+};
+use super::error::{map_request_error, LLMError};
+use super::sse::SseDecoder;
+use crate::models::{LLMProvider, Message, ModelInfo};
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use serde_json::json;
 
+const CHAT_COMPLETIONS_URL: &str = "https://api.openai.com/v1/chat/completions";
+
 pub struct OpenAIClient {
     http_client: HttpLLMClient,
     api_key: String,
+    endpoint: String,
+    // Sent as `OpenAI-Organization` and `OpenAI-Project`, for keys that
+    // belong to several organizations or projects
+    organization: Option<String>,
+    project: Option<String>,
 }
 
 impl OpenAIClient {
@@ -14,9 +27,37 @@ impl OpenAIClient {
         Self {
             http_client: HttpLLMClient::new(),
             api_key,
+            endpoint: CHAT_COMPLETIONS_URL.to_string(),
+            organization: None,
+            project: None,
         }
     }
 
+    #[cfg(test)]
+    fn with_endpoint(mut self, endpoint: String) -> Self {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// Use `http_client` (e.g. with custom timeouts or a proxy).
+    pub fn with_http_client(mut self, http_client: HttpLLMClient) -> Self {
+        self.http_client = http_client;
+        self
+    }
+
+    /// Bill and route requests to `organization` and `project`. Blank
+    /// values are left out.
+    pub fn with_organization(
+        mut self,
+        organization: Option<String>,
+        project: Option<String>,
+    ) -> Self {
+        let present = |value: Option<String>| value.filter(|v| !v.trim().is_empty());
+        self.organization = present(organization);
+        self.project = present(project);
+        self
+    }
+
     fn create_headers(&self) -> Result<HeaderMap> {
         let mut headers = HeaderMap::new();
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
@@ -24,64 +65,223 @@ impl OpenAIClient {
             AUTHORIZATION,
             HeaderValue::from_str(&format!("Bearer {}", self.api_key))?,
         );
+        for (name, value) in [
+            ("openai-organization", &self.organization),
+            ("openai-project", &self.project),
+        ] {
+            if let Some(value) = value {
+                headers.insert(
+                    HeaderName::from_static(name),
+                    HeaderValue::from_str(value.trim())?,
+                );
+            }
+        }
         Ok(headers)
     }
 
-    async fn make_request(&self, messages: &[Message], model: &str) -> Result<String> {
-        let headers = self.create_headers()?;
-        let api_messages = messages_to_api_format(messages);
-
-        let request_body = json!({
+    fn request_body(
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> serde_json::Value {
+        let mut body = json!({
             "model": model,
-            "messages": api_messages,
-            "max_tokens": 4096,
-            "temperature": 0.7
+            "messages": messages_to_api_format(messages),
         });
+        let max_tokens = params.max_tokens.unwrap_or(4096);
+        if ModelInfo::for_model(model).reasoning {
+            // Reasoning models reject `temperature` and `max_tokens`
+            body["max_completion_tokens"] = json!(max_tokens);
+        } else {
+            body["max_tokens"] = json!(max_tokens);
+            body["temperature"] = json!(params.temperature.unwrap_or(0.7));
+        }
+
+        match &params.response_format {
+            Some(ResponseFormat::JsonObject) => {
+                body["response_format"] = json!({ "type": "json_object" });
+            }
+            Some(ResponseFormat::JsonSchema { name, schema }) => {
+                body["response_format"] = json!({
+                    "type": "json_schema",
+                    "json_schema": { "name": name, "schema": schema, "strict": true }
+                });
+            }
+            None => {}
+        }
+        let stop = params.stop_for(&LLMProvider::OpenAI);
+        if !stop.is_empty() {
+            body["stop"] = json!(stop);
+        }
+        body
+    }
+
+    async fn make_request(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> Result<Completion> {
+        let headers = self.create_headers()?;
+        let request_body = Self::request_body(messages, model, params);
 
         let response = self
             .http_client
             .client()
-            .post("https://api.openai.com/v1/chat/completions")
+            .post(&self.endpoint)
             .headers(headers)
             .json(&request_body)
             .send()
             .await
-            .context("Failed to send request to OpenAI API")?;
+            .map_err(|e| map_request_error("OpenAI", e, "Failed to send request to OpenAI API"))?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow!(
-                "OpenAI API request failed with status {}: {}",
-                status,
-                error_text
-            ));
+            return Err(self
+                .http_client
+                .api_error(&LLMProvider::OpenAI, "OpenAI", &request_body, response)
+                .await);
         }
 
-        let response_json: serde_json::Value = response
-            .json()
+        let status = response.status().as_u16();
+        let text = response
+            .text()
             .await
-            .context("Failed to parse OpenAI API response")?;
+            .map_err(|e| map_request_error("OpenAI", e, "Failed to read OpenAI API response"))?;
+        self.http_client
+            .inspector()
+            .record(&LLMProvider::OpenAI, &request_body, status, &text);
+        let response_json: serde_json::Value = serde_json::from_str(&text)
+            .map_err(|e| anyhow!("Failed to parse OpenAI API response: {}", e))?;
 
         // Extract the content from OpenAI's response format
-        let content = response_json["choices"]
-            .as_array()
-            .and_then(|arr| arr.first())
-            .and_then(|obj| obj["message"]["content"].as_str())
+        let completion = parse_chat_completion(&response_json)
             .ok_or_else(|| anyhow!("Invalid response format from OpenAI API"))?;
 
-        Ok(content.to_string())
+        if params.response_format.is_some() {
+            serde_json::from_str::<serde_json::Value>(&completion.text)
+                .map_err(|e| anyhow!("OpenAI returned invalid JSON in JSON mode: {}", e))?;
+        }
+
+        Ok(completion)
+    }
+
+    async fn make_streaming_request(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+        on_delta: &mut (dyn for<'s> FnMut(&'s str) + Send),
+    ) -> Result<Completion> {
+        let headers = self.create_headers()?;
+        let mut request_body = Self::request_body(messages, model, params);
+        request_body["stream"] = json!(true);
+
+        let response = self
+            .http_client
+            .client()
+            .post(&self.endpoint)
+            .headers(headers)
+            .json(&request_body)
+            .send()
+            .await
+            .map_err(|e| map_request_error("OpenAI", e, "Failed to send request to OpenAI API"))?;
+
+        if !response.status().is_success() {
+            return Err(self
+                .http_client
+                .api_error(&LLMProvider::OpenAI, "OpenAI", &request_body, response)
+                .await);
+        }
+
+        // Server-sent events: `data: {chunk}` lines, ending with `data: [DONE]`.
+        // The last chunk's `finish_reason` is `length` at the token limit. A
+        // body that ends or errors before `[DONE]` was cut off.
+        let interrupted = || {
+            anyhow::Error::new(LLMError::StreamInterrupted {
+                provider: "OpenAI".to_string(),
+            })
+        };
+        let mut body = response.bytes_stream();
+        let mut decoder = SseDecoder::default();
+        let mut content = String::new();
+        let mut truncated = false;
+        while let Some(chunk) = body.next().await {
+            for event in decoder.feed(&chunk.map_err(|_| interrupted())?) {
+                if event.is_done() {
+                    self.http_client.inspector().record(
+                        &LLMProvider::OpenAI,
+                        &request_body,
+                        200,
+                        &content,
+                    );
+                    return Ok(Completion {
+                        text: content,
+                        truncated,
+                    });
+                }
+                let chunk: serde_json::Value = serde_json::from_str(&event.data)
+                    .map_err(|e| anyhow!("Invalid stream chunk from OpenAI API: {}", e))?;
+                if let Some(delta) = chunk["choices"][0]["delta"]["content"].as_str() {
+                    content.push_str(delta);
+                    on_delta(delta);
+                }
+                if chunk["choices"][0]["finish_reason"] == "length" {
+                    truncated = true;
+                }
+            }
+        }
+        Err(interrupted())
     }
 }
 
 #[async_trait::async_trait]
 impl LLMClient for OpenAIClient {
     async fn send_message(&self, messages: &[Message], model: &str) -> Result<String> {
+        self.send_message_with_params(messages, model, &GenerationParams::default())
+            .await
+    }
+
+    async fn send_message_with_params(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> Result<String> {
+        self.complete_with_params(messages, model, params)
+            .await
+            .map(|completion| completion.text)
+    }
+
+    async fn complete_with_params(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+    ) -> Result<Completion> {
         if messages.is_empty() {
             return Err(anyhow!("No messages to send"));
         }
+        self.make_request(messages, model, params).await
+    }
 
-        self.make_request(messages, model).await
+    async fn stream_message_with_params(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &GenerationParams,
+        on_delta: &mut (dyn for<'s> FnMut(&'s str) + Send),
+    ) -> Result<Completion> {
+        if messages.is_empty() {
+            return Err(anyhow!("No messages to send"));
+        }
+        // JSON mode validates the whole reply, so it isn't streamed
+        if params.response_format.is_some() {
+            let completion = self.make_request(messages, model, params).await?;
+            on_delta(&completion.text);
+            return Ok(completion);
+        }
+        self.make_streaming_request(messages, model, params, on_delta)
+            .await
     }
 
     fn provider(&self) -> LLMProvider {
@@ -92,6 +292,41 @@ impl LLMClient for OpenAIClient {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::llm::stream::stream_with_recovery;
+    use crate::llm::Inspector;
+    use wiremock::matchers::{body_partial_json, body_string_contains, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn json_mode() -> GenerationParams {
+        GenerationParams {
+            response_format: Some(ResponseFormat::JsonObject),
+            ..Default::default()
+        }
+    }
+
+    fn completion(content: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{ "message": { "content": content } }]
+        }))
+    }
+
+    fn event_stream(deltas: &[&str], done: bool) -> ResponseTemplate {
+        let mut body: String = deltas
+            .iter()
+            .map(|d| {
+                format!(
+                    "data: {}\n\n",
+                    json!({ "choices": [{ "delta": { "content": d } }] })
+                )
+            })
+            .collect();
+        if done {
+            body.push_str("data: [DONE]\n\n");
+        }
+        ResponseTemplate::new(200)
+            .insert_header("content-type", "text/event-stream")
+            .set_body_string(body)
+    }
 
     #[test]
     fn test_openai_client_creation() {
@@ -109,5 +344,321 @@ mod tests {
 
         let auth_header = headers.get(AUTHORIZATION).unwrap().to_str().unwrap();
         assert!(auth_header.starts_with("Bearer "));
+        assert!(!headers.contains_key("openai-organization"));
+        assert!(!headers.contains_key("openai-project"));
+    }
+
+    #[test]
+    fn test_organization_headers() {
+        let client = OpenAIClient::new("test-key".to_string())
+            .with_organization(Some("org-abc123".to_string()), Some("proj_xyz".to_string()));
+        let headers = client.create_headers().unwrap();
+        assert_eq!(headers["OpenAI-Organization"], "org-abc123");
+        assert_eq!(headers["OpenAI-Project"], "proj_xyz");
+
+        // Blank values are left out rather than sent empty
+        let client = OpenAIClient::new("test-key".to_string())
+            .with_organization(Some("org-abc123".to_string()), Some(" ".to_string()));
+        let headers = client.create_headers().unwrap();
+        assert!(headers.contains_key("openai-organization"));
+        assert!(!headers.contains_key("openai-project"));
+    }
+
+    #[test]
+    fn test_request_body_response_format() {
+        let messages = [Message::user("Reply in JSON".to_string())];
+
+        let body = OpenAIClient::request_body(&messages, "gpt-4o", &GenerationParams::default());
+        assert!(body.get("response_format").is_none());
+
+        let body = OpenAIClient::request_body(&messages, "gpt-4o", &json_mode());
+        assert_eq!(body["response_format"], json!({ "type": "json_object" }));
+
+        let schema = json!({ "type": "object", "properties": { "ok": { "type": "boolean" } } });
+        let params = GenerationParams {
+            response_format: Some(ResponseFormat::JsonSchema {
+                name: "status".into(),
+                schema: schema.clone(),
+            }),
+            ..Default::default()
+        };
+        let body = OpenAIClient::request_body(&messages, "gpt-4o", &params);
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        assert_eq!(body["response_format"]["json_schema"]["schema"], schema);
+    }
+
+    #[test]
+    fn test_reasoning_model_request_body() {
+        let messages = [Message::user("Prove it".to_string())];
+        let params = GenerationParams {
+            temperature: Some(0.5),
+            max_tokens: Some(1000),
+            ..Default::default()
+        };
+
+        let body = OpenAIClient::request_body(&messages, "o1", &params);
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("max_tokens").is_none());
+        assert_eq!(body["max_completion_tokens"], 1000);
+
+        let body = OpenAIClient::request_body(&messages, "gpt-4o", &params);
+        assert_eq!(body["max_tokens"], 1000);
+        assert_eq!(body["temperature"], 0.5);
+        assert!(body.get("max_completion_tokens").is_none());
+    }
+
+    #[test]
+    fn test_request_body_stop() {
+        let messages = [Message::user("List three".to_string())];
+
+        let body = OpenAIClient::request_body(&messages, "gpt-4o", &GenerationParams::default());
+        assert!(body.get("stop").is_none());
+
+        let params = GenerationParams {
+            stop: vec!["END".into(), "\n\n".into()],
+            ..Default::default()
+        };
+        let body = OpenAIClient::request_body(&messages, "gpt-4o", &params);
+        assert_eq!(body["stop"], json!(["END", "\n\n"]));
+        assert!(body.get("stop_sequences").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_extra_stop_sequences_are_dropped() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "stop": ["a", "b", "c", "d"] })))
+            .respond_with(completion("Hi there"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = OpenAIClient::new("test-key".to_string()).with_endpoint(server.uri());
+        let params = GenerationParams {
+            stop: ["a", "b", "c", "d", "e"].map(String::from).to_vec(),
+            ..Default::default()
+        };
+        let reply = client
+            .complete_with_params(&[Message::user("Hi".to_string())], "gpt-4o", &params)
+            .await
+            .unwrap();
+        assert_eq!(reply.text, "Hi there");
+    }
+
+    #[tokio::test]
+    async fn test_inspector_records_redacted_bodies() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(completion("Rotate it"))
+            .mount(&server)
+            .await;
+        let inspector = Inspector::default();
+        let client = OpenAIClient::new("sk-secret-header-key-0123456789".to_string())
+            .with_endpoint(server.uri())
+            .with_http_client(HttpLLMClient::new().with_inspector(inspector.clone()));
+        let messages = [Message::user(
+            "I leaked sk-proj-Abc123def456GHI789jkl".to_string(),
+        )];
+
+        client.send_message(&messages, "gpt-4o").await.unwrap();
+        assert!(inspector.exchanges(&LLMProvider::OpenAI).is_empty());
+
+        inspector.configure(true, 5);
+        client.send_message(&messages, "gpt-4o").await.unwrap();
+        let exchanges = inspector.exchanges(&LLMProvider::OpenAI);
+        assert_eq!(exchanges.len(), 1);
+        let exchange = &exchanges[0];
+        assert_eq!(exchange.status, 200);
+        assert!(exchange.request.contains("\"model\": \"gpt-4o\""));
+        assert!(exchange.request.contains("I leaked ***"));
+        assert!(exchange.response.contains("Rotate it"));
+        for body in [&exchange.request, &exchange.response] {
+            assert!(!body.contains("sk-"));
+            assert!(!body.to_lowercase().contains("authorization"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inspector_records_error_responses() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "error": { "message": "Unsupported parameter: 'stop'", "type": "invalid_request_error" }
+            })))
+            .mount(&server)
+            .await;
+        let inspector = Inspector::default();
+        inspector.configure(true, 5);
+        let client = OpenAIClient::new("test-key".to_string())
+            .with_endpoint(server.uri())
+            .with_http_client(HttpLLMClient::new().with_inspector(inspector.clone()));
+
+        let messages = [Message::user("Hi".to_string())];
+        client.send_message(&messages, "gpt-4o").await.unwrap_err();
+        let exchanges = inspector.exchanges(&LLMProvider::OpenAI);
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].status, 400);
+        assert!(exchanges[0].response.contains("Unsupported parameter"));
+    }
+
+    #[tokio::test]
+    async fn test_json_mode_validates_response() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(
+                json!({ "response_format": { "type": "json_object" } }),
+            ))
+            .respond_with(completion("Sure! {\"ok\": true}"))
+            .mount(&server)
+            .await;
+
+        let client = OpenAIClient::new("test-key".to_string()).with_endpoint(server.uri());
+        let messages = [Message::user("Reply in JSON".to_string())];
+        let err = client
+            .send_message_with_params(&messages, "gpt-4o", &json_mode())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid JSON"));
+    }
+
+    #[tokio::test]
+    async fn test_custom_model_id_is_sent_verbatim() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "model": "gpt-4.1-2025-04-14" })))
+            .respond_with(completion("Hi"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = OpenAIClient::new("test-key".to_string()).with_endpoint(server.uri());
+        let messages = [Message::user("Hello".to_string())];
+        let reply = client
+            .send_message(&messages, "gpt-4.1-2025-04-14")
+            .await
+            .unwrap();
+        assert_eq!(reply, "Hi");
+    }
+
+    #[tokio::test]
+    async fn test_json_mode_accepts_json_response() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(completion("{\"ok\": true}"))
+            .mount(&server)
+            .await;
+
+        let client = OpenAIClient::new("test-key".to_string()).with_endpoint(server.uri());
+        let messages = [Message::user("Reply in JSON".to_string())];
+        let reply = client
+            .send_message_with_params(&messages, "gpt-4o", &json_mode())
+            .await
+            .unwrap();
+        assert_eq!(reply, "{\"ok\": true}");
+    }
+
+    #[tokio::test]
+    async fn test_stream_cut_off_is_retried() {
+        let server = MockServer::start().await;
+        // The first stream stops before [DONE]; the retry finishes the reply
+        Mock::given(body_string_contains("cut off"))
+            .respond_with(event_stream(&[" world"], true))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "stream": true })))
+            .respond_with(event_stream(&["Hello"], false))
+            .mount(&server)
+            .await;
+
+        let client = OpenAIClient::new("test-key".to_string()).with_endpoint(server.uri());
+        let messages = [Message::user("Greet me".to_string())];
+        let mut shown = Vec::new();
+        let reply = stream_with_recovery(
+            &client,
+            &messages,
+            "gpt-4o",
+            &GenerationParams::default(),
+            &mut |delta| shown.push(delta.to_string()),
+        )
+        .await
+        .unwrap();
+
+        assert!(reply.complete);
+        assert_eq!(reply.content, "Hello world");
+        assert_eq!(shown, vec!["Hello", " world"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_cut_off_twice_is_incomplete() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(event_stream(&["Hel", "lo"], false))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let client = OpenAIClient::new("test-key".to_string()).with_endpoint(server.uri());
+        let messages = [Message::user("Greet me".to_string())];
+        let err = client
+            .stream_message_with_params(
+                &messages,
+                "gpt-4o",
+                &GenerationParams::default(),
+                &mut |_| {},
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LLMError>(),
+            Some(&LLMError::StreamInterrupted {
+                provider: "OpenAI".into()
+            })
+        );
+
+        let reply = stream_with_recovery(
+            &client,
+            &messages,
+            "gpt-4o",
+            &GenerationParams::default(),
+            &mut |_| {},
+        )
+        .await
+        .unwrap();
+        assert!(!reply.complete);
+        assert_eq!(reply.content, "HelloHello");
+    }
+
+    #[tokio::test]
+    async fn test_stream_at_token_limit_is_truncated() {
+        let body = format!(
+            "data: {}\n\ndata: {}\n\ndata: [DONE]\n\n",
+            json!({ "choices": [{ "delta": { "content": "Hel" } }] }),
+            json!({ "choices": [{ "delta": {}, "finish_reason": "length" }] })
+        );
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", "text/event-stream")
+                    .set_body_string(body),
+            )
+            .mount(&server)
+            .await;
+
+        let client = OpenAIClient::new("test-key".to_string()).with_endpoint(server.uri());
+        let messages = [Message::user("Greet me".to_string())];
+        let reply = stream_with_recovery(
+            &client,
+            &messages,
+            "gpt-4o",
+            &GenerationParams::default(),
+            &mut |_| {},
+        )
+        .await
+        .unwrap();
+        assert!(reply.complete);
+        assert!(reply.truncated);
+        assert_eq!(reply.content, "Hel");
     }
 }
@@ -0,0 +1,115 @@
+use super::client::HttpLLMClient;
+use super::inspector::Inspector;
+use super::{ClaudeClient, CustomOpenAICompatibleClient, LLMClient, OpenAIClient};
+use crate::models::{LLMProvider, Settings};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Builds a provider's client from settings, recording its requests in the
+/// inspector, or `None` if the provider isn't configured (e.g. has no API
+/// key).
+pub type ClientFactory = fn(&Settings, &Inspector) -> Option<Arc<dyn LLMClient>>;
+
+// One factory per provider; adding a provider means adding it here
+const FACTORIES: &[ClientFactory] = &[claude_client, openai_client, custom_client];
+
+fn claude_client(settings: &Settings, inspector: &Inspector) -> Option<Arc<dyn LLMClient>> {
+    let key = settings.claude_api_key.clone()?;
+    Some(Arc::new(ClaudeClient::new(key).with_http_client(
+        http_client_for(settings, &LLMProvider::Claude, inspector),
+    )))
+}
+
+fn openai_client(settings: &Settings, inspector: &Inspector) -> Option<Arc<dyn LLMClient>> {
+    let key = settings.openai_api_key.clone()?;
+    Some(Arc::new(
+        OpenAIClient::new(key)
+            .with_organization(settings.openai_org.clone(), settings.openai_project.clone())
+            .with_http_client(http_client_for(settings, &LLMProvider::OpenAI, inspector)),
+    ))
+}
+
+fn custom_client(settings: &Settings, inspector: &Inspector) -> Option<Arc<dyn LLMClient>> {
+    let custom = settings.custom_provider.clone()?;
+    Some(Arc::new(
+        CustomOpenAICompatibleClient::new(custom).with_http_client(http_client_for(
+            settings,
+            &LLMProvider::Custom,
+            inspector,
+        )),
+    ))
+}
+
+// HTTP client with the provider's timeouts and the configured proxy
+fn http_client_for(
+    settings: &Settings,
+    provider: &LLMProvider,
+    inspector: &Inspector,
+) -> HttpLLMClient {
+    let timeouts = settings.timeouts_for(provider);
+    HttpLLMClient::with_options(timeouts, settings.proxy_url.as_deref())
+        .unwrap_or_else(|e| {
+            eprintln!("Ignoring proxy_url: {:#}", e);
+            HttpLLMClient::with_timeouts(timeouts)
+        })
+        .with_inspector(inspector.clone())
+}
+
+/// The LLM clients for every configured provider.
+#[derive(Default)]
+pub struct ClientRegistry {
+    clients: HashMap<LLMProvider, Arc<dyn LLMClient>>,
+}
+
+impl ClientRegistry {
+    pub fn from_settings(settings: &Settings, inspector: &Inspector) -> Self {
+        let clients = FACTORIES
+            .iter()
+            .filter_map(|factory| factory(settings, inspector))
+            .map(|client| (client.provider(), client))
+            .collect();
+        Self { clients }
+    }
+
+    /// Replace all clients, e.g. after API keys or timeouts have changed.
+    pub fn rebuild(&mut self, settings: &Settings, inspector: &Inspector) {
+        *self = Self::from_settings(settings, inspector);
+    }
+
+    pub fn get(&self, provider: &LLMProvider) -> Option<Arc<dyn LLMClient>> {
+        self.clients.get(provider).cloned()
+    }
+
+    /// Register `client` for its provider, replacing any existing one.
+    #[cfg(test)]
+    pub fn insert(&mut self, client: Arc<dyn LLMClient>) {
+        self.clients.insert(client.provider(), client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_builds_configured_clients() {
+        let mut settings = Settings {
+            claude_api_key: None,
+            openai_api_key: Some("sk-test".to_string()),
+            custom_provider: None,
+            ..Default::default()
+        };
+
+        let inspector = Inspector::default();
+        let mut registry = ClientRegistry::from_settings(&settings, &inspector);
+        assert!(registry.get(&LLMProvider::Claude).is_none());
+        assert!(registry.get(&LLMProvider::Custom).is_none());
+        let openai = registry.get(&LLMProvider::OpenAI).unwrap();
+        assert_eq!(openai.provider(), LLMProvider::OpenAI);
+
+        settings.claude_api_key = Some("sk-ant-test".to_string());
+        registry.rebuild(&settings, &inspector);
+        let claude = registry.get(&LLMProvider::Claude).unwrap();
+        assert_eq!(claude.provider(), LLMProvider::Claude);
+    }
+}
@@ -0,0 +1,128 @@
+/// One server-sent event.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SseEvent {
+    /// The `event:` field, if the server named the event.
+    pub event: Option<String>,
+    /// The `data:` lines, joined with newlines.
+    pub data: String,
+    pub id: Option<String>,
+}
+
+impl SseEvent {
+    /// Whether this is the `[DONE]` sentinel OpenAI-style APIs end with.
+    pub fn is_done(&self) -> bool {
+        self.data.trim() == "[DONE]"
+    }
+}
+
+/// Incremental parser for a `text/event-stream` body. Feed it the body's
+/// chunks as they arrive; events and lines may be split anywhere across
+/// chunks.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    // Bytes of a line that hasn't ended yet
+    pending: Vec<u8>,
+    // Fields of the event being read
+    event: SseEvent,
+    has_data: bool,
+}
+
+impl SseDecoder {
+    /// Parse `chunk`, returning the events it completes, in order.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.pending.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            if let Some(event) = self.line(line) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    // Apply one line, returning the event a blank line completes
+    fn line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            let event = std::mem::take(&mut self.event);
+            // An event without data isn't dispatched
+            return std::mem::take(&mut self.has_data).then_some(event);
+        }
+        if line.starts_with(':') {
+            // Comment, often sent as a keep-alive
+            return None;
+        }
+
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => self.event.event = Some(value.to_string()),
+            "data" => {
+                if self.has_data {
+                    self.event.data.push('\n');
+                }
+                self.event.data.push_str(value);
+                self.has_data = true;
+            }
+            "id" => self.event.id = Some(value.to_string()),
+            // `retry` and unknown fields are ignored
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(data: &str) -> SseEvent {
+        SseEvent {
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_events_split_across_chunks() {
+        let body = ": keep-alive\n\
+                    data: {\"text\":\"Hel\"}\n\n\
+                    event: delta\r\nid: 7\r\ndata: first line\r\ndata:second line\r\n\r\n\
+                    event: ping\n\n\
+                    data: caf\u{e9}\n\n\
+                    data: [DONE]\n\n";
+
+        // Every split point must give the same events
+        let bytes = body.as_bytes();
+        for split in 0..bytes.len() {
+            let mut decoder = SseDecoder::default();
+            let mut events = decoder.feed(&bytes[..split]);
+            events.extend(decoder.feed(&bytes[split..]));
+            assert_eq!(
+                events,
+                vec![
+                    data("{\"text\":\"Hel\"}"),
+                    SseEvent {
+                        event: Some("delta".to_string()),
+                        data: "first line\nsecond line".to_string(),
+                        id: Some("7".to_string()),
+                    },
+                    data("caf\u{e9}"),
+                    data("[DONE]"),
+                ],
+                "split at byte {}",
+                split
+            );
+            assert!(events[3].is_done());
+        }
+    }
+
+    #[test]
+    fn test_unfinished_event_is_held_back() {
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.feed(b"data: partial\n").is_empty());
+        assert_eq!(decoder.feed(b"\n"), vec![data("partial")]);
+    }
+}
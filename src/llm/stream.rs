@@ -0,0 +1,273 @@
+use super::client::{Completion, GenerationParams, LLMClient};
+use super::error::LLMError;
+use crate::models::Message;
+use anyhow::Result;
+use std::sync::{Arc, Mutex};
+
+// Sent after a cut-off reply so the model picks up where it stopped
+const CONTINUE_PROMPT: &str =
+    "Your previous reply was cut off. Continue exactly where it stopped, without repeating anything.";
+
+/// A streamed reply, possibly cut short.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamedReply {
+    pub content: String,
+    // False if the stream was interrupted and the retry didn't finish it
+    pub complete: bool,
+    // The provider stopped the reply at its token limit
+    pub truncated: bool,
+}
+
+/// Text streamed in on a background task and not yet shown. The task keeps
+/// reading whether or not the UI is drawing frames (egui draws few or none
+/// while the window is unfocused), so a stalled UI can't stall the
+/// connection into a timeout. Clones share the buffer.
+#[derive(Clone, Default)]
+pub struct DeltaBuffer {
+    pending: Arc<Mutex<Deltas>>,
+    // Called after each delta, e.g. to ask the UI for a repaint
+    notify: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+/// What arrived since the buffer was last read.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Deltas {
+    // The reply started over (another attempt); drop what was shown
+    pub restart: bool,
+    pub text: String,
+}
+
+impl DeltaBuffer {
+    pub fn with_notify(notify: impl Fn() + Send + Sync + 'static) -> Self {
+        Self {
+            pending: Arc::default(),
+            notify: Some(Arc::new(notify)),
+        }
+    }
+
+    pub fn push(&self, delta: &str) {
+        self.pending.lock().unwrap().text.push_str(delta);
+        if let Some(notify) = &self.notify {
+            notify();
+        }
+    }
+
+    /// Start the reply over, e.g. when a fallback provider takes over.
+    pub fn restart(&self) {
+        *self.pending.lock().unwrap() = Deltas {
+            restart: true,
+            text: String::new(),
+        };
+    }
+
+    /// Everything since the last call.
+    pub fn take(&self) -> Deltas {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+fn is_interrupted(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<LLMError>(),
+        Some(LLMError::StreamInterrupted { .. })
+    )
+}
+
+/// Stream a reply, retrying once if the stream drops part way through. The
+/// retry sends the partial reply back and asks the model to continue, and
+/// its deltas are appended to the same reply. If the reply still isn't
+/// finished, what arrived is returned with `complete: false`. Errors before
+/// any text arrived are returned as-is.
+pub async fn stream_with_recovery(
+    client: &dyn LLMClient,
+    messages: &[Message],
+    model: &str,
+    params: &GenerationParams,
+    on_delta: &mut (dyn for<'s> FnMut(&'s str) + Send),
+) -> Result<StreamedReply> {
+    let mut content = String::new();
+    let mut collect = |delta: &str| {
+        content.push_str(delta);
+        on_delta(delta);
+    };
+
+    match client
+        .stream_message_with_params(messages, model, params, &mut collect)
+        .await
+    {
+        Ok(Completion { truncated, .. }) => {
+            return Ok(StreamedReply {
+                content,
+                complete: true,
+                truncated,
+            })
+        }
+        Err(e) if !is_interrupted(&e) || content.is_empty() => return Err(e),
+        Err(_) => {}
+    }
+
+    let mut continued = messages.to_vec();
+    continued.push(Message::assistant(content.clone()));
+    continued.push(Message::user(CONTINUE_PROMPT.to_string()));
+
+    let mut collect = |delta: &str| {
+        content.push_str(delta);
+        on_delta(delta);
+    };
+    let continuation = client
+        .stream_message_with_params(&continued, model, params, &mut collect)
+        .await;
+
+    Ok(StreamedReply {
+        content,
+        complete: continuation.is_ok(),
+        truncated: continuation.is_ok_and(|c| c.truncated),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::LLMProvider;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Streams a scripted list of attempts: each is the deltas to send and
+    // whether the stream then drops
+    struct FlakyClient {
+        attempts: Mutex<Vec<(Vec<&'static str>, bool)>>,
+        seen: Mutex<Vec<Vec<Message>>>,
+    }
+
+    impl FlakyClient {
+        fn new(attempts: Vec<(Vec<&'static str>, bool)>) -> Self {
+            Self {
+                attempts: Mutex::new(attempts),
+                seen: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for FlakyClient {
+        async fn send_message(&self, _messages: &[Message], _model: &str) -> Result<String> {
+            unreachable!("streaming only")
+        }
+
+        async fn stream_message_with_params(
+            &self,
+            messages: &[Message],
+            _model: &str,
+            _params: &GenerationParams,
+            on_delta: &mut (dyn for<'s> FnMut(&'s str) + Send),
+        ) -> Result<Completion> {
+            self.seen.lock().unwrap().push(messages.to_vec());
+            let (deltas, drops) = self.attempts.lock().unwrap().remove(0);
+            for delta in &deltas {
+                on_delta(delta);
+            }
+            if drops {
+                return Err(LLMError::StreamInterrupted {
+                    provider: "Test".into(),
+                }
+                .into());
+            }
+            Ok(Completion {
+                text: deltas.concat(),
+                truncated: false,
+            })
+        }
+
+        fn provider(&self) -> LLMProvider {
+            LLMProvider::OpenAI
+        }
+    }
+
+    async fn stream(client: &FlakyClient) -> (Result<StreamedReply>, String) {
+        let mut shown = String::new();
+        let messages = [Message::user("Count to five".to_string())];
+        let reply = stream_with_recovery(
+            client,
+            &messages,
+            "gpt-4o",
+            &GenerationParams::default(),
+            &mut |delta| shown.push_str(delta),
+        )
+        .await;
+        (reply, shown)
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_stream_is_continued() {
+        let client = FlakyClient::new(vec![(vec!["1, 2", ", 3"], true), (vec![", 4, 5"], false)]);
+
+        let (reply, shown) = stream(&client).await;
+        let reply = reply.unwrap();
+        assert!(reply.complete);
+        assert_eq!(reply.content, "1, 2, 3, 4, 5");
+        assert_eq!(shown, reply.content);
+
+        // The retry carries the partial reply and asks to continue
+        let seen = client.seen.lock().unwrap();
+        let retry = &seen[1];
+        assert_eq!(retry[1].content, "1, 2, 3");
+        assert_eq!(retry[2].content, CONTINUE_PROMPT);
+    }
+
+    #[tokio::test]
+    async fn test_stream_marked_incomplete_after_failed_retry() {
+        let client = FlakyClient::new(vec![(vec!["1, 2"], true), (vec![", 3"], true)]);
+
+        let reply = stream(&client).await.0.unwrap();
+        assert!(!reply.complete);
+        assert_eq!(reply.content, "1, 2, 3");
+    }
+
+    #[tokio::test]
+    async fn test_deltas_are_buffered_until_taken() {
+        let notified = Arc::new(AtomicUsize::new(0));
+        let counter = notified.clone();
+        let buffer = DeltaBuffer::with_notify(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // Nobody reads while the task streams
+        let writer = buffer.clone();
+        tokio::spawn(async move {
+            for delta in ["1, ", "2, ", "3"] {
+                writer.push(delta);
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(notified.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            buffer.take(),
+            Deltas {
+                restart: false,
+                text: "1, 2, 3".to_string()
+            }
+        );
+        assert_eq!(buffer.take(), Deltas::default());
+
+        buffer.push("stale");
+        buffer.restart();
+        buffer.push("fresh");
+        assert_eq!(
+            buffer.take(),
+            Deltas {
+                restart: true,
+                text: "fresh".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_dropped_before_any_text_is_an_error() {
+        let client = FlakyClient::new(vec![(vec![], true)]);
+
+        let err = stream(&client).await.0.unwrap_err();
+        assert!(is_interrupted(&err));
+        assert_eq!(client.seen.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,186 @@
+/// How a table column's cells are aligned, from its `:--` / `:-:` / `--:`
+/// delimiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Alignment {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// A markdown (pipe) table. Every row has as many cells as the header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    pub header: Vec<String>,
+    pub alignments: Vec<Alignment>,
+    pub rows: Vec<Vec<String>>,
+}
+
+/// A run of message text: plain text, or a table to lay out as a grid.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Block {
+    Text(String),
+    Table(Table),
+}
+
+/// Split `text` into plain text and the markdown tables in it, in order.
+/// Tables inside code blocks are left as text.
+pub fn split_tables(text: &str) -> Vec<Block> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut blocks = Vec::new();
+    let mut plain: Vec<&str> = Vec::new();
+    let mut in_code = false;
+    let mut i = 0;
+    while i < lines.len() {
+        if lines[i].trim_start().starts_with("```") {
+            in_code = !in_code;
+        }
+        let table = (!in_code).then(|| table_at(&lines[i..])).flatten();
+        let Some((table, used)) = table else {
+            plain.push(lines[i]);
+            i += 1;
+            continue;
+        };
+        if !plain.is_empty() {
+            blocks.push(Block::Text(plain.join("\n")));
+            plain.clear();
+        }
+        blocks.push(Block::Table(table));
+        i += used;
+    }
+    if !plain.is_empty() {
+        blocks.push(Block::Text(plain.join("\n")));
+    }
+    blocks
+}
+
+// The table starting at `lines[0]`, if there is one, and how many lines it
+// takes up: a header row, a delimiter row with as many cells, then rows
+// until a line without a pipe
+fn table_at(lines: &[&str]) -> Option<(Table, usize)> {
+    let (header, delimiter) = (lines.first()?, lines.get(1)?);
+    if !header.contains('|') {
+        return None;
+    }
+    let header = cells(header);
+    let alignments = cells(delimiter)
+        .iter()
+        .map(|cell| alignment(cell))
+        .collect::<Option<Vec<_>>>()?;
+    if alignments.len() != header.len() {
+        return None;
+    }
+
+    let rows: Vec<Vec<String>> = lines[2..]
+        .iter()
+        .take_while(|line| line.contains('|') && !line.trim().is_empty())
+        .map(|line| {
+            // Ragged rows are padded or cut to the header's width
+            let mut row = cells(line);
+            row.resize(header.len(), String::new());
+            row
+        })
+        .collect();
+    let used = 2 + rows.len();
+    Some((
+        Table {
+            header,
+            alignments,
+            rows,
+        },
+        used,
+    ))
+}
+
+// A row's cells, without the optional outer pipes. `\|` is a literal pipe
+fn cells(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = match line.strip_suffix('|') {
+        Some(rest) if !rest.ends_with('\\') => rest,
+        _ => line,
+    };
+
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'|') => {
+                cell.push('|');
+                chars.next();
+            }
+            '|' => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            _ => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().to_string());
+    cells
+}
+
+// The alignment a delimiter cell (`---`, `:--`, `:-:`, `--:`) sets, or
+// `None` if it isn't one
+fn alignment(cell: &str) -> Option<Alignment> {
+    let left = cell.starts_with(':');
+    let right = cell.ends_with(':');
+    let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+    if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+        return None;
+    }
+    Some(match (left, right) {
+        (true, true) => Alignment::Center,
+        (false, true) => Alignment::Right,
+        _ => Alignment::Left,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(cells: &[&str]) -> Vec<String> {
+        cells.iter().map(|cell| cell.to_string()).collect()
+    }
+
+    #[test]
+    fn test_split_tables() {
+        let text = "Sizes by crate:\n\
+                    \n\
+                    | Crate | Lines | Notes |\n\
+                    |:------|------:|:-----:|\n\
+                    | serde | 1200 | a \\| b |\n\
+                    | tokio | 900 |\n\
+                    | regex | 300 | fast | extra |\n\
+                    \n\
+                    That's all.";
+
+        let blocks = split_tables(text);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0], Block::Text("Sizes by crate:\n".to_string()));
+        assert_eq!(blocks[2], Block::Text("\nThat's all.".to_string()));
+        let Block::Table(table) = &blocks[1] else {
+            panic!("expected a table, got {:?}", blocks[1]);
+        };
+        assert_eq!(table.header, strings(&["Crate", "Lines", "Notes"]));
+        assert_eq!(
+            table.alignments,
+            vec![Alignment::Left, Alignment::Right, Alignment::Center]
+        );
+        assert_eq!(
+            table.rows,
+            vec![
+                strings(&["serde", "1200", "a | b"]),
+                strings(&["tokio", "900", ""]),
+                strings(&["regex", "300", "fast"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_without_tables_is_kept() {
+        // No delimiter row, and a table in a code block
+        let text = "a | b\nc | d\n```\n| x |\n|---|\n```";
+        assert_eq!(split_tables(text), vec![Block::Text(text.to_string())]);
+        assert!(split_tables("").is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+use crate::models::LLMProvider;
+
+/// Quick actions offered by the command palette.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaletteAction {
+    NewTab,
+    CloseTab,
+    SwitchProvider(LLMProvider),
+    ToggleCodeExecution,
+    ToggleFocusMode,
+    OpenSettings,
+    ExportChat,
+    CopyRedactedTranscript,
+    AttachGitDiff,
+    DumpWorkspace,
+}
+
+impl PaletteAction {
+    /// Every action, in the order shown for an empty query.
+    pub fn all() -> Vec<PaletteAction> {
+        vec![
+            PaletteAction::NewTab,
+            PaletteAction::CloseTab,
+            PaletteAction::SwitchProvider(LLMProvider::Claude),
+            PaletteAction::SwitchProvider(LLMProvider::OpenAI),
+            PaletteAction::SwitchProvider(LLMProvider::Custom),
+            PaletteAction::ToggleCodeExecution,
+            PaletteAction::ToggleFocusMode,
+            PaletteAction::OpenSettings,
+            PaletteAction::ExportChat,
+            PaletteAction::CopyRedactedTranscript,
+            PaletteAction::AttachGitDiff,
+            PaletteAction::DumpWorkspace,
+        ]
+    }
+
+    pub fn label(&self) -> String {
+        match self {
+            PaletteAction::NewTab => "New tab".to_string(),
+            PaletteAction::CloseTab => "Close tab".to_string(),
+            PaletteAction::SwitchProvider(provider) => {
+                format!("Switch provider: {}", provider.as_str())
+            }
+            PaletteAction::ToggleCodeExecution => "Toggle code execution".to_string(),
+            PaletteAction::ToggleFocusMode => "Toggle focus mode".to_string(),
+            PaletteAction::OpenSettings => "Open settings".to_string(),
+            PaletteAction::ExportChat => "Export chat".to_string(),
+            PaletteAction::CopyRedactedTranscript => "Copy redacted transcript".to_string(),
+            PaletteAction::AttachGitDiff => "Attach git diff".to_string(),
+            PaletteAction::DumpWorkspace => "Dump workspace".to_string(),
+        }
+    }
+}
+
+/// How well `query` matches `text`, or `None` if its characters don't all
+/// appear in `text` in order. Case is ignored, and matches at the start of
+/// words or right after the previous match score higher, so "expch" ranks
+/// "Export chat" well above a scattered match.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i32> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let is_word_start = |i: usize| i == 0 || !text[i - 1].is_alphanumeric();
+
+    let mut score = 0;
+    let mut next = 0;
+    let mut previous: Option<usize> = None;
+    for c in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let candidates = || (next..text.len()).filter(|&i| text[i] == c);
+        // Prefer continuing the current run, then the next word start
+        let i = candidates()
+            .find(|&i| previous.is_some_and(|p| p + 1 == i))
+            .or_else(|| candidates().find(|&i| is_word_start(i)))
+            .or_else(|| candidates().next())?;
+
+        score += 1;
+        if is_word_start(i) {
+            score += 8;
+        }
+        match previous {
+            Some(p) if p + 1 == i => score += 5,
+            // Skipped characters cost a little
+            Some(p) => score -= (i - p - 1) as i32,
+            None => score -= i as i32,
+        }
+        previous = Some(i);
+        next = i + 1;
+    }
+    Some(score)
+}
+
+/// The actions matching `query`, best first. Equal scores keep the order of
+/// `actions`.
+pub fn filter_actions(query: &str, actions: &[PaletteAction]) -> Vec<PaletteAction> {
+    let mut scored: Vec<(i32, &PaletteAction)> = actions
+        .iter()
+        .filter_map(|action| fuzzy_score(query, &action.label()).map(|score| (score, action)))
+        .collect();
+    scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    scored
+        .into_iter()
+        .map(|(_, action)| action.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_ranks_best_match_first() {
+        let actions = PaletteAction::all();
+        assert_eq!(
+            filter_actions("expch", &actions),
+            vec![PaletteAction::ExportChat]
+        );
+        assert_eq!(
+            filter_actions("set", &actions).first(),
+            Some(&PaletteAction::OpenSettings)
+        );
+        assert_eq!(
+            filter_actions("sw claude", &actions).first(),
+            Some(&PaletteAction::SwitchProvider(LLMProvider::Claude))
+        );
+        assert_eq!(filter_actions("", &actions), actions);
+        assert!(filter_actions("xyz", &actions).is_empty());
+    }
+
+    #[test]
+    fn test_fuzzy_score_prefers_word_starts() {
+        assert!(fuzzy_score("ct", "Close tab") > fuzzy_score("ct", "Toggle code execution"));
+        assert_eq!(fuzzy_score("tabx", "Close tab"), None);
+    }
+}
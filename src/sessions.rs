@@ -0,0 +1,509 @@
+use crate::models::{App, ChatTab, LLMProvider, Message, Settings};
+use crate::redact::redact;
+use crate::terminal::emulator::{TerminalLine, TerminalSession};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// A chat as saved to disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedSession {
+    pub id: Uuid,
+    pub title: String,
+    pub provider: LLMProvider,
+    pub model: String,
+    pub messages: Vec<Message>,
+    // Missing from sessions saved before tabs had them
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    #[serde(default)]
+    pub stateless: bool,
+    pub saved_at: DateTime<Utc>,
+}
+
+impl SavedSession {
+    pub fn from_tab(tab: &ChatTab) -> Self {
+        Self {
+            id: tab.session_id,
+            title: tab.title.clone(),
+            provider: tab.provider.clone(),
+            model: tab.model.clone(),
+            messages: tab.messages.clone(),
+            system_prompt: tab.system_prompt.clone(),
+            stop_sequences: tab.stop_sequences.clone(),
+            stateless: tab.stateless,
+            saved_at: Utc::now(),
+        }
+    }
+
+    /// A hash of everything `from_tab` saves from `tab` but the time, to
+    /// tell whether the tab changed since it was last saved.
+    pub fn fingerprint(tab: &ChatTab) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut session = Self::from_tab(tab);
+        session.saved_at = DateTime::<Utc>::UNIX_EPOCH;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        serde_json::to_vec(&session)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// A tab continuing this conversation.
+    pub fn into_tab(self) -> ChatTab {
+        let mut tab = ChatTab::new(self.title, self.provider);
+        tab.session_id = self.id;
+        tab.model = self.model;
+        tab.messages = self.messages;
+        tab.system_prompt = self.system_prompt;
+        tab.stop_sequences = self.stop_sequences;
+        tab.stateless = self.stateless;
+        tab.title_is_default = false;
+        tab
+    }
+}
+
+/// A terminal session's scrollback, as saved in a `Workspace`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedTerminal {
+    pub title: String,
+    pub working_directory: PathBuf,
+    pub lines: Vec<TerminalLine>,
+}
+
+impl SavedTerminal {
+    pub fn from_session(session: &TerminalSession) -> Self {
+        Self {
+            title: session.title.clone(),
+            working_directory: session.working_directory.clone(),
+            lines: session
+                .history
+                .iter()
+                .map(|line| TerminalLine {
+                    // Restored lines are capped again as they're added
+                    content: line.full_content().to_string(),
+                    full_content: None,
+                    ..line.clone()
+                })
+                .collect(),
+        }
+    }
+
+    pub fn into_session(self) -> TerminalSession {
+        let mut session = TerminalSession::new(Uuid::new_v4(), self.title);
+        session.working_directory = self.working_directory;
+        for line in self.lines {
+            session.add_line(line);
+        }
+        session
+    }
+}
+
+/// Every open chat and terminal plus the settings, bundled into one file to
+/// reproduce a bug report elsewhere.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Workspace {
+    pub tabs: Vec<SavedSession>,
+    pub current_tab: usize,
+    pub terminals: Vec<SavedTerminal>,
+    pub settings: Settings,
+    // Whether API keys and tokens were left in; otherwise they're masked in
+    // the text and removed from the settings
+    pub includes_secrets: bool,
+    pub saved_at: DateTime<Utc>,
+}
+
+impl Workspace {
+    /// Bundle `app`'s tabs and settings with `terminals`. Unless
+    /// `include_secrets`, configured keys are dropped from the settings and
+    /// secrets in messages and terminal output are masked (see `redact`).
+    pub fn capture(app: &App, terminals: &[&TerminalSession], include_secrets: bool) -> Self {
+        let clean = |text: &str| {
+            if include_secrets {
+                text.to_string()
+            } else {
+                redact(text, &app.settings)
+            }
+        };
+
+        let tabs = app
+            .tabs
+            .iter()
+            .map(|tab| {
+                let mut session = SavedSession::from_tab(tab);
+                session.title = clean(&session.title);
+                session.system_prompt = session.system_prompt.as_deref().map(clean);
+                for message in &mut session.messages {
+                    message.content = clean(&message.content);
+                    for alternative in &mut message.alternatives {
+                        *alternative = clean(alternative);
+                    }
+                    for run in &mut message.command_runs {
+                        run.command = clean(&run.command);
+                        run.output = clean(&run.output);
+                    }
+                }
+                session
+            })
+            .collect();
+        let terminals = terminals
+            .iter()
+            .map(|session| {
+                let mut terminal = SavedTerminal::from_session(session);
+                for line in &mut terminal.lines {
+                    line.content = clean(&line.content);
+                }
+                terminal
+            })
+            .collect();
+
+        let mut settings = app.settings.clone();
+        if !include_secrets {
+            settings.claude_api_key = None;
+            settings.openai_api_key = None;
+            if let Some(custom) = &mut settings.custom_provider {
+                custom.api_key.clear();
+            }
+            settings.proxy_url = settings.proxy_url.as_deref().map(clean);
+        }
+
+        Self {
+            tabs,
+            current_tab: app.current_tab,
+            terminals,
+            settings,
+            includes_secrets: include_secrets,
+            saved_at: Utc::now(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&json).with_context(|| format!("Invalid workspace {}", path.display()))
+    }
+
+    /// Replace `app`'s tabs with the bundle's, returning its terminal
+    /// sessions. `app` keeps its own settings: the bundle's are there to be
+    /// read, since using them could send local keys to the bundle's
+    /// endpoints or proxy, and would be saved over the user's config.
+    pub fn restore(self, app: &mut App) -> Vec<TerminalSession> {
+        app.tabs = self.tabs.into_iter().map(SavedSession::into_tab).collect();
+        if app.tabs.is_empty() {
+            app.add_new_tab();
+        }
+        app.current_tab = self.current_tab.min(app.tabs.len() - 1);
+        self.terminals
+            .into_iter()
+            .map(SavedTerminal::into_session)
+            .collect()
+    }
+}
+
+/// Unsent input, saved so it survives a crash or restart.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Drafts {
+    pub chat: String,
+    pub terminal: String,
+}
+
+/// Saved chats, one JSON file per session in a directory.
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The store in `sessions_dir`, or else `sessions` next to the config
+    /// file.
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let dir = match &settings.sessions_dir {
+            Some(dir) => dir.clone(),
+            None => dirs::config_dir()
+                .context("Failed to get config directory")?
+                .join("llm-terminal")
+                .join("sessions"),
+        };
+        Ok(Self::new(dir))
+    }
+
+    fn path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// Write `session`, replacing any earlier save of it.
+    pub fn save(&self, session: &SavedSession) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let path = self.path(session.id);
+        let json = serde_json::to_string_pretty(session)?;
+        std::fs::write(&path, json).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn load(&self, id: Uuid) -> Result<SavedSession> {
+        let path = self.path(id);
+        let json = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&json).with_context(|| format!("Invalid session {}", path.display()))
+    }
+
+    fn drafts_path(&self) -> PathBuf {
+        self.dir.join("drafts.json")
+    }
+
+    pub fn save_drafts(&self, drafts: &Drafts) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let path = self.drafts_path();
+        let json = serde_json::to_string_pretty(drafts)?;
+        std::fs::write(&path, json).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// The saved drafts, or empty ones if there are none.
+    pub fn load_drafts(&self) -> Drafts {
+        std::fs::read_to_string(self.drafts_path())
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    /// Saved sessions, most recently saved first. Files that aren't
+    /// readable sessions are skipped.
+    pub fn list(&self) -> Result<Vec<SavedSession>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.dir.display()))
+            }
+        };
+        let mut sessions: Vec<SavedSession> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| std::fs::read_to_string(path).ok())
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.saved_at));
+        Ok(sessions)
+    }
+
+    /// Delete all but the `keep` most recently saved sessions, returning
+    /// how many were deleted.
+    pub fn prune(&self, keep: usize) -> Result<usize> {
+        let old = self.list()?.into_iter().skip(keep);
+        let mut deleted = 0;
+        for session in old {
+            let path = self.path(session.id);
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to delete {}", path.display()))?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store() -> SessionStore {
+        SessionStore::new(std::env::temp_dir().join(format!("sessions-{}", Uuid::new_v4())))
+    }
+
+    // A session titled `title`, saved `minutes_ago`
+    fn session(title: &str, minutes_ago: i64) -> SavedSession {
+        let mut tab = ChatTab::new(title.to_string(), LLMProvider::Claude);
+        tab.add_message(Message::user(format!("About {}", title)));
+        let mut session = SavedSession::from_tab(&tab);
+        session.saved_at = Utc::now() - chrono::Duration::minutes(minutes_ago);
+        session
+    }
+
+    #[test]
+    fn test_list_is_newest_first() {
+        let store = temp_store();
+        assert!(store.list().unwrap().is_empty());
+        for (title, minutes_ago) in [("middle", 5), ("newest", 1), ("oldest", 30)] {
+            store.save(&session(title, minutes_ago)).unwrap();
+        }
+        std::fs::write(store.dir.join("notes.json"), "not a session").unwrap();
+
+        let titles: Vec<String> = store.list().unwrap().into_iter().map(|s| s.title).collect();
+        assert_eq!(titles, vec!["newest", "middle", "oldest"]);
+
+        let newest = &store.list().unwrap()[0];
+        let tab = store.load(newest.id).unwrap().into_tab();
+        assert_eq!(tab.session_id, newest.id);
+        assert_eq!(tab.messages[0].content, "About newest");
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_tab_settings_round_trip() {
+        let mut tab = ChatTab::new("Review".to_string(), LLMProvider::OpenAI);
+        tab.set_system_prompt("Answer tersely.");
+        tab.add_stop_sequence("END");
+        tab.stateless = true;
+        let json = serde_json::to_string(&SavedSession::from_tab(&tab)).unwrap();
+
+        let restored = serde_json::from_str::<SavedSession>(&json)
+            .unwrap()
+            .into_tab();
+        assert_eq!(restored.system_prompt.as_deref(), Some("Answer tersely."));
+        assert_eq!(restored.stop_sequences, vec!["END"]);
+        assert!(restored.stateless);
+
+        // Sessions saved before these fields existed still load
+        let mut old: serde_json::Value = serde_json::from_str(&json).unwrap();
+        old.as_object_mut().unwrap().remove("system_prompt");
+        old.as_object_mut().unwrap().remove("stop_sequences");
+        old.as_object_mut().unwrap().remove("stateless");
+        let restored = serde_json::from_value::<SavedSession>(old)
+            .unwrap()
+            .into_tab();
+        assert_eq!(restored.system_prompt, None);
+        assert!(restored.stop_sequences.is_empty());
+        assert!(!restored.stateless);
+    }
+
+    #[test]
+    fn test_fingerprint_covers_every_saved_edit() {
+        let mut tab = ChatTab::new("Chat 1".to_string(), LLMProvider::OpenAI);
+        tab.add_message(Message::assistant("Step one".to_string()));
+        let mut seen = vec![SavedSession::fingerprint(&tab)];
+        let mut assert_changed = |tab: &ChatTab| {
+            let fingerprint = SavedSession::fingerprint(tab);
+            assert!(!seen.contains(&fingerprint));
+            seen.push(fingerprint);
+        };
+
+        // Continue
+        tab.messages[0].content.push_str(" and step two");
+        assert_changed(&tab);
+        // Regenerate
+        tab.messages[0]
+            .alternatives
+            .push("Another take".to_string());
+        assert_changed(&tab);
+        tab.title = "Renamed".to_string();
+        assert_changed(&tab);
+        tab.set_system_prompt("Answer tersely.");
+        assert_changed(&tab);
+        tab.add_stop_sequence("END");
+        assert_changed(&tab);
+        tab.stateless = true;
+        assert_changed(&tab);
+
+        // Not saved, so not a change
+        let before = SavedSession::fingerprint(&tab);
+        tab.is_waiting = true;
+        assert_eq!(SavedSession::fingerprint(&tab), before);
+    }
+
+    #[test]
+    fn test_drafts_round_trip() {
+        let store = temp_store();
+        assert_eq!(store.load_drafts(), Drafts::default());
+
+        let drafts = Drafts {
+            chat: "Half-written question".to_string(),
+            terminal: "cargo te".to_string(),
+        };
+        store.save_drafts(&drafts).unwrap();
+        assert_eq!(store.load_drafts(), drafts);
+        // Not mistaken for a saved chat
+        assert!(store.list().unwrap().is_empty());
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_workspace_round_trip() {
+        let mut app = App::new();
+        app.settings.openai_api_key = Some("sk-abcdefghijklmnopqrstuvwx".to_string());
+        app.settings.proxy_url = Some("http://proxy.example.com:3128".to_string());
+        app.tabs[0].add_message(Message::user("List the files".to_string()));
+        app.add_new_tab();
+        app.tabs[1].provider = LLMProvider::OpenAI;
+        app.tabs[1].title = "sk-abcdefghijklmnopqrstuvwx".to_string();
+        app.tabs[1].system_prompt = Some("Use key sk-abcdefghijklmnopqrstuvwx".to_string());
+        app.tabs[1].add_message(Message::user(
+            "Why does sk-abcdefghijklmnopqrstuvwx fail?".to_string(),
+        ));
+        let mut terminal = TerminalSession::new(Uuid::new_v4(), "Terminal 1".to_string());
+        terminal.add_command("ls".to_string());
+        terminal.add_output("Cargo.toml\nsrc".to_string());
+
+        let path = std::env::temp_dir().join(format!("workspace-{}.json", Uuid::new_v4()));
+        Workspace::capture(&app, &[&terminal], false)
+            .save(&path)
+            .unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        assert!(!json.contains("sk-abcdefghijklmnopqrstuvwx"));
+
+        let mut restored = App::new();
+        restored.settings.openai_api_key = Some("sk-local-key".to_string());
+        let terminals = Workspace::load(&path).unwrap().restore(&mut restored);
+        assert_eq!(restored.tabs.len(), 2);
+        assert_eq!(restored.current_tab, 1);
+        assert_eq!(restored.tabs[0].messages[0].content, "List the files");
+        assert_eq!(restored.tabs[1].provider, LLMProvider::OpenAI);
+        assert_eq!(restored.tabs[1].messages[0].content, "Why does *** fail?");
+        assert_eq!(restored.tabs[1].title, "***");
+        assert_eq!(
+            restored.tabs[1].system_prompt.as_deref(),
+            Some("Use key ***")
+        );
+        // Settings stay local
+        assert_eq!(
+            restored.settings.openai_api_key.as_deref(),
+            Some("sk-local-key")
+        );
+        assert_eq!(restored.settings.proxy_url, None);
+        assert_eq!(terminals.len(), 1);
+        let lines: Vec<&str> = terminals[0]
+            .history
+            .iter()
+            .map(|line| line.content.as_str())
+            .collect();
+        assert_eq!(lines, vec!["$ ls", "Cargo.toml", "src"]);
+
+        // Opting in keeps secrets
+        let workspace = Workspace::capture(&app, &[&terminal], true);
+        assert!(workspace.includes_secrets);
+        assert_eq!(
+            workspace.settings.openai_api_key.as_deref(),
+            Some("sk-abcdefghijklmnopqrstuvwx")
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_prune_keeps_newest() {
+        let store = temp_store();
+        for minutes_ago in 0..5 {
+            store
+                .save(&session(&format!("chat {}", minutes_ago), minutes_ago))
+                .unwrap();
+        }
+
+        assert_eq!(store.prune(3).unwrap(), 2);
+        let titles: Vec<String> = store.list().unwrap().into_iter().map(|s| s.title).collect();
+        assert_eq!(titles, vec!["chat 0", "chat 1", "chat 2"]);
+        assert_eq!(std::fs::read_dir(&store.dir).unwrap().count(), 3);
+        assert_eq!(store.prune(3).unwrap(), 0);
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::{anyhow, Context, Result};
+use similar::{ChangeTag, TextDiff};
+use std::path::{Path, PathBuf};
+
+// Commands that edit the file given as their last argument
+const EDITING_COMMANDS: &[&str] = &["touch", "tee", "truncate", "New-Item", "Set-Content"];
+
+/// Guess which file `command` modifies: the target of an output redirect,
+/// an in-place `sed -i`/`perl -i` edit, or the last argument of a command
+/// that writes a file (`touch`, `tee`, `cp`, `mv`, ...).
+pub fn detect_target_path(command: &str) -> Option<PathBuf> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+
+    // `... > file` / `... >> file`, also written without the space
+    for (i, word) in words.iter().enumerate() {
+        let target = match word.trim_start_matches('>') {
+            "" if word.starts_with('>') => words.get(i + 1).copied(),
+            rest if word.starts_with('>') => Some(rest),
+            _ => None,
+        };
+        if let Some(target) = target {
+            return Some(PathBuf::from(unquote(target)));
+        }
+    }
+
+    let program = *words.first()?;
+    let in_place = matches!(program, "sed" | "perl") && words.iter().any(|w| w.starts_with("-i"));
+    if in_place || matches!(program, "cp" | "mv") || EDITING_COMMANDS.contains(&program) {
+        return words
+            .iter()
+            .skip(1)
+            .rev()
+            .find(|w| !w.starts_with('-'))
+            .map(|w| PathBuf::from(unquote(w)));
+    }
+    None
+}
+
+/// A path declared in a message with a `# file: <path>` line.
+pub fn declared_target(message: &str) -> Option<PathBuf> {
+    message.lines().find_map(|line| {
+        let path = line
+            .trim()
+            .strip_prefix('#')?
+            .trim()
+            .strip_prefix("file:")?;
+        let path = path.trim();
+        (!path.is_empty()).then(|| PathBuf::from(path))
+    })
+}
+
+fn unquote(word: &str) -> &str {
+    word.trim_matches(|c| c == '"' || c == '\'')
+}
+
+/// Unified diff of `before` and `after`, labelled with `path`. Empty if the
+/// two are identical.
+pub fn unified_diff(path: &Path, before: &str, after: &str) -> String {
+    let path = path.display().to_string();
+    TextDiff::from_lines(before, after)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string()
+}
+
+/// How a command's output differs from a baseline run's.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    // Unified diff of the two, for display
+    pub unified: String,
+}
+
+impl OutputDiff {
+    pub fn is_unchanged(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Compare `output` with `baseline` line by line. A missing final newline
+/// doesn't count as a change.
+pub fn output_diff(baseline: &str, output: &str) -> OutputDiff {
+    let with_newline = |text: &str| match text {
+        "" => String::new(),
+        text if text.ends_with('\n') => text.to_string(),
+        text => format!("{}\n", text),
+    };
+    let (baseline, output) = (with_newline(baseline), with_newline(output));
+    let diff = TextDiff::from_lines(&baseline, &output);
+
+    let (mut added, mut removed) = (Vec::new(), Vec::new());
+    for change in diff.iter_all_changes() {
+        let line = change.value().trim_end_matches('\n').to_string();
+        match change.tag() {
+            ChangeTag::Insert => added.push(line),
+            ChangeTag::Delete => removed.push(line),
+            ChangeTag::Equal => {}
+        }
+    }
+    let unified = diff
+        .unified_diff()
+        .context_radius(3)
+        .header("baseline", "output")
+        .to_string();
+    OutputDiff {
+        added,
+        removed,
+        unified,
+    }
+}
+
+/// File contents captured before a command runs, so the change can be
+/// shown afterwards. A missing file counts as empty.
+#[derive(Debug, Clone)]
+pub struct FileSnapshot {
+    pub path: PathBuf,
+    before: String,
+}
+
+impl FileSnapshot {
+    pub fn capture(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let before = std::fs::read_to_string(&path).unwrap_or_default();
+        Self { path, before }
+    }
+
+    /// Diff against the file's current contents, or `None` if unchanged.
+    pub fn diff(&self) -> Option<String> {
+        let after = std::fs::read_to_string(&self.path).unwrap_or_default();
+        let diff = unified_diff(&self.path, &self.before, &after);
+        (!diff.is_empty()).then_some(diff)
+    }
+}
+
+/// Uncommitted changes in the git repository containing `dir`: `git diff`,
+/// followed by `git diff --staged` when `staged` is set. Empty when there
+/// are no changes.
+pub fn git_diff(dir: &Path, staged: bool) -> Result<String> {
+    let run = |args: &[&str]| -> Result<String> {
+        let output = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            // Untranslated messages, so errors can be recognised
+            .env("LC_ALL", "C")
+            .output()
+            .context("Failed to run git")?;
+        if output.status.success() {
+            return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("not a git repository") {
+            Err(anyhow!("{} isn't in a git repository", dir.display()))
+        } else {
+            Err(anyhow!("git {} failed: {}", args[0], stderr.trim()))
+        }
+    };
+
+    // Outside a repository `git diff` would compare paths instead
+    run(&["rev-parse", "--is-inside-work-tree"])?;
+    let mut diff = run(&["diff"])?;
+    if staged {
+        diff.push_str(&run(&["diff", "--staged"])?);
+    }
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_git_diff_outside_repository() {
+        let dir = std::env::temp_dir().join(format!("no-repo-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let err = git_diff(&dir, true).unwrap_err();
+        assert!(err.to_string().contains("isn't in a git repository"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unified_diff_hunks() {
+        let before = "one\ntwo\nthree\n";
+        let after = "one\n2\nthree\nfour\n";
+
+        let diff = unified_diff(Path::new("notes.txt"), before, after);
+        assert_eq!(
+            diff,
+            "--- a/notes.txt\n\
+             +++ b/notes.txt\n\
+             @@ -1,3 +1,4 @@\n \
+             one\n\
+             -two\n\
+             +2\n \
+             three\n\
+             +four\n"
+        );
+        assert_eq!(unified_diff(Path::new("notes.txt"), before, before), "");
+    }
+
+    #[test]
+    fn test_output_diff() {
+        let baseline = "test a ... ok\ntest b ... FAILED\ntest c ... ok";
+        let output = "test a ... ok\ntest b ... ok\ntest c ... ok\ntest d ... ok\n";
+
+        let diff = output_diff(baseline, output);
+        assert_eq!(diff.removed, vec!["test b ... FAILED"]);
+        assert_eq!(diff.added, vec!["test b ... ok", "test d ... ok"]);
+        assert!(diff.unified.starts_with("--- baseline\n+++ output\n@@"));
+        assert!(diff.unified.contains("\n-test b ... FAILED\n"));
+
+        let diff = output_diff("same\n", "same");
+        assert!(diff.is_unchanged());
+        assert!(diff.unified.is_empty());
+        assert_eq!(output_diff("", "new").added, vec!["new"]);
+    }
+
+    #[test]
+    fn test_detect_target_path() {
+        let detect = |command| detect_target_path(command).map(|p| p.display().to_string());
+        assert_eq!(detect("echo hi > out.txt").as_deref(), Some("out.txt"));
+        assert_eq!(detect("echo hi >>log.txt").as_deref(), Some("log.txt"));
+        assert_eq!(
+            detect("sed -i 's/a/b/' 'src/main.rs'").as_deref(),
+            Some("src/main.rs")
+        );
+        assert_eq!(detect("cp -r a.txt b.txt").as_deref(), Some("b.txt"));
+        assert_eq!(detect("sed 's/a/b/' file"), None);
+        assert_eq!(detect("ls -la"), None);
+
+        let message = "Here you go:\n```bash\n# file: config.toml\necho x >> config.toml\n```";
+        assert_eq!(declared_target(message), Some(PathBuf::from("config.toml")));
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let path = std::env::temp_dir().join(format!("llm-terminal-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "a\n").unwrap();
+
+        let snapshot = FileSnapshot::capture(&path);
+        assert_eq!(snapshot.diff(), None);
+
+        std::fs::write(&path, "a\nb\n").unwrap();
+        let diff = snapshot.diff().unwrap();
+        assert!(diff.contains("+b\n"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
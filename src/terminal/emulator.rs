@@ -1,17 +1,28 @@
 #![allow(dead_code)]
 use super::process::ProcessManager;
-use anyhow::Result;
-use std::collections::VecDeque;
+use super::pty::{PseudoTerminal, PtyOutput};
+use super::shell::{expand_alias, looks_destructive};
+use crate::models::Settings;
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::LazyLock;
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TerminalLine {
     pub content: String,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    // The original line if `content` was cut down to the line length cap
+    #[serde(skip)]
+    pub full_content: Option<String>,
     pub line_type: TerminalLineType,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TerminalLineType {
     Output,
     Error,
@@ -23,6 +34,7 @@ impl TerminalLine {
         Self {
             content,
             timestamp: chrono::Utc::now(),
+            full_content: None,
             line_type: TerminalLineType::Output,
         }
     }
@@ -31,6 +43,7 @@ impl TerminalLine {
         Self {
             content,
             timestamp: chrono::Utc::now(),
+            full_content: None,
             line_type: TerminalLineType::Error,
         }
     }
@@ -39,11 +52,222 @@ impl TerminalLine {
         Self {
             content,
             timestamp: chrono::Utc::now(),
+            full_content: None,
             line_type: TerminalLineType::System,
         }
     }
+
+    /// The line as it was added, before any length cap was applied.
+    pub fn full_content(&self) -> &str {
+        self.full_content.as_deref().unwrap_or(&self.content)
+    }
+
+    /// Whether the displayed content was cut down from a longer line.
+    pub fn is_truncated(&self) -> bool {
+        self.full_content.is_some()
+    }
+
+    // Cut `content` to at most `max_bytes` (on a char boundary) followed by
+    // a marker with the original size, keeping the original aside
+    fn capped(mut self, max_bytes: usize) -> Self {
+        if self.content.len() <= max_bytes {
+            return self;
+        }
+        let mut cut = max_bytes;
+        while !self.content.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        let full = std::mem::take(&mut self.content);
+        self.content = format!("{}…(truncated, {} bytes)", &full[..cut], full.len());
+        self.full_content = Some(full);
+        self
+    }
+}
+
+/// Lines longer than this (in bytes) are truncated for display.
+pub const DEFAULT_MAX_LINE_BYTES: usize = 10_000;
+
+/// Lines a terminal's scrollback keeps by default.
+pub const DEFAULT_SCROLLBACK_LINES: usize = 1_000;
+
+/// The welcome text a new terminal session starts with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Banner {
+    /// Session start, shell, OS and working directory
+    Default,
+    /// This text, verbatim, one system line per line of text
+    Custom(String),
+    Disabled,
+}
+
+impl Banner {
+    pub fn lines(&self, shell: &str, working_directory: &Path) -> Vec<String> {
+        match self {
+            Banner::Default => vec![
+                "Terminal session started".to_string(),
+                format!("Shell: {} on {}", shell, std::env::consts::OS),
+                format!("Working directory: {}", working_directory.display()),
+            ],
+            Banner::Custom(text) => text.lines().map(str::to_string).collect(),
+            Banner::Disabled => Vec::new(),
+        }
+    }
+}
+
+/// Split `text` into rows of at most `width` characters, breaking after
+/// whitespace where possible and mid-word otherwise. Continuation rows are
+/// indented like the first so indented output stays aligned.
+pub fn wrap_line(text: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let width = width.max(1);
+    let indent_len = chars.iter().take_while(|c| **c == ' ').count();
+    // Keep at least half the row for text
+    let indent = " ".repeat(if indent_len * 2 <= width {
+        indent_len
+    } else {
+        0
+    });
+
+    let mut rows = Vec::new();
+    let mut start = 0;
+    let mut prefix = "";
+    loop {
+        let room = width - prefix.len();
+        if chars.len() - start <= room {
+            rows.push(format!(
+                "{}{}",
+                prefix,
+                chars[start..].iter().collect::<String>()
+            ));
+            return rows;
+        }
+        let limit = start + room;
+        // Break after the last space that fits, if the row isn't all spaces
+        let end = match chars[start..=limit].iter().rposition(|c| c.is_whitespace()) {
+            Some(space)
+                if chars[start..start + space]
+                    .iter()
+                    .any(|c| !c.is_whitespace()) =>
+            {
+                start + space
+            }
+            _ => limit,
+        };
+        let row: String = chars[start..end].iter().collect();
+        rows.push(format!("{}{}", prefix, row.trim_end()));
+        start = end;
+        while start < chars.len() && chars[start] == ' ' {
+            start += 1;
+        }
+        prefix = &indent;
+    }
+}
+
+/// Runs of consecutive identical lines (same text and type) folded into the
+/// first line of the run and its length, for display. The history itself is
+/// untouched, so a run's count grows as more repeats arrive.
+pub fn collapse_repeats<'a>(
+    lines: impl IntoIterator<Item = &'a TerminalLine>,
+) -> Vec<(&'a TerminalLine, usize)> {
+    let mut runs: Vec<(&TerminalLine, usize)> = Vec::new();
+    for line in lines {
+        match runs.last_mut() {
+            Some((first, count))
+                if first.line_type == line.line_type
+                    && first.full_content() == line.full_content() =>
+            {
+                *count += 1
+            }
+            _ => runs.push((line, 1)),
+        }
+    }
+    runs
+}
+
+// Matches a shell prompt such as `user@host:~/src$`, `bash-5.2#` or
+// `PS C:\Users\me>`
+static PROMPT_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(PS [^>]*>|[\w.\-]+@[\w.\-]+(:\S*)?\s?[$#%>]|\w*sh-[\d.]+[$#])\s*$").unwrap()
+});
+
+// Whether `text` is a prompt. A bare `$`, `#`, `%` or `>` only counts when
+// followed by the space a prompt leaves for input, since a command may
+// print that character on its own
+fn is_prompt(text: &str) -> bool {
+    let trimmed = text.trim_end();
+    PROMPT_RE.is_match(trimmed)
+        || (matches!(trimmed, "$" | "#" | "%" | ">") && trimmed.len() < text.len())
+}
+
+// Echoes still expected at most; older ones are forgotten
+const MAX_PENDING_ECHOES: usize = 32;
+
+/// Suppresses the shell's echo of commands we sent and its prompt lines, so
+/// each command appears once (as our own `$ command` line) in the history.
+#[derive(Debug, Default)]
+pub struct EchoFilter {
+    pending: VecDeque<String>,
+}
+
+impl EchoFilter {
+    /// Register a command that was just sent, so its echo can be dropped.
+    /// Each line of a multi-line command is echoed separately.
+    pub fn expect(&mut self, command: &str) {
+        for line in command.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if self.pending.len() == MAX_PENDING_ECHOES {
+                self.pending.pop_front();
+            }
+            self.pending.push_back(line.to_string());
+        }
+    }
+
+    /// Returns `false` if `line` (without its line ending) is a prompt or
+    /// an echo of a sent command. An expected echo is only looked for in
+    /// the next line of output; a shell that doesn't echo never gets a
+    /// later line that happens to match dropped.
+    pub fn accept(&mut self, line: &str) -> bool {
+        if line.trim().is_empty() {
+            return true;
+        }
+        if is_prompt(line) {
+            return false;
+        }
+
+        let Some(command) = self.pending.pop_front() else {
+            return true;
+        };
+        match line.trim_end().strip_suffix(command.as_str()) {
+            Some(prefix) => !(prefix.trim().is_empty() || is_prompt(prefix)),
+            None => true,
+        }
+    }
+}
+
+/// File format for `TerminalSession::export_scrollback`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// One line per history entry, optionally prefixed with `[HH:MM:SS]`
+    Text { timestamps: bool },
+    /// The session title, truncation count and every line with its
+    /// timestamp and type
+    Json,
 }
 
+#[derive(Serialize)]
+struct ScrollbackExport<'a> {
+    title: &'a str,
+    truncated_lines: usize,
+    lines: &'a VecDeque<TerminalLine>,
+}
+
+/// A command's output kept to compare later runs against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Baseline {
+    pub command: String,
+    pub output: String,
+}
+
+#[derive(Debug)]
 pub struct TerminalSession {
     pub id: Uuid,
     pub title: String,
@@ -52,6 +276,18 @@ pub struct TerminalSession {
     pub working_directory: std::path::PathBuf,
     pub is_active: bool,
     max_history: usize,
+    // Longer lines are truncated with a marker giving their original size
+    max_line_bytes: usize,
+    // Lines dropped from the front of `history` once it hit `max_history`
+    truncated_lines: usize,
+    echo_filter: EchoFilter,
+    // Markers echoed after each command sent to the shell; a command has
+    // finished once its marker comes back
+    pending_sentinels: VecDeque<String>,
+    // Commands to run one at a time, each once the one before it finishes
+    pub queued_commands: VecDeque<String>,
+    // Set with "Set baseline"; "Diff against baseline" compares with it
+    pub baseline: Option<Baseline>,
 }
 
 impl TerminalSession {
@@ -64,21 +300,93 @@ impl TerminalSession {
             working_directory: std::env::current_dir()
                 .unwrap_or_else(|_| std::path::PathBuf::from(".")),
             is_active: false,
-            max_history: 1000, // Keep last 1000 lines
+            max_history: DEFAULT_SCROLLBACK_LINES,
+            max_line_bytes: DEFAULT_MAX_LINE_BYTES,
+            truncated_lines: 0,
+            echo_filter: EchoFilter::default(),
+            pending_sentinels: VecDeque::new(),
+            queued_commands: VecDeque::new(),
+            baseline: None,
         }
     }
 
     pub fn add_line(&mut self, line: TerminalLine) {
         if self.history.len() >= self.max_history {
             self.history.pop_front();
+            self.truncated_lines += 1;
         }
-        self.history.push_back(line);
+        self.history.push_back(line.capped(self.max_line_bytes));
+    }
+
+    /// Keep at most `max_history` lines (at least one), dropping the oldest
+    /// beyond it now and as lines are added.
+    pub fn set_max_history(&mut self, max_history: usize) {
+        self.max_history = max_history.max(1);
+        while self.history.len() > self.max_history {
+            self.history.pop_front();
+            self.truncated_lines += 1;
+        }
+    }
+
+    /// Change the line length cap for lines added from now on.
+    pub fn set_max_line_bytes(&mut self, max_line_bytes: usize) {
+        self.max_line_bytes = max_line_bytes;
+    }
+
+    /// Queue `commands` to run after any already queued.
+    pub fn queue_commands(&mut self, commands: impl IntoIterator<Item = String>) {
+        self.queued_commands.extend(commands);
     }
 
     pub fn add_command(&mut self, command: String) {
+        self.echo_filter.expect(&command);
         self.add_line(TerminalLine::system(format!("$ {}", command)));
     }
 
+    /// Record a raw line read from the shell, dropping prompt/echo noise
+    /// and completion markers.
+    pub fn ingest_output(&mut self, output: &PtyOutput) {
+        let raw = output.text().trim_end_matches(['\n', '\r']);
+        let line = raw.trim();
+        if self.take_sentinel(line) || !self.echo_filter.accept(raw) {
+            return;
+        }
+
+        match output {
+            PtyOutput::Stdout(_) => self.add_output(line.to_string()),
+            PtyOutput::Stderr(_) => self.add_error(line.to_string()),
+        }
+    }
+
+    /// Start waiting for `marker`, echoed by the shell once the command
+    /// sent before it has finished.
+    pub fn expect_sentinel(&mut self, marker: String) {
+        self.pending_sentinels.push_back(marker);
+    }
+
+    /// Whether a command sent to the shell hasn't finished yet.
+    pub fn is_command_running(&self) -> bool {
+        !self.pending_sentinels.is_empty()
+    }
+
+    // Returns `true` for lines carrying a marker (the marker itself, or the
+    // shell echoing the command that prints it), completing the command
+    // once the marker arrives on its own
+    fn take_sentinel(&mut self, line: &str) -> bool {
+        let Some(index) = self
+            .pending_sentinels
+            .iter()
+            .position(|marker| line.contains(marker.as_str()))
+        else {
+            return false;
+        };
+        if line == self.pending_sentinels[index] {
+            // Markers arrive in order, so anything before it is finished too
+            self.pending_sentinels.drain(..=index);
+        }
+        true
+    }
+
     pub fn add_output(&mut self, output: String) {
         // Split multi-line output into separate lines
         for line in output.lines() {
@@ -86,6 +394,11 @@ impl TerminalSession {
         }
     }
 
+    /// Add a single output line verbatim, keeping blank lines.
+    pub fn add_output_line(&mut self, line: String) {
+        self.add_line(TerminalLine::output(line));
+    }
+
     pub fn add_error(&mut self, error: String) {
         for line in error.lines() {
             self.add_line(TerminalLine::error(line.to_string()));
@@ -109,6 +422,62 @@ impl TerminalSession {
 
     pub fn clear_history(&mut self) {
         self.history.clear();
+        self.truncated_lines = 0;
+    }
+
+    /// The history lines in `range` as clipboard text, one per line.
+    /// Truncated lines are copied in full.
+    pub fn copy_text(&self, range: std::ops::Range<usize>) -> String {
+        let end = range.end.min(self.history.len());
+        let start = range.start.min(end);
+        let lines: Vec<&str> = self
+            .history
+            .range(start..end)
+            .map(TerminalLine::full_content)
+            .collect();
+        lines.join("\n")
+    }
+
+    /// Number of lines dropped because the scrollback is capped.
+    pub fn truncated_lines(&self) -> usize {
+        self.truncated_lines
+    }
+
+    /// Render the scrollback in `format`. Text exports start with a note if
+    /// earlier lines were dropped.
+    pub fn render_scrollback(&self, format: ExportFormat) -> Result<String> {
+        match format {
+            ExportFormat::Text { timestamps } => {
+                let mut text = String::new();
+                if self.truncated_lines > 0 {
+                    text.push_str(&format!(
+                        "[{} earlier lines not included: scrollback is limited to {} lines]\n",
+                        self.truncated_lines, self.max_history
+                    ));
+                }
+                for line in &self.history {
+                    if timestamps {
+                        let time = line.timestamp.with_timezone(&chrono::Local);
+                        text.push_str(&format!("[{}] ", time.format("%H:%M:%S")));
+                    }
+                    text.push_str(&line.content);
+                    text.push('\n');
+                }
+                Ok(text)
+            }
+            ExportFormat::Json => Ok(serde_json::to_string_pretty(&ScrollbackExport {
+                title: &self.title,
+                truncated_lines: self.truncated_lines,
+                lines: &self.history,
+            })?),
+        }
+    }
+
+    /// Write the full scrollback to `path`.
+    pub fn export_scrollback(&self, path: &Path, format: ExportFormat) -> Result<()> {
+        let contents = self.render_scrollback(format)?;
+        std::fs::write(path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))
     }
 }
 
@@ -116,14 +485,39 @@ pub struct TerminalEmulator {
     pub process_manager: ProcessManager,
     pub sessions: Vec<TerminalSession>,
     pub active_session: usize,
+    // Printed at the top of each new session
+    pub banner: Banner,
+    // Expanded before commands are sent to the shell (the `aliases` setting)
+    pub aliases: HashMap<String, String>,
 }
 
 impl TerminalEmulator {
     pub fn new() -> Self {
+        Self::with_banner(Banner::Default)
+    }
+
+    /// An emulator with the banner, aliases and line ending from `settings`.
+    pub fn from_settings(settings: &Settings) -> Self {
+        let mut emulator = Self::with_banner(settings.terminal_banner());
+        emulator.apply_settings(settings);
+        emulator
+    }
+
+    /// Pick up changes to `settings` made since the emulator was built.
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.banner = settings.terminal_banner();
+        self.aliases = settings.aliases.clone();
+        self.process_manager
+            .set_line_ending(settings.shell_line_ending);
+    }
+
+    pub fn with_banner(banner: Banner) -> Self {
         let mut emulator = Self {
             process_manager: ProcessManager::new(),
             sessions: Vec::new(),
             active_session: 0,
+            banner,
+            aliases: HashMap::new(),
         };
 
         // Create a default terminal session
@@ -139,13 +533,10 @@ impl TerminalEmulator {
 
         let session_title = format!("Terminal {}", self.sessions.len() + 1);
         let mut session = TerminalSession::new(terminal_id, session_title);
-
-        // Add welcome message
-        session.add_system_message("Terminal session started".to_string());
-        session.add_system_message(format!(
-            "Working directory: {}",
-            session.working_directory.display()
-        ));
+        let shell = PseudoTerminal::get_default_shell();
+        for line in self.banner.lines(&shell, &session.working_directory) {
+            session.add_system_message(line);
+        }
 
         self.sessions.push(session);
 
@@ -212,20 +603,135 @@ impl TerminalEmulator {
         }
     }
 
-    pub async fn execute_command(&mut self, command: &str) -> Result<()> {
-        if let Some(session) = self.get_active_session_mut() {
-            // Add command to history
-            session.add_command(command.to_string());
+    /// Whether the shell behind session `index` is still alive.
+    pub fn is_session_running(&mut self, index: usize) -> bool {
+        match self.sessions.get(index) {
+            Some(session) => self.process_manager.is_terminal_running(&session.id),
+            None => false,
+        }
+    }
 
-            // Clear current input
-            session.current_input.clear();
+    /// Start a new shell for session `index`, keeping its title and history.
+    pub fn restart_session(&mut self, index: usize) -> Result<()> {
+        let session = self
+            .sessions
+            .get_mut(index)
+            .ok_or_else(|| anyhow::anyhow!("Session index {} out of bounds", index))?;
 
-            // Execute command
-            self.process_manager.send_input_to_active(command).await?;
+        self.process_manager.restart_terminal(session.id)?;
+        // Commands sent to the old shell will never report back
+        session.pending_sentinels.clear();
+        session.add_system_message("Session restarted".to_string());
+        if session.is_active {
+            self.process_manager.set_active_terminal(session.id)?;
+        }
+        Ok(())
+    }
 
-            Ok(())
+    /// Send what's typed to every session instead of just the active one,
+    /// noting the change in each session.
+    pub fn set_broadcast(&mut self, broadcast: bool) {
+        self.process_manager.set_broadcast(broadcast);
+        let note = if broadcast {
+            format!(
+                "Broadcast on: input goes to all {} sessions",
+                self.sessions.len()
+            )
         } else {
-            Err(anyhow::anyhow!("No active terminal session"))
+            "Broadcast off".to_string()
+        };
+        for session in &mut self.sessions {
+            session.add_system_message(note.clone());
+        }
+    }
+
+    /// What to show while broadcast is on, so it isn't left on by mistake.
+    pub fn broadcast_indicator(&self) -> Option<String> {
+        self.process_manager
+            .is_broadcast()
+            .then(|| format!("BROADCAST to {} sessions", self.sessions.len()))
+    }
+
+    /// Whether `command` would be broadcast and looks destructive, so
+    /// `execute_command` refuses it until confirmed.
+    pub fn needs_broadcast_confirmation(&self, command: &str) -> bool {
+        self.process_manager.is_broadcast() && looks_destructive(command)
+    }
+
+    pub async fn execute_command(&mut self, command: &str) -> Result<()> {
+        if self.needs_broadcast_confirmation(command) {
+            return Err(anyhow::anyhow!(
+                "Not broadcasting '{}' to {} sessions without confirmation",
+                command,
+                self.sessions.len()
+            ));
+        }
+        self.execute_confirmed(command).await
+    }
+
+    /// Run `command`, broadcasting it even if it looks destructive, once the
+    /// user has confirmed it.
+    pub async fn execute_confirmed(&mut self, command: &str) -> Result<()> {
+        if self.get_active_session().is_none() {
+            return Err(anyhow::anyhow!("No active terminal session"));
+        }
+        // Follow the command with an echo of a unique marker; the command
+        // has finished once the marker is printed
+        let marker = format!("__llm_terminal_done_{}__", Uuid::new_v4().simple());
+        let broadcast = self.process_manager.is_broadcast();
+        for (index, session) in self.sessions.iter_mut().enumerate() {
+            if broadcast || index == self.active_session {
+                session.add_command(command.to_string());
+                session.current_input.clear();
+                session.expect_sentinel(marker.clone());
+            }
+        }
+
+        // Execute command
+        let command = expand_alias(command, &[&self.aliases]);
+        self.process_manager.send_input_to_active(&command).await?;
+        self.process_manager
+            .send_input_to_active(&format!("echo {}", marker))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Run the commands queued in the active session through
+    /// `execute_command`, each once the one before it has finished.
+    pub async fn run_queued(&mut self) -> Result<()> {
+        loop {
+            self.wait_for_idle().await;
+            let Some(command) = self
+                .get_active_session_mut()
+                .and_then(|session| session.queued_commands.pop_front())
+            else {
+                return Ok(());
+            };
+            self.execute_command(&command).await?;
+        }
+    }
+
+    /// Whether a command sent to the active session is still running.
+    pub fn is_command_running(&self) -> bool {
+        self.get_active_session()
+            .is_some_and(|session| session.is_command_running())
+    }
+
+    /// Read the active shell's output until every command sent to it has
+    /// finished. Output is recorded as it arrives, so this can be cancelled
+    /// (e.g. by a timeout) and called again without losing anything.
+    pub async fn wait_for_idle(&mut self) {
+        while self.is_command_running() {
+            let output = self.process_manager.read_output_from_active().await;
+            let Some(session) = self.get_active_session_mut() else {
+                return;
+            };
+            match output {
+                Some(output) => session.ingest_output(&output),
+                // The shell exited; its commands won't report back
+                None => session.pending_sentinels.clear(),
+            }
         }
     }
 
@@ -233,11 +739,7 @@ impl TerminalEmulator {
         // Read output from active terminal
         while let Some(output) = self.process_manager.read_output_from_active().await {
             if let Some(session) = self.get_active_session_mut() {
-                if let Some(stripped) = output.strip_prefix("ERROR:") {
-                    session.add_error(stripped.trim().to_string());
-                } else {
-                    session.add_output(output.trim().to_string());
-                }
+                session.ingest_output(&output);
             }
         }
 
@@ -303,3 +805,438 @@ impl Default for TerminalEmulator {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::first_code_block;
+    use crate::terminal::shell::LineEnding;
+
+    fn contents(session: &TerminalSession) -> Vec<&str> {
+        session.history.iter().map(|l| l.content.as_str()).collect()
+    }
+
+    fn stdout(line: &str) -> PtyOutput {
+        PtyOutput::Stdout(line.to_string())
+    }
+
+    fn stderr(line: &str) -> PtyOutput {
+        PtyOutput::Stderr(line.to_string())
+    }
+
+    #[test]
+    fn test_echoing_shell_output_is_deduplicated() {
+        let mut session = TerminalSession::new(Uuid::new_v4(), "test".into());
+        session.add_command("ls".into());
+
+        // What an interactive shell writes back: prompt + echo, output, prompt
+        for raw in [
+            stderr("user@host:~/src$ ls\n"),
+            stdout("Cargo.toml\n"),
+            stdout("src\n"),
+            stderr("user@host:~/src$ \n"),
+        ] {
+            session.ingest_output(&raw);
+        }
+
+        assert_eq!(contents(&session), vec!["$ ls", "Cargo.toml", "src"]);
+    }
+
+    #[test]
+    fn test_echo_only_suppressed_once() {
+        let mut session = TerminalSession::new(Uuid::new_v4(), "test".into());
+        session.add_command("echo hi".into());
+        session.ingest_output(&stdout("echo hi\n"));
+        // A later line that happens to match is real output
+        session.ingest_output(&stdout("echo hi\n"));
+        session.ingest_output(&stdout("PS C:\\Users\\me>\n"));
+        session.ingest_output(&stdout("bash-5.2$ \n"));
+        session.ingest_output(&stdout("100%\n"));
+        // A bare symbol is output unless it's a prompt waiting for input
+        session.ingest_output(&stdout("$\n"));
+        session.ingest_output(&stdout("$ \n"));
+
+        assert_eq!(
+            contents(&session),
+            vec!["$ echo hi", "echo hi", "100%", "$"]
+        );
+    }
+
+    #[test]
+    fn test_echo_expected_only_on_next_line() {
+        let mut session = TerminalSession::new(Uuid::new_v4(), "test".into());
+        // A shell with echo off: the expected echo expires unseen
+        session.add_command("make".into());
+        session.ingest_output(&stdout("Building\n"));
+        session.ingest_output(&stdout("make\n"));
+        assert_eq!(contents(&session), vec!["$ make", "Building", "make"]);
+
+        let mut filter = EchoFilter::default();
+        for n in 0..100 {
+            filter.expect(&format!("echo {}", n));
+        }
+        assert_eq!(filter.pending.len(), MAX_PENDING_ECHOES);
+    }
+
+    #[test]
+    fn test_stream_decides_line_type() {
+        let mut session = TerminalSession::new(Uuid::new_v4(), "test".into());
+        session.ingest_output(&stdout("ERROR: 3 tests failed\n"));
+        session.ingest_output(&stderr("warning: unused variable\n"));
+
+        let lines: Vec<(&str, TerminalLineType)> = session
+            .history
+            .iter()
+            .map(|l| (l.content.as_str(), l.line_type))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                ("ERROR: 3 tests failed", TerminalLineType::Output),
+                ("warning: unused variable", TerminalLineType::Error),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wrap_line() {
+        assert_eq!(wrap_line("short", 10), vec!["short"]);
+        assert_eq!(
+            wrap_line("the quick brown fox jumps", 10),
+            vec!["the quick", "brown fox", "jumps"]
+        );
+        // Words longer than the width are split
+        assert_eq!(
+            wrap_line("abcdefghijklmnop", 6),
+            vec!["abcdef", "ghijkl", "mnop"]
+        );
+        // Continuation rows keep the line's indentation
+        assert_eq!(
+            wrap_line("    -rw-r--r-- 1 user staff", 14),
+            vec!["    -rw-r--r--", "    1 user", "    staff"]
+        );
+        assert_eq!(wrap_line("", 5), vec![""]);
+        assert_eq!(wrap_line("é€ü", 1), vec!["é", "€", "ü"]);
+    }
+
+    #[test]
+    fn test_copy_text() {
+        let mut session = TerminalSession::new(Uuid::new_v4(), "test".into());
+        session.set_max_line_bytes(8);
+        session.add_command("ls".into());
+        session.add_output("a-very-long-file-name.txt".into());
+        session.add_error("ls: cannot access 'x'".into());
+
+        assert_eq!(
+            session.copy_text(1..3),
+            "a-very-long-file-name.txt\nls: cannot access 'x'"
+        );
+        assert_eq!(session.copy_text(0..1), "$ ls");
+        assert_eq!(session.copy_text(2..10), "ls: cannot access 'x'");
+        assert_eq!(session.copy_text(5..10), "");
+    }
+
+    #[test]
+    fn test_banner_lines() {
+        let cwd = Path::new("/tmp/work");
+        let default = Banner::Default.lines("bash", cwd);
+        assert_eq!(default[0], "Terminal session started");
+        assert!(default[1].starts_with("Shell: bash on "));
+        assert_eq!(default[2], "Working directory: /tmp/work");
+
+        assert_eq!(
+            Banner::Custom("Welcome back!\n  $PWD is yours".into()).lines("bash", cwd),
+            vec!["Welcome back!", "  $PWD is yours"]
+        );
+        assert!(Banner::Custom(String::new()).lines("bash", cwd).is_empty());
+        assert!(Banner::Disabled.lines("bash", cwd).is_empty());
+    }
+
+    #[test]
+    fn test_collapse_repeats() {
+        let lines = vec![
+            TerminalLine::system("$ watch date".into()),
+            TerminalLine::output("tick".into()),
+            TerminalLine::output("tick".into()),
+            TerminalLine::output("tick".into()),
+            TerminalLine::error("tick".into()),
+            TerminalLine::output("tock".into()),
+            TerminalLine::output("tick".into()),
+            TerminalLine::output("tick".into()),
+        ];
+        let runs: Vec<(&str, TerminalLineType, usize)> = collapse_repeats(&lines)
+            .into_iter()
+            .map(|(line, count)| (line.content.as_str(), line.line_type, count))
+            .collect();
+        assert_eq!(
+            runs,
+            vec![
+                ("$ watch date", TerminalLineType::System, 1),
+                ("tick", TerminalLineType::Output, 3),
+                ("tick", TerminalLineType::Error, 1),
+                ("tock", TerminalLineType::Output, 1),
+                ("tick", TerminalLineType::Output, 2),
+            ]
+        );
+        assert!(collapse_repeats(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_long_line_is_capped() {
+        let mut session = TerminalSession::new(Uuid::new_v4(), "test".into());
+        let blob = "x".repeat(1024 * 1024);
+        session.add_output(blob.clone());
+
+        let line = &session.history[0];
+        assert!(line.is_truncated());
+        assert_eq!(
+            line.content,
+            format!(
+                "{}…(truncated, 1048576 bytes)",
+                &blob[..DEFAULT_MAX_LINE_BYTES]
+            )
+        );
+        assert_eq!(line.full_content(), blob);
+
+        // Cuts never split a multi-byte character
+        session.set_max_line_bytes(4);
+        session.add_output("ééé".into());
+        assert_eq!(session.history[1].content, "éé…(truncated, 6 bytes)");
+        session.add_output("ok".into());
+        assert!(!session.history[2].is_truncated());
+    }
+
+    #[test]
+    fn test_export_text() {
+        let mut session = TerminalSession::new(Uuid::new_v4(), "test".into());
+        session.add_command("ls".into());
+        session.add_output("a.txt".into());
+
+        let text = session
+            .render_scrollback(ExportFormat::Text { timestamps: false })
+            .unwrap();
+        assert_eq!(text, "$ ls\na.txt\n");
+
+        let text = session
+            .render_scrollback(ExportFormat::Text { timestamps: true })
+            .unwrap();
+        let first = text.lines().next().unwrap();
+        assert!(Regex::new(r"^\[\d{2}:\d{2}:\d{2}\] \$ ls$")
+            .unwrap()
+            .is_match(first));
+    }
+
+    #[test]
+    fn test_export_json_line_types() {
+        let mut session = TerminalSession::new(Uuid::new_v4(), "test".into());
+        session.add_command("ls".into());
+        session.add_output("a.txt".into());
+        session.add_error("denied".into());
+
+        let json: serde_json::Value =
+            serde_json::from_str(&session.render_scrollback(ExportFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["title"], "test");
+        assert_eq!(json["truncated_lines"], 0);
+        let types: Vec<&str> = json["lines"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|line| line["line_type"].as_str().unwrap())
+            .collect();
+        assert_eq!(types, vec!["system", "output", "error"]);
+        assert!(json["lines"][0]["timestamp"].is_string());
+    }
+
+    #[test]
+    fn test_export_notes_truncation() {
+        let mut session = TerminalSession::new(Uuid::new_v4(), "test".into());
+        for i in 0..1005 {
+            session.add_output(format!("line {}", i));
+        }
+        assert_eq!(session.truncated_lines(), 5);
+
+        let text = session
+            .render_scrollback(ExportFormat::Text { timestamps: false })
+            .unwrap();
+        assert!(text.starts_with("[5 earlier lines not included"));
+        assert!(text.contains("\nline 5\n"));
+
+        let path = std::env::temp_dir().join(format!("scrollback-{}.json", Uuid::new_v4()));
+        session
+            .export_scrollback(&path, ExportFormat::Json)
+            .unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["truncated_lines"], 5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sentinel_marks_command_finished() {
+        let mut session = TerminalSession::new(Uuid::new_v4(), "test".into());
+        session.add_command("make".into());
+        session.expect_sentinel("__done_1__".into());
+        assert!(session.is_command_running());
+
+        session.ingest_output(&stdout("building\n"));
+        session.ingest_output(&stdout("echo __done_1__\n"));
+        assert!(session.is_command_running());
+        session.ingest_output(&stdout("__done_1__\n"));
+        assert!(!session.is_command_running());
+
+        assert_eq!(contents(&session), vec!["$ make", "building"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_wait_for_idle_after_command_output() {
+        let mut emulator = TerminalEmulator::new();
+        emulator.execute_command("echo first").await.unwrap();
+        emulator.execute_command("echo second").await.unwrap();
+        assert!(emulator.is_command_running());
+
+        tokio::time::timeout(std::time::Duration::from_secs(10), emulator.wait_for_idle())
+            .await
+            .expect("commands should finish");
+        assert!(!emulator.is_command_running());
+
+        let lines = contents(&emulator.sessions[0]);
+        assert!(lines.ends_with(&["$ echo first", "$ echo second", "first", "second"]));
+        assert!(!lines
+            .iter()
+            .any(|line| line.contains("__llm_terminal_done_")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reply_script_runs_as_one_command() {
+        let reply = "Run these:\n```sh\necho one\necho two\n```";
+        let mut emulator = TerminalEmulator::with_banner(Banner::Disabled);
+        emulator.sessions[0].queue_commands(first_code_block(reply));
+
+        tokio::time::timeout(std::time::Duration::from_secs(10), emulator.run_queued())
+            .await
+            .expect("commands should finish")
+            .unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(10), emulator.wait_for_idle())
+            .await
+            .expect("commands should finish");
+        let lines = contents(&emulator.sessions[0]);
+        assert_eq!(lines, vec!["$ echo one\necho two", "one", "two"]);
+        assert!(emulator.sessions[0].queued_commands.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_commands_expand_aliases() {
+        let mut settings = Settings::default();
+        settings
+            .aliases
+            .insert("greet".to_string(), "echo hello".to_string());
+        let mut emulator = TerminalEmulator::from_settings(&settings);
+        emulator.execute_command("greet world").await.unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(10), emulator.wait_for_idle())
+            .await
+            .expect("command should finish");
+        let lines = contents(&emulator.sessions[0]);
+        assert!(lines.ends_with(&["$ greet world", "hello world"]));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_line_ending_setting_reaches_every_shell() {
+        let line_endings = |emulator: &TerminalEmulator| -> Vec<LineEnding> {
+            emulator
+                .sessions
+                .iter()
+                .map(|session| {
+                    emulator
+                        .process_manager
+                        .get_terminal(&session.id)
+                        .unwrap()
+                        .line_ending()
+                })
+                .collect()
+        };
+        let mut settings = Settings {
+            shell_line_ending: Some(LineEnding::Crlf),
+            ..Default::default()
+        };
+        let mut emulator = TerminalEmulator::from_settings(&settings);
+        emulator.create_session().unwrap();
+        emulator.restart_session(0).unwrap();
+        assert_eq!(line_endings(&emulator), [LineEnding::Crlf; 2]);
+
+        settings.shell_line_ending = None;
+        emulator.apply_settings(&settings);
+        assert_eq!(line_endings(&emulator), [LineEnding::Lf; 2]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_broadcast_reaches_every_session() {
+        async fn finish(emulator: &mut TerminalEmulator, index: usize) {
+            emulator.set_active_session(index).unwrap();
+            tokio::time::timeout(std::time::Duration::from_secs(10), emulator.wait_for_idle())
+                .await
+                .expect("command should finish");
+        }
+        let mut emulator = TerminalEmulator::with_banner(Banner::Disabled);
+        emulator.create_session().unwrap();
+
+        emulator.execute_command("echo only-active").await.unwrap();
+        assert!(!emulator.sessions[1].is_command_running());
+        finish(&mut emulator, 0).await;
+        assert!(contents(&emulator.sessions[0]).ends_with(&["$ echo only-active", "only-active"]));
+        assert!(contents(&emulator.sessions[1]).is_empty());
+
+        emulator.set_broadcast(true);
+        assert_eq!(
+            emulator.broadcast_indicator().as_deref(),
+            Some("BROADCAST to 2 sessions")
+        );
+        emulator.execute_command("echo to-all").await.unwrap();
+        finish(&mut emulator, 0).await;
+        finish(&mut emulator, 1).await;
+        for session in &emulator.sessions {
+            assert!(contents(session).ends_with(&["$ echo to-all", "to-all"]));
+        }
+
+        assert!(emulator.execute_command("rm -rf scratch").await.is_err());
+        assert!(emulator
+            .sessions
+            .iter()
+            .all(|session| !session.is_command_running()));
+        emulator.set_broadcast(false);
+        assert!(emulator.broadcast_indicator().is_none());
+        assert!(!emulator.needs_broadcast_confirmation("rm -rf scratch"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_restart_dead_session_keeps_history() {
+        let mut emulator = TerminalEmulator::new();
+        emulator.execute_command("exit").await.unwrap();
+
+        for _ in 0..100 {
+            if !emulator.is_session_running(0) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(!emulator.is_session_running(0));
+        let id = emulator.sessions[0].id;
+
+        emulator.restart_session(0).unwrap();
+        assert!(emulator.is_session_running(0));
+        assert_eq!(emulator.sessions[0].id, id);
+        assert_eq!(emulator.sessions[0].title, "Terminal 1");
+
+        let lines = contents(&emulator.sessions[0]);
+        assert_eq!(lines[0], "Terminal session started");
+        assert!(lines.contains(&"$ exit"));
+        assert_eq!(lines.last(), Some(&"Session restarted"));
+        assert!(emulator.restart_session(5).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+pub mod diff;
 pub mod emulator;
 pub mod process;
 pub mod pty;
+pub mod shell;
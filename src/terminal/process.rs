@@ -1,5 +1,6 @@
 #![allow(dead_code)]
-use super::pty::PseudoTerminal;
+use super::pty::{PseudoTerminal, PtyOutput};
+use super::shell::LineEnding;
 use anyhow::Result;
 use std::collections::HashMap;
 use uuid::Uuid;
@@ -7,6 +8,10 @@ use uuid::Uuid;
 pub struct ProcessManager {
     terminals: HashMap<Uuid, PseudoTerminal>,
     active_terminal: Option<Uuid>,
+    // Input for the active terminal goes to every terminal
+    broadcast: bool,
+    // Given to every terminal in place of the one picked for its shell
+    line_ending: Option<LineEnding>,
 }
 
 impl ProcessManager {
@@ -14,12 +19,15 @@ impl ProcessManager {
         Self {
             terminals: HashMap::new(),
             active_terminal: None,
+            broadcast: false,
+            line_ending: None,
         }
     }
 
     pub fn create_terminal(&mut self) -> Result<Uuid> {
         let terminal_id = Uuid::new_v4();
-        let pty = PseudoTerminal::new()?;
+        let mut pty = PseudoTerminal::new()?;
+        pty.set_line_ending(self.line_ending);
 
         self.terminals.insert(terminal_id, pty);
 
@@ -31,6 +39,25 @@ impl ProcessManager {
         Ok(terminal_id)
     }
 
+    /// Start a fresh shell under an existing id, replacing (and killing) any
+    /// terminal still registered there.
+    pub fn restart_terminal(&mut self, id: Uuid) -> Result<()> {
+        let mut pty = PseudoTerminal::new()?;
+        pty.set_line_ending(self.line_ending);
+        self.terminals.insert(id, pty);
+        if self.active_terminal.is_none() {
+            self.active_terminal = Some(id);
+        }
+        Ok(())
+    }
+
+    pub fn is_terminal_running(&mut self, id: &Uuid) -> bool {
+        self.terminals
+            .get_mut(id)
+            .map(|terminal| terminal.is_running())
+            .unwrap_or(false)
+    }
+
     pub fn get_terminal(&self, id: &Uuid) -> Option<&PseudoTerminal> {
         self.terminals.get(id)
     }
@@ -81,7 +108,29 @@ impl ProcessManager {
         self.terminals.len()
     }
 
+    /// End input lines with `line_ending` in every terminal, now and when
+    /// created or restarted; `None` lets each use its shell's.
+    pub fn set_line_ending(&mut self, line_ending: Option<LineEnding>) {
+        self.line_ending = line_ending;
+        for terminal in self.terminals.values_mut() {
+            terminal.set_line_ending(line_ending);
+        }
+    }
+
+    pub fn set_broadcast(&mut self, broadcast: bool) {
+        self.broadcast = broadcast;
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.broadcast
+    }
+
+    /// Send `input` to the active terminal, or to every terminal while
+    /// broadcasting.
     pub async fn send_input_to_active(&self, input: &str) -> Result<()> {
+        if self.broadcast {
+            return self.send_input_to_all(input).await;
+        }
         if let Some(terminal) = self.get_active_terminal() {
             terminal.send_input(input).await
         } else {
@@ -89,7 +138,22 @@ impl ProcessManager {
         }
     }
 
-    pub async fn read_output_from_active(&mut self) -> Option<String> {
+    /// Send `input` to every terminal. One failing doesn't stop the rest
+    /// getting it; the first error is returned.
+    pub async fn send_input_to_all(&self, input: &str) -> Result<()> {
+        if self.terminals.is_empty() {
+            return Err(anyhow::anyhow!("No active terminal"));
+        }
+        let mut result = Ok(());
+        for terminal in self.terminals.values() {
+            if let Err(e) = terminal.send_input(input).await {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    pub async fn read_output_from_active(&mut self) -> Option<PtyOutput> {
         if let Some(terminal) = self.get_active_terminal_mut() {
             terminal.read_output().await
         } else {
@@ -1,15 +1,35 @@
 #![allow(dead_code)]
+use super::shell::LineEnding;
 use anyhow::{anyhow, Result};
 use std::process::Stdio;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as TokioBufReader};
 use tokio::process::{Child as TokioChild, Command as TokioCommand};
 use tokio::sync::mpsc;
 
+/// A line read from the shell, tagged with the stream it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PtyOutput {
+    Stdout(String),
+    Stderr(String),
+}
+
+impl PtyOutput {
+    pub fn text(&self) -> &str {
+        match self {
+            PtyOutput::Stdout(text) | PtyOutput::Stderr(text) => text,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PseudoTerminal {
     child: Option<TokioChild>,
-    output_receiver: mpsc::Receiver<String>,
+    output_receiver: mpsc::Receiver<PtyOutput>,
     input_sender: mpsc::Sender<String>,
+    line_ending: LineEnding,
+    // The ending picked for the shell, used again when an override is
+    // cleared
+    shell_line_ending: LineEnding,
 }
 
 impl PseudoTerminal {
@@ -17,7 +37,7 @@ impl PseudoTerminal {
         let working_directory = std::env::current_dir()?;
         let shell_command = Self::get_default_shell();
 
-        let (output_sender, output_receiver) = mpsc::channel::<String>(1000);
+        let (output_sender, output_receiver) = mpsc::channel::<PtyOutput>(1000);
         let (input_sender, mut input_receiver) = mpsc::channel::<String>(100);
 
         // Start the shell process
@@ -75,7 +95,11 @@ impl PseudoTerminal {
                 match reader.read_line(&mut line).await {
                     Ok(0) => break, // EOF
                     Ok(_) => {
-                        if output_sender_stdout.send(line.clone()).await.is_err() {
+                        if output_sender_stdout
+                            .send(PtyOutput::Stdout(line.clone()))
+                            .await
+                            .is_err()
+                        {
                             break;
                         }
                     }
@@ -98,7 +122,7 @@ impl PseudoTerminal {
                     Ok(0) => break, // EOF
                     Ok(_) => {
                         if output_sender
-                            .send(format!("ERROR: {}", line))
+                            .send(PtyOutput::Stderr(line.clone()))
                             .await
                             .is_err()
                         {
@@ -117,11 +141,13 @@ impl PseudoTerminal {
             child: Some(child),
             output_receiver,
             input_sender,
+            line_ending: LineEnding::for_shell(&shell_command),
+            shell_line_ending: LineEnding::for_shell(&shell_command),
         })
     }
 
     #[cfg(windows)]
-    fn get_default_shell() -> String {
+    pub fn get_default_shell() -> String {
         // Check if PowerShell Core (pwsh) is available, otherwise use PowerShell 5.1
         if which::which("pwsh").is_ok() {
             "pwsh".to_string()
@@ -131,25 +157,29 @@ impl PseudoTerminal {
     }
 
     #[cfg(not(windows))]
-    fn get_default_shell() -> String {
+    pub fn get_default_shell() -> String {
         std::env::var("SHELL").unwrap_or_else(|_| "/bin/bash".to_string())
     }
 
-    pub async fn send_input(&self, input: &str) -> Result<()> {
-        let input_with_newline = if input.ends_with('\n') {
-            input.to_string()
-        } else {
-            format!("{}\n", input)
-        };
+    /// Override the line ending picked for the shell, or go back to it
+    /// with `None`.
+    pub fn set_line_ending(&mut self, line_ending: Option<LineEnding>) {
+        self.line_ending = line_ending.unwrap_or(self.shell_line_ending);
+    }
 
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    pub async fn send_input(&self, input: &str) -> Result<()> {
         self.input_sender
-            .send(input_with_newline)
+            .send(self.line_ending.terminate(input))
             .await
             .map_err(|_| anyhow!("Failed to send input to terminal"))?;
         Ok(())
     }
 
-    pub async fn read_output(&mut self) -> Option<String> {
+    pub async fn read_output(&mut self) -> Option<PtyOutput> {
         self.output_receiver.recv().await
     }
 
@@ -0,0 +1,1170 @@
+use crate::models::CommandRun;
+use anyhow::{anyhow, Result};
+use encoding_rs::Encoding;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+
+// How long output must be quiet, with a partial line pending, before the
+// command is considered to be waiting for input
+const PROMPT_QUIET: Duration = Duration::from_millis(150);
+
+// However long it waits at prompts, an interactive command is killed once it
+// has run for this many times its timeout
+const PROMPT_WAIT_FACTOR: u32 = 10;
+
+/// The shell one-shot commands run in: PowerShell on Windows, sh on
+/// Unix-like systems.
+pub fn shell_name() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "powershell"
+    } else {
+        "sh"
+    }
+}
+
+/// What ends each line written to a shell's input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    /// The ending `shell` expects: CRLF for cmd and PowerShell on Windows,
+    /// LF for everything else.
+    pub fn for_shell(shell: &str) -> Self {
+        let name = Path::new(shell)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(shell)
+            .to_ascii_lowercase();
+        let windows_shell = matches!(name.as_str(), "cmd" | "powershell" | "pwsh");
+        if cfg!(target_os = "windows") && windows_shell {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+
+    /// `input` as shell input: every line, including the last, ends with
+    /// this ending, whatever endings it came with.
+    pub fn terminate(self, input: &str) -> String {
+        let input = input.replace("\r\n", "\n").replace('\r', "\n");
+        let input = input.strip_suffix('\n').unwrap_or(&input);
+        let mut out = input.replace('\n', self.as_str());
+        out.push_str(self.as_str());
+        out
+    }
+}
+
+// Build the platform shell invocation for a one-shot command
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new(shell_name());
+    if cfg!(target_os = "windows") {
+        cmd.arg("-Command").arg(command);
+    } else {
+        cmd.arg("-c").arg(command);
+    }
+    cmd
+}
+
+/// `command` with a leading alias replaced by its definition, and then any
+/// alias the definition starts with, keeping the rest of the command as
+/// arguments (`gs foo` becomes `git status foo`). Each alias is expanded at
+/// most once, so `ls` = `ls -la`, or two aliases naming each other, can't
+/// loop. Earlier tables take precedence.
+pub fn expand_alias(command: &str, tables: &[&HashMap<String, String>]) -> String {
+    let mut command = command.to_string();
+    let mut used = HashSet::new();
+    loop {
+        let trimmed = command.trim_start();
+        let name_end = trimmed
+            .find(|c: char| c.is_whitespace() || c == ';' || c == '&' || c == '|')
+            .unwrap_or(trimmed.len());
+        let name = &trimmed[..name_end];
+        if used.contains(name) {
+            return command;
+        }
+        let Some(expansion) = tables.iter().find_map(|table| table.get(name)) else {
+            return command;
+        };
+        used.insert(name.to_string());
+        command = format!("{}{}", expansion, &trimmed[name_end..]);
+    }
+}
+
+/// Whether `command` deletes, overwrites or shuts things down in a way that
+/// can't be undone, e.g. `rm -rf` (or `-R`), `dd`, `mkfs` or
+/// `git push --force`.
+pub fn looks_destructive(command: &str) -> bool {
+    split_commands(command).iter().any(|command| {
+        let words = shell_words(command);
+        let words: Vec<&str> = words
+            .iter()
+            .map(String::as_str)
+            .skip_while(|word| *word == "sudo")
+            .collect();
+        let has_flag = |short: char, long: &str| {
+            words.iter().any(|word| {
+                *word == long
+                    || (word.starts_with('-') && !word.starts_with("--") && word.contains(short))
+            })
+        };
+        match words.as_slice() {
+            ["rm", ..] => {
+                has_flag('r', "--recursive")
+                    || has_flag('R', "--recursive")
+                    || has_flag('f', "--force")
+            }
+            ["dd" | "shred" | "shutdown" | "reboot" | "halt" | "poweroff", ..] => true,
+            [program, ..] if program.starts_with("mkfs") => true,
+            ["git", "push", ..] => has_flag('f', "--force"),
+            ["git", "reset", ..] => words.contains(&"--hard"),
+            ["git", "clean", ..] => has_flag('f', "--force"),
+            // Writing over a disk
+            _ => command.replace(' ', "").contains(">/dev/sd"),
+        }
+    })
+}
+
+// Builtins that only change the shell they run in, which exits with the
+// command, and that `ShellEnv` doesn't emulate
+const UNEMULATED_BUILTINS: &[&str] = &["source", ".", "set", "shopt", "umask", "ulimit"];
+
+/// Caps how many commands run at once. Clones share the cap; commands over
+/// it wait for one of the others to finish before they start.
+#[derive(Debug, Clone)]
+pub struct ExecutionLimit {
+    slots: Arc<Semaphore>,
+}
+
+impl ExecutionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max.max(1))),
+        }
+    }
+
+    // Wait for a free slot, held until the permit is dropped
+    async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("execution limit semaphore is never closed")
+    }
+}
+
+impl PartialEq for ExecutionLimit {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.slots, &other.slots)
+    }
+}
+
+/// State that shell builtins would normally keep between commands. Every
+/// command runs in a fresh shell, so `cd`, `export`, `unset`, `alias` and
+/// `unalias` are recorded here and applied to later commands instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellEnv {
+    pub cwd: PathBuf,
+    pub vars: HashMap<String, String>,
+    pub aliases: HashMap<String, String>,
+    // From the `aliases` setting; ones defined with `alias` take precedence
+    pub configured_aliases: HashMap<String, String>,
+    // Tried for output that isn't valid UTF-8
+    pub output_encoding: Option<&'static Encoding>,
+    // Ends each line of input sent to a running command
+    pub line_ending: LineEnding,
+    // Shared cap on commands run with `run_shell_command` at once
+    pub execution_limit: Option<ExecutionLimit>,
+}
+
+impl ShellEnv {
+    pub fn new(cwd: PathBuf) -> Self {
+        Self {
+            cwd,
+            vars: HashMap::new(),
+            aliases: HashMap::new(),
+            configured_aliases: HashMap::new(),
+            output_encoding: None,
+            line_ending: LineEnding::for_shell(shell_name()),
+            execution_limit: None,
+        }
+    }
+
+    /// Record the effect of the builtins in `command`, which has just run
+    /// successfully. Returns a warning for each builtin whose effect is lost
+    /// because it isn't emulated.
+    pub fn apply_builtins(&mut self, command: &str) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for simple in split_commands(command) {
+            let words = shell_words(&simple);
+            let Some((name, args)) = words.split_first() else {
+                continue;
+            };
+            match name.as_str() {
+                "cd" => self.change_dir(args.first().map(String::as_str)),
+                "export" => {
+                    for arg in args {
+                        if let Some((key, value)) = arg.split_once('=') {
+                            let value = self.expand_vars(value);
+                            self.vars.insert(key.to_string(), value);
+                        }
+                    }
+                }
+                "unset" => {
+                    for arg in args {
+                        self.vars.remove(arg);
+                    }
+                }
+                "alias" => {
+                    for arg in args {
+                        if let Some((alias, value)) = arg.split_once('=') {
+                            self.aliases.insert(alias.to_string(), value.to_string());
+                        }
+                    }
+                }
+                "unalias" => {
+                    for arg in args {
+                        self.aliases.remove(arg);
+                    }
+                }
+                builtin if UNEMULATED_BUILTINS.contains(&builtin) => warnings.push(format!(
+                    "`{}` has no lasting effect: each command runs in a new shell",
+                    builtin
+                )),
+                _ => {}
+            }
+        }
+        warnings
+    }
+
+    /// `command` with a leading alias expanded (see `expand_alias`).
+    pub fn expand_aliases(&self, command: &str) -> String {
+        expand_alias(command, &[&self.aliases, &self.configured_aliases])
+    }
+
+    fn change_dir(&mut self, target: Option<&str>) {
+        let target = match target {
+            None | Some("~") => dirs::home_dir(),
+            // `cd -` would need the previous directory
+            Some("-") => None,
+            Some(path) => {
+                let path = self.expand_vars(path);
+                let path = match path.strip_prefix("~/") {
+                    Some(rest) => dirs::home_dir().map(|home| home.join(rest)),
+                    None => Some(PathBuf::from(path)),
+                };
+                path.map(|path| self.cwd.join(path))
+            }
+        };
+        if let Some(dir) = target.filter(|dir| dir.is_dir()) {
+            self.cwd = normalize(&dir);
+        }
+    }
+
+    // Substitute `$NAME` and `${NAME}` from the recorded variables, then the
+    // app's own environment
+    fn expand_vars(&self, text: &str) -> String {
+        let mut out = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find('$') {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let (name, consumed) = match after.strip_prefix('{') {
+                Some(braced) => match braced.find('}') {
+                    Some(end) => (&braced[..end], end + 2),
+                    None => ("", 0),
+                },
+                None => {
+                    let end = after
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(after.len());
+                    (&after[..end], end)
+                }
+            };
+            if name.is_empty() {
+                out.push('$');
+                rest = after;
+                continue;
+            }
+            let value = self
+                .vars
+                .get(name)
+                .cloned()
+                .or_else(|| std::env::var(name).ok())
+                .unwrap_or_default();
+            out.push_str(&value);
+            rest = &after[consumed..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    // Run in the recorded directory with the recorded variables
+    fn configure(&self, cmd: &mut Command) {
+        cmd.current_dir(&self.cwd).envs(&self.vars);
+    }
+}
+
+// Resolve `.` and `..` without touching the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+// Split a command line into its simple commands at `;`, `&&`, `||`, `|`, `&`
+// and newlines outside quotes
+fn split_commands(command: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), _) if c == q => {
+                quote = None;
+                current.push(c);
+            }
+            (Some(_), _) => current.push(c),
+            (None, '\\') => {
+                current.push(c);
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                current.push(c);
+            }
+            (None, ';' | '\n' | '&' | '|') => {
+                if chars.peek() == Some(&c) {
+                    chars.next();
+                }
+                commands.push(std::mem::take(&mut current));
+            }
+            (None, _) => current.push(c),
+        }
+    }
+    commands.push(current);
+    commands.retain(|c| !c.trim().is_empty());
+    commands
+}
+
+// Split a simple command into words, removing quotes
+fn shell_words(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = None::<String>;
+    let mut quote = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, '\\') => {
+                if let Some(next) = chars.next() {
+                    word.get_or_insert_with(String::new).push(next);
+                }
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, _) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// Decode command output. Valid UTF-8 is used as is; anything else is
+/// decoded with `fallback` if it has one and the bytes are valid in it.
+/// Otherwise, and for output containing NUL bytes, the result is a
+/// `[binary output: N bytes]` label rather than replacement characters.
+pub fn decode_output(bytes: &[u8], fallback: Option<&'static Encoding>) -> String {
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+    let binary = || format!("[binary output: {} bytes]", bytes.len());
+    if bytes.contains(&0) {
+        return binary();
+    }
+    match fallback
+        .and_then(|encoding| encoding.decode_without_bom_handling_and_without_replacement(bytes))
+    {
+        Some(text) => text.into_owned(),
+        None => binary(),
+    }
+}
+
+// Language behind common interpreters and toolchains, for naming what to
+// install when one is missing
+const INTERPRETERS: &[(&str, &str)] = &[
+    ("python", "Python"),
+    ("python3", "Python"),
+    ("pip", "Python"),
+    ("pip3", "Python"),
+    ("node", "Node.js"),
+    ("npm", "Node.js"),
+    ("npx", "Node.js"),
+    ("deno", "Deno"),
+    ("ruby", "Ruby"),
+    ("perl", "Perl"),
+    ("php", "PHP"),
+    ("go", "Go"),
+    ("cargo", "Rust"),
+    ("rustc", "Rust"),
+    ("java", "Java"),
+    ("javac", "Java"),
+];
+
+// "sh: 1: foo: not found" (dash) or "sh: foo: command not found" (bash)
+static NOT_FOUND_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^\S+:(?: \d+:)? ([^:\s]+): (?:command )?not found").unwrap());
+
+/// A friendly explanation for a program that couldn't be found, naming the
+/// language to install when `program` is a known interpreter.
+pub fn missing_program_message(program: &str) -> String {
+    let name = Path::new(program)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(program);
+    match INTERPRETERS.iter().find(|(binary, _)| *binary == name) {
+        Some((_, language)) => format!(
+            "{} not found on PATH; install {} or check your PATH",
+            name, language
+        ),
+        None => format!(
+            "{} not found on PATH; install it or check the command name",
+            name
+        ),
+    }
+}
+
+// Turn a failure to start `program` into an error worth showing
+fn spawn_error(program: &str, error: std::io::Error) -> anyhow::Error {
+    if error.kind() == std::io::ErrorKind::NotFound {
+        anyhow!(missing_program_message(program))
+    } else {
+        anyhow!("Failed to start {}: {}", program, error)
+    }
+}
+
+// The shell exits with 127 and says "not found" when it can't find the
+// command; explain which program was missing. Other failures that exit
+// with 127 (such as a script's own `exit 127`) get no note
+fn missing_program_note(command: &str, exit_code: Option<i32>, output: &str) -> Option<String> {
+    if cfg!(target_os = "windows") || exit_code != Some(127) {
+        return None;
+    }
+    let program = match NOT_FOUND_RE.captures(output) {
+        Some(caps) => caps[1].to_string(),
+        // Shells that word it differently, such as zsh
+        None if output.contains("not found") => command.split_whitespace().next()?.to_string(),
+        None => return None,
+    };
+    Some(missing_program_message(&program))
+}
+
+// `output` with the note on a missing program, if any, below the shell's
+// own terse message
+fn explain_missing_program(command: &str, exit_code: Option<i32>, output: &mut String) {
+    if let Some(note) = missing_program_note(command, exit_code, output) {
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        output.push_str(&note);
+    }
+}
+
+// Result reported for a command killed at its timeout
+fn timed_out(command: &str, timeout: Duration) -> CommandRun {
+    CommandRun {
+        command: command.to_string(),
+        exit_code: None,
+        success: false,
+        output: format!("Command timed out after {:?} and was killed", timeout),
+        diff: None,
+    }
+}
+
+/// Run `command` to completion in a fresh shell process, set up from `env`,
+/// and capture its combined output and exit status. Only failing to spawn the shell is an
+/// error; a non-zero exit is reported through the returned `CommandRun`.
+/// When the command itself isn't installed the output says which program
+/// is missing.
+///
+/// The command gets no stdin. If it hasn't exited after `timeout` the
+/// process is killed and the run is reported as failed with no exit code.
+/// Under `env.execution_limit` the command may wait to start; `timeout`
+/// counts from when it does.
+pub async fn run_shell_command(
+    command: &str,
+    env: &ShellEnv,
+    timeout: Duration,
+) -> Result<CommandRun> {
+    let _slot = match &env.execution_limit {
+        Some(limit) => Some(limit.acquire().await),
+        None => None,
+    };
+    let mut cmd = shell_command(&env.expand_aliases(command));
+    env.configure(&mut cmd);
+    let child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| spawn_error(shell_name(), e))?;
+
+    // Dropping the unfinished future drops the child, which kills it
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(output) => output?,
+        Err(_) => {
+            return Ok(timed_out(command, timeout));
+        }
+    };
+
+    let stdout = decode_output(&output.stdout, env.output_encoding);
+    let stderr = decode_output(&output.stderr, env.output_encoding);
+
+    let mut combined = String::new();
+    if !stdout.is_empty() {
+        combined.push_str(&stdout);
+    }
+    if !stderr.is_empty() {
+        if !combined.is_empty() {
+            combined.push('\n');
+        }
+        combined.push_str(&stderr);
+    }
+    let mut combined = combined.trim().to_string();
+    explain_missing_program(command, output.status.code(), &mut combined);
+
+    Ok(CommandRun {
+        command: command.to_string(),
+        exit_code: output.status.code(),
+        success: output.status.success(),
+        output: combined,
+        diff: None,
+    })
+}
+
+/// Progress reported by `run_interactive_command` while the command runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandEvent {
+    /// A complete line of output (stdout or stderr)
+    Output(String),
+    /// The command printed `prompt` without a newline and has gone quiet
+    /// while still running, so it is most likely waiting for a response
+    AwaitingInput(String),
+}
+
+// Splits raw output into lines, holding back an unterminated tail
+#[derive(Default)]
+struct LineBuffer {
+    pending: Vec<u8>,
+    encoding: Option<&'static Encoding>,
+}
+
+impl LineBuffer {
+    fn push(&mut self, bytes: &[u8], on_event: &mut impl FnMut(CommandEvent)) {
+        self.pending.extend_from_slice(bytes);
+        while let Some(newline) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=newline).collect();
+            let line = decode_output(&line, self.encoding);
+            on_event(CommandEvent::Output(line.trim_end().to_string()));
+        }
+    }
+
+    // Hand out whatever partial line is buffered
+    fn take(&mut self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let partial = decode_output(&self.pending, self.encoding);
+        self.pending.clear();
+        Some(partial)
+    }
+}
+
+async fn read_chunk(reader: &mut Option<impl AsyncRead + Unpin>, buf: &mut [u8]) -> usize {
+    match reader {
+        Some(reader) => reader.read(buf).await.unwrap_or(0),
+        None => std::future::pending().await,
+    }
+}
+
+/// Like `run_shell_command`, but with stdin connected to `input` (each
+/// message is written as a line) and output reported through `on_event` as
+/// it arrives, so the caller can answer prompts from the running command.
+/// Time spent waiting at a prompt, from `AwaitingInput` until the next line
+/// of input, doesn't count towards `timeout`; the command is still killed
+/// after `PROMPT_WAIT_FACTOR` times `timeout` in all.
+pub async fn run_interactive_command(
+    command: &str,
+    env: &ShellEnv,
+    timeout: Duration,
+    mut input: mpsc::UnboundedReceiver<String>,
+    mut on_event: impl FnMut(CommandEvent),
+) -> Result<CommandRun> {
+    let mut cmd = shell_command(&env.expand_aliases(command));
+    env.configure(&mut cmd);
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| spawn_error(shell_name(), e))?;
+
+    let mut stdin = child.stdin.take();
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+    if stdout.is_none() || stderr.is_none() {
+        return Err(anyhow!("Failed to capture command output"));
+    }
+
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    let hard_limit = timeout.saturating_mul(PROMPT_WAIT_FACTOR);
+    let hard_deadline = tokio::time::sleep(hard_limit);
+    tokio::pin!(hard_deadline);
+
+    let mut output = Vec::new();
+    let mut lines = LineBuffer {
+        encoding: env.output_encoding,
+        ..Default::default()
+    };
+    let (mut out_buf, mut err_buf) = ([0u8; 4096], [0u8; 4096]);
+    let mut input_open = true;
+    // Time left before the timeout, kept while the command waits at a prompt
+    let mut paused: Option<Duration> = None;
+    let resume = |paused: &mut Option<Duration>,
+                  deadline: std::pin::Pin<&mut tokio::time::Sleep>| {
+        if let Some(remaining) = paused.take() {
+            deadline.reset(tokio::time::Instant::now() + remaining);
+        }
+    };
+
+    while stdout.is_some() || stderr.is_some() {
+        tokio::select! {
+            n = read_chunk(&mut stdout, &mut out_buf) => {
+                if n == 0 {
+                    stdout = None;
+                } else {
+                    output.extend_from_slice(&out_buf[..n]);
+                    lines.push(&out_buf[..n], &mut on_event);
+                }
+            }
+            n = read_chunk(&mut stderr, &mut err_buf) => {
+                if n == 0 {
+                    stderr = None;
+                } else {
+                    output.extend_from_slice(&err_buf[..n]);
+                    lines.push(&err_buf[..n], &mut on_event);
+                }
+            }
+            line = input.recv(), if input_open => match (line, stdin.as_mut()) {
+                (Some(line), Some(pipe)) => {
+                    resume(&mut paused, deadline.as_mut());
+                    let line = env.line_ending.terminate(&line);
+                    let written = pipe.write_all(line.as_bytes()).await;
+                    if written.is_err() || pipe.flush().await.is_err() {
+                        stdin = None;
+                    }
+                }
+                // Sender gone: close stdin so the command sees EOF
+                (None, _) => {
+                    input_open = false;
+                    stdin = None;
+                }
+                (Some(_), None) => {}
+            },
+            _ = tokio::time::sleep(PROMPT_QUIET), if !lines.pending.is_empty() => {
+                if let Some(prompt) = lines.take() {
+                    if paused.is_none() {
+                        let now = tokio::time::Instant::now();
+                        paused = Some(deadline.deadline().saturating_duration_since(now));
+                    }
+                    on_event(CommandEvent::AwaitingInput(prompt));
+                }
+            }
+            _ = &mut deadline, if paused.is_none() => {
+                let _ = child.start_kill();
+                return Ok(timed_out(command, timeout));
+            }
+            _ = &mut hard_deadline => {
+                let _ = child.start_kill();
+                return Ok(timed_out(command, hard_limit));
+            }
+        }
+    }
+    // With its output closed the command is no longer at a prompt
+    resume(&mut paused, deadline.as_mut());
+
+    if let Some(rest) = lines.take() {
+        on_event(CommandEvent::Output(rest));
+    }
+
+    let status = tokio::select! {
+        status = child.wait() => status?,
+        _ = &mut deadline => {
+            let _ = child.start_kill();
+            return Ok(timed_out(command, timeout));
+        }
+    };
+
+    let mut output = decode_output(&output, env.output_encoding)
+        .trim()
+        .to_string();
+    // The output has already been streamed, so the note is sent as a line too
+    if let Some(note) = missing_program_note(command, status.code(), &output) {
+        on_event(CommandEvent::Output(note));
+    }
+    explain_missing_program(command, status.code(), &mut output);
+
+    Ok(CommandRun {
+        command: command.to_string(),
+        exit_code: status.code(),
+        success: status.success(),
+        output,
+        diff: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn env() -> ShellEnv {
+        ShellEnv::new(std::env::current_dir().unwrap())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_execution_limit() {
+        // How long two commands take when started together under `max`
+        let run_pair = |max: usize, first: &'static str, timeout: Duration| async move {
+            let mut env = env();
+            env.execution_limit = Some(ExecutionLimit::new(max));
+            let started = std::time::Instant::now();
+            let (a, b) = tokio::join!(
+                run_shell_command(first, &env, TIMEOUT),
+                run_shell_command("sleep 0.3 && echo second", &env, timeout)
+            );
+            (started.elapsed(), a.unwrap(), b.unwrap())
+        };
+
+        let (elapsed, _, _) = run_pair(1, "sleep 0.3", TIMEOUT).await;
+        assert!(elapsed >= Duration::from_millis(550), "{:?}", elapsed);
+        let (elapsed, _, _) = run_pair(2, "sleep 0.3", TIMEOUT).await;
+        assert!(elapsed < Duration::from_millis(550), "{:?}", elapsed);
+
+        // Time spent queued doesn't count towards the timeout
+        let (_, first, second) = run_pair(1, "sleep 0.6", Duration::from_millis(500)).await;
+        assert!(first.success);
+        assert!(second.success, "{}", second.output);
+        assert_eq!(second.output, "second");
+    }
+
+    #[tokio::test]
+    async fn test_successful_command() {
+        let run = run_shell_command("echo hello", &env(), TIMEOUT)
+            .await
+            .unwrap();
+        assert!(run.success);
+        assert_eq!(run.exit_code, Some(0));
+        assert_eq!(run.output, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_failing_command_reports_exit_code() {
+        let run = run_shell_command("exit 3", &env(), TIMEOUT).await.unwrap();
+        assert!(!run.success);
+        assert_eq!(run.exit_code, Some(3));
+        assert_eq!(run.failure_message(), "Command failed with exit code 3");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_missing_interpreter_is_explained() {
+        let run = run_shell_command("definitely-not-python-xyz script.py", &env(), TIMEOUT)
+            .await
+            .unwrap();
+        assert!(!run.success);
+        assert_eq!(run.exit_code, Some(127));
+        assert!(
+            run.output.ends_with(
+                "definitely-not-python-xyz not found on PATH; install it or check the command name"
+            ),
+            "{}",
+            run.output
+        );
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        drop(tx);
+        let mut streamed = Vec::new();
+        let run = run_interactive_command(
+            "cd . && definitely-not-python-xyz",
+            &env(),
+            TIMEOUT,
+            rx,
+            |event| {
+                if let CommandEvent::Output(line) = event {
+                    streamed.push(line);
+                }
+            },
+        )
+        .await
+        .unwrap();
+        assert!(
+            run.output
+                .contains("definitely-not-python-xyz not found on PATH"),
+            "{}",
+            run.output
+        );
+        // The terminal shows output as it streams, so the note is streamed
+        assert!(streamed
+            .last()
+            .is_some_and(|line| line.starts_with("definitely-not-python-xyz not found on PATH")));
+
+        // Exiting with 127 for another reason isn't a missing program
+        let run = run_shell_command("echo done; exit 127", &env(), TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(run.output, "done");
+
+        let error = std::process::Command::new("definitely-not-python-xyz")
+            .spawn()
+            .unwrap_err();
+        assert_eq!(
+            spawn_error("definitely-not-python-xyz", error).to_string(),
+            "definitely-not-python-xyz not found on PATH; install it or check the command name"
+        );
+        assert_eq!(
+            missing_program_message("/usr/bin/python3"),
+            "python3 not found on PATH; install Python or check your PATH"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hung_command_is_killed_after_timeout() {
+        let started = std::time::Instant::now();
+        let run = run_shell_command("sleep 10", &env(), Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!run.success);
+        assert_eq!(run.exit_code, None);
+        assert_eq!(
+            run.failure_message(),
+            "Command timed out after 200ms and was killed"
+        );
+    }
+
+    #[test]
+    fn test_line_endings_follow_shell() {
+        assert_eq!(LineEnding::for_shell("/bin/bash"), LineEnding::Lf);
+        assert_eq!(LineEnding::for_shell("sh"), LineEnding::Lf);
+        let windows = if cfg!(target_os = "windows") {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        };
+        assert_eq!(LineEnding::for_shell("powershell"), windows);
+        assert_eq!(
+            LineEnding::for_shell(r"C:\Windows\System32\cmd.exe"),
+            windows
+        );
+
+        assert_eq!(LineEnding::Lf.terminate("ls"), "ls\n");
+        assert_eq!(LineEnding::Crlf.terminate("dir"), "dir\r\n");
+        // Endings already present are converted, not doubled
+        assert_eq!(LineEnding::Crlf.terminate("a\nb\n"), "a\r\nb\r\n");
+        assert_eq!(LineEnding::Lf.terminate("a\r\nb\rc\r\n"), "a\nb\nc\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_interactive_command_receives_response() {
+        let (input_tx, input_rx) = mpsc::unbounded_channel();
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            run_interactive_command(
+                "printf 'Continue? [Y/n] '; read answer; echo \"got $answer\"",
+                &env(),
+                TIMEOUT,
+                input_rx,
+                move |event| {
+                    let _ = event_tx.send(event);
+                },
+            )
+            .await
+        });
+
+        assert_eq!(
+            event_rx.recv().await,
+            Some(CommandEvent::AwaitingInput("Continue? [Y/n] ".into()))
+        );
+        input_tx.send("y".into()).unwrap();
+        assert_eq!(
+            event_rx.recv().await,
+            Some(CommandEvent::Output("got y".into()))
+        );
+
+        let run = task.await.unwrap().unwrap();
+        assert!(run.success);
+        assert_eq!(run.output, "Continue? [Y/n] got y");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_prompt_waits_past_timeout() {
+        let (input_tx, input_rx) = mpsc::unbounded_channel();
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            run_interactive_command(
+                "printf 'Name: '; read name; echo \"hi $name\"",
+                &env(),
+                Duration::from_millis(300),
+                input_rx,
+                move |event| {
+                    let _ = event_tx.send(event);
+                },
+            )
+            .await
+        });
+
+        assert_eq!(
+            event_rx.recv().await,
+            Some(CommandEvent::AwaitingInput("Name: ".into()))
+        );
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        input_tx.send("ann".into()).unwrap();
+
+        let run = task.await.unwrap().unwrap();
+        assert!(run.success);
+        assert_eq!(run.output, "Name: hi ann");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_partial_line_then_hang_is_killed() {
+        let (_input_tx, input_rx) = mpsc::unbounded_channel();
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let timeout = Duration::from_millis(200);
+        let started = std::time::Instant::now();
+        let run = run_interactive_command(
+            "printf 'Working...'; sleep 9999",
+            &env(),
+            timeout,
+            input_rx,
+            move |event| {
+                let _ = event_tx.send(event);
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            event_rx.recv().await,
+            Some(CommandEvent::AwaitingInput("Working...".into()))
+        );
+        assert!(!run.success);
+        assert_eq!(run.exit_code, None);
+        assert!(started.elapsed() < timeout * PROMPT_WAIT_FACTOR * 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_interactive_command_times_out_waiting_for_input() {
+        let (_input_tx, input_rx) = mpsc::unbounded_channel();
+        let run = run_interactive_command(
+            "read answer",
+            &env(),
+            Duration::from_millis(300),
+            input_rx,
+            |_| {},
+        )
+        .await
+        .unwrap();
+        assert!(!run.success);
+        assert_eq!(run.exit_code, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_export_persists_across_commands() {
+        let mut env = env();
+        let run = run_shell_command("export X=1; echo $X", &env, TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(run.output, "1");
+
+        // A fresh shell doesn't see it unless the emulated env carries it
+        let run = run_shell_command("echo \"[$X]\"", &env, TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(run.output, "[]");
+
+        assert!(env.apply_builtins("export X=1; echo $X").is_empty());
+        let run = run_shell_command("echo $X", &env, TIMEOUT).await.unwrap();
+        assert_eq!(run.output, "1");
+
+        env.apply_builtins("unset X");
+        assert!(!env.vars.contains_key("X"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cd_and_alias_persist_across_commands() {
+        let root = std::env::temp_dir().join(format!("shell-env-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let mut env = ShellEnv::new(root.clone());
+
+        env.apply_builtins("cd sub && ls");
+        assert_eq!(env.cwd, root.join("sub"));
+        env.apply_builtins("cd ../missing");
+        assert_eq!(env.cwd, root.join("sub"));
+
+        env.apply_builtins("alias here='basename \"$(pwd)\"'");
+        let run = run_shell_command("here", &env, TIMEOUT).await.unwrap();
+        assert_eq!(run.output, "sub");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_expand_alias() {
+        let table = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(name, expansion)| (name.to_string(), expansion.to_string()))
+                .collect()
+        };
+        let configured = table(&[
+            ("gs", "g status"),
+            ("g", "git"),
+            ("ls", "ls -la"),
+            ("ping", "pong"),
+            ("pong", "ping -c 1"),
+        ]);
+        let session = table(&[("g", "git --no-pager")]);
+
+        assert_eq!(expand_alias("gs foo", &[&configured]), "git status foo");
+        assert_eq!(expand_alias("gs; ls", &[&configured]), "git status; ls");
+        assert_eq!(expand_alias("ls src", &[&configured]), "ls -la src");
+        assert_eq!(expand_alias("ping host", &[&configured]), "ping -c 1 host");
+        assert_eq!(
+            expand_alias("  cargo build", &[&configured]),
+            "  cargo build"
+        );
+        assert_eq!(
+            expand_alias("gs", &[&session, &configured]),
+            "git --no-pager status"
+        );
+    }
+
+    #[test]
+    fn test_looks_destructive() {
+        for command in [
+            "rm -rf build",
+            "sudo rm -f /etc/hosts",
+            "ls && rm --recursive tmp",
+            "rm -R build",
+            "rm -vR build",
+            "dd if=/dev/zero of=/dev/sda",
+            "sudo mkfs.ext4 /dev/sdb1",
+            "git push -f origin main",
+            "git reset --hard HEAD~3",
+            "cat image > /dev/sda",
+        ] {
+            assert!(looks_destructive(command), "{}", command);
+        }
+        for command in [
+            "rm notes.txt",
+            "ls -rf",
+            "git push origin main",
+            "git reset HEAD~1",
+            "echo 'rm -rf /'",
+            "uptime",
+        ] {
+            assert!(!looks_destructive(command), "{}", command);
+        }
+    }
+
+    #[test]
+    fn test_unemulated_builtins_warn() {
+        let mut env = env();
+        let warnings = env.apply_builtins("source venv/bin/activate; set -e");
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("`source` has no lasting effect"));
+        assert!(env.apply_builtins("echo 'source; set'").is_empty());
+    }
+
+    #[test]
+    fn test_decode_output() {
+        assert_eq!(decode_output("héllo".as_bytes(), None), "héllo");
+
+        let latin1 = b"caf\xe9";
+        assert_eq!(decode_output(latin1, None), "[binary output: 4 bytes]");
+        assert_eq!(
+            decode_output(latin1, Some(encoding_rs::WINDOWS_1252)),
+            "café"
+        );
+        // Bytes that aren't valid in the fallback either
+        assert_eq!(
+            decode_output(b"\x81 ", Some(encoding_rs::SHIFT_JIS)),
+            "[binary output: 2 bytes]"
+        );
+        assert_eq!(
+            decode_output(b"\x00\xff\x10", Some(encoding_rs::WINDOWS_1252)),
+            "[binary output: 3 bytes]"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_non_utf8_output_is_labeled() {
+        let mut env = env();
+        let run = run_shell_command("printf 'caf\\351'", &env, TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(run.output, "[binary output: 4 bytes]");
+
+        env.output_encoding = Some(encoding_rs::WINDOWS_1252);
+        let run = run_shell_command("printf 'caf\\351'", &env, TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(run.output, "café");
+    }
+}
@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+
+/// The built-in colour schemes, chosen with the `theme` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ThemePreset {
+    #[default]
+    Dark,
+    Light,
+    HighContrast,
+}
+
+/// What a piece of text is, and so which colour it gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorRole {
+    User,
+    Assistant,
+    System,
+    Error,
+    // Commands echoed in the terminal (`$ ls`)
+    Prompt,
+    // Terminal output
+    Output,
+    Success,
+    Warning,
+}
+
+/// A colour, as RGB, for every `ColorRole`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub dark: bool,
+    pub background: [u8; 3],
+    pub user: [u8; 3],
+    pub assistant: [u8; 3],
+    pub system: [u8; 3],
+    pub error: [u8; 3],
+    pub prompt: [u8; 3],
+    pub output: [u8; 3],
+    pub success: [u8; 3],
+    pub warning: [u8; 3],
+    // Colour assistant labels by provider (see `LLMProvider::theme_color`)
+    // instead of with `assistant`
+    pub provider_colors: bool,
+}
+
+impl Theme {
+    pub fn color(&self, role: ColorRole) -> [u8; 3] {
+        match role {
+            ColorRole::User => self.user,
+            ColorRole::Assistant => self.assistant,
+            ColorRole::System => self.system,
+            ColorRole::Error => self.error,
+            ColorRole::Prompt => self.prompt,
+            ColorRole::Output => self.output,
+            ColorRole::Success => self.success,
+            ColorRole::Warning => self.warning,
+        }
+    }
+}
+
+impl ThemePreset {
+    pub const ALL: [ThemePreset; 3] = [
+        ThemePreset::Dark,
+        ThemePreset::Light,
+        ThemePreset::HighContrast,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ThemePreset::Dark => "Dark",
+            ThemePreset::Light => "Light",
+            ThemePreset::HighContrast => "High contrast",
+        }
+    }
+
+    pub fn theme(&self) -> Theme {
+        match self {
+            ThemePreset::Dark => Theme {
+                dark: true,
+                background: [27, 27, 27],
+                user: [173, 216, 230],
+                assistant: [217, 119, 87],
+                system: [160, 160, 160],
+                error: [255, 128, 128],
+                prompt: [160, 160, 160],
+                output: [255, 255, 255],
+                success: [144, 238, 144],
+                warning: [255, 255, 0],
+                provider_colors: true,
+            },
+            ThemePreset::Light => Theme {
+                dark: false,
+                background: [248, 248, 248],
+                user: [0, 90, 170],
+                assistant: [170, 70, 40],
+                system: [100, 100, 100],
+                error: [190, 30, 30],
+                prompt: [70, 70, 140],
+                output: [20, 20, 20],
+                success: [20, 120, 40],
+                warning: [150, 100, 0],
+                provider_colors: true,
+            },
+            // Provider colours are too dim against black, so one strong
+            // colour is used for every assistant
+            ThemePreset::HighContrast => Theme {
+                dark: true,
+                background: [0, 0, 0],
+                user: [0, 255, 255],
+                assistant: [255, 170, 0],
+                system: [230, 230, 230],
+                error: [255, 120, 120],
+                prompt: [255, 255, 255],
+                output: [255, 255, 255],
+                success: [0, 255, 0],
+                warning: [255, 255, 0],
+                provider_colors: false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROLES: [ColorRole; 8] = [
+        ColorRole::User,
+        ColorRole::Assistant,
+        ColorRole::System,
+        ColorRole::Error,
+        ColorRole::Prompt,
+        ColorRole::Output,
+        ColorRole::Success,
+        ColorRole::Warning,
+    ];
+
+    // WCAG contrast ratio between two colours
+    fn contrast(a: [u8; 3], b: [u8; 3]) -> f64 {
+        let luminance = |[r, g, b]: [u8; 3]| {
+            let linear = |c: u8| {
+                let c = c as f64 / 255.0;
+                if c <= 0.03928 {
+                    c / 12.92
+                } else {
+                    ((c + 0.055) / 1.055).powf(2.4)
+                }
+            };
+            0.2126 * linear(r) + 0.7152 * linear(g) + 0.0722 * linear(b)
+        };
+        let (a, b) = (luminance(a), luminance(b));
+        (a.max(b) + 0.05) / (a.min(b) + 0.05)
+    }
+
+    #[test]
+    fn test_every_role_is_readable() {
+        for preset in ThemePreset::ALL {
+            let theme = preset.theme();
+            let minimum = match preset {
+                ThemePreset::HighContrast => 7.0,
+                _ => 3.0,
+            };
+            for role in ROLES {
+                let ratio = contrast(theme.color(role), theme.background);
+                assert!(ratio >= minimum, "{:?} {:?}: {:.1}", preset, role, ratio);
+            }
+        }
+    }
+
+    #[test]
+    fn test_presets_differ() {
+        let dark = ThemePreset::Dark.theme();
+        let light = ThemePreset::Light.theme();
+        assert_ne!(
+            dark.color(ColorRole::Output),
+            light.color(ColorRole::Output)
+        );
+        assert_ne!(
+            dark.color(ColorRole::User),
+            ThemePreset::HighContrast.theme().color(ColorRole::User)
+        );
+        assert!(dark.dark && !light.dark);
+    }
+}
@@ -1,24 +1,246 @@
+use crate::models::CommandRun;
 use crate::terminal::emulator::TerminalEmulator;
-use anyhow::Result;
+use crate::terminal::shell::{run_shell_command, ExecutionLimit, ShellEnv};
+use anyhow::{anyhow, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::fmt;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+// Longest a command in a parallel group may run before it's killed
+const DEFAULT_PARALLEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One step of a workflow.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WorkflowStep {
+    /// A command sent to the terminal.
+    Command(String),
+    /// Independent commands run at the same time, each in its own shell.
+    /// The workflow moves on once all of them have finished.
+    Parallel(Vec<String>),
+}
+
+impl From<String> for WorkflowStep {
+    fn from(command: String) -> Self {
+        WorkflowStep::Command(command)
+    }
+}
+
+impl From<&str> for WorkflowStep {
+    fn from(command: &str) -> Self {
+        WorkflowStep::Command(command.to_string())
+    }
+}
+
+/// What happens when a command in a parallel group fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FailurePolicy {
+    /// Let the rest of the group finish, then stop the workflow.
+    #[default]
+    StopAfterGroup,
+    /// Kill the rest of the group at once and stop the workflow.
+    CancelGroup,
+    /// Carry on with the next step.
+    Continue,
+}
+
+/// A workflow stopped through its `CancellationToken`. Travels inside the
+/// `anyhow::Error` returned by `Workflow::run`; use
+/// `err.downcast_ref::<WorkflowCancelled>()` to see how far it got.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkflowCancelled {
+    /// How many steps finished before the cancellation.
+    pub completed: usize,
+    pub total: usize,
+    /// Results from the parallel groups that finished.
+    pub runs: Vec<CommandRun>,
+}
+
+impl fmt::Display for WorkflowCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Workflow cancelled after {} of {} steps",
+            self.completed, self.total
+        )
+    }
+}
+
+impl std::error::Error for WorkflowCancelled {}
 
 pub struct Workflow {
     pub name: String,
-    pub steps: Vec<String>,
+    pub steps: Vec<WorkflowStep>,
+    pub failure_policy: FailurePolicy,
+    pub parallel_timeout: Duration,
+    // Shared cap on how many commands of a parallel group run at once
+    pub execution_limit: Option<ExecutionLimit>,
 }
 
 impl Workflow {
-    pub fn new(name: impl Into<String>, steps: Vec<String>) -> Self {
+    pub fn new(name: impl Into<String>, steps: Vec<WorkflowStep>) -> Self {
         Self {
             name: name.into(),
             steps,
+            failure_policy: FailurePolicy::default(),
+            parallel_timeout: DEFAULT_PARALLEL_TIMEOUT,
+            execution_limit: None,
+        }
+    }
+
+    pub fn with_execution_limit(mut self, execution_limit: ExecutionLimit) -> Self {
+        self.execution_limit = Some(execution_limit);
+        self
+    }
+
+    pub fn with_failure_policy(mut self, failure_policy: FailurePolicy) -> Self {
+        self.failure_policy = failure_policy;
+        self
+    }
+
+    /// Run the steps in the terminal one after another, waiting for each to
+    /// finish before sending the next. Parallel groups run outside the
+    /// terminal, in the session's working directory; their results are
+    /// added to the session's history and returned, in the order listed.
+    ///
+    /// A failing group is an error unless the policy is `Continue`.
+    ///
+    /// Cancelling `cancel` stops the workflow before its next step, or during
+    /// a step: a terminal command is stopped by restarting the session's
+    /// shell, and a parallel group's commands are killed. The error is then
+    /// a `WorkflowCancelled`.
+    pub async fn run(
+        &self,
+        terminal: &mut TerminalEmulator,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<CommandRun>> {
+        let mut results = Vec::new();
+        for (completed, step) in self.steps.iter().enumerate() {
+            if cancel.is_cancelled() {
+                return Err(self.cancelled(terminal, completed, &results));
+            }
+            match step {
+                WorkflowStep::Command(command) => {
+                    terminal.execute_command(command).await?;
+                    let stopped = tokio::select! {
+                        _ = terminal.wait_for_idle() => false,
+                        _ = cancel.cancelled() => true,
+                    };
+                    if stopped {
+                        // The command won't report back; a new shell leaves
+                        // nothing running
+                        terminal.restart_session(terminal.active_session)?;
+                        return Err(self.cancelled(terminal, completed, &results));
+                    }
+                }
+                WorkflowStep::Parallel(commands) => {
+                    let cwd = terminal
+                        .get_active_session()
+                        .map(|session| session.working_directory.clone())
+                        .unwrap_or_else(|| std::path::PathBuf::from("."));
+                    let mut env = ShellEnv::new(cwd);
+                    env.execution_limit = self.execution_limit.clone();
+                    // Dropping the group's futures kills its commands
+                    let runs = tokio::select! {
+                        runs = self.run_group(commands, &env) => runs,
+                        _ = cancel.cancelled() => {
+                            return Err(self.cancelled(terminal, completed, &results));
+                        }
+                    };
+                    if let Some(session) = terminal.get_active_session_mut() {
+                        for run in &runs {
+                            session.add_command(run.command.clone());
+                            if run.success {
+                                session.add_output(run.output.clone());
+                            } else {
+                                session.add_error(run.output.clone());
+                            }
+                        }
+                    }
+
+                    let failed: Vec<&str> = runs
+                        .iter()
+                        .filter(|run| !run.success)
+                        .map(|run| run.command.as_str())
+                        .collect();
+                    let stop = !failed.is_empty() && self.failure_policy != FailurePolicy::Continue;
+                    let error = anyhow!(
+                        "{} of {} parallel commands failed: {}",
+                        failed.len(),
+                        commands.len(),
+                        failed.join(", ")
+                    );
+                    results.extend(runs);
+                    if stop {
+                        return Err(error);
+                    }
+                }
+            }
         }
+        Ok(results)
     }
 
-    pub async fn run(&self, terminal: &mut TerminalEmulator) -> Result<()> {
-        for step in &self.steps {
-            terminal.execute_command(step).await?;
+    // The error for a run cancelled after `completed` steps, noted in the
+    // terminal
+    fn cancelled(
+        &self,
+        terminal: &mut TerminalEmulator,
+        completed: usize,
+        runs: &[CommandRun],
+    ) -> anyhow::Error {
+        let cancelled = WorkflowCancelled {
+            completed,
+            total: self.steps.len(),
+            runs: runs.to_vec(),
+        };
+        if let Some(session) = terminal.get_active_session_mut() {
+            session.add_system_message(cancelled.to_string());
         }
-        Ok(())
+        anyhow::Error::new(cancelled)
+    }
+
+    // Run `commands` concurrently, returning their results in the order
+    // given. Commands cancelled by the failure policy are reported as failed
+    async fn run_group(&self, commands: &[String], env: &ShellEnv) -> Vec<CommandRun> {
+        let mut pending: FuturesUnordered<_> = commands
+            .iter()
+            .enumerate()
+            .map(|(i, command)| async move {
+                let run = run_shell_command(command, env, self.parallel_timeout)
+                    .await
+                    .unwrap_or_else(|error| CommandRun {
+                        command: command.clone(),
+                        exit_code: None,
+                        success: false,
+                        output: format!("Failed to start: {}", error),
+                        diff: None,
+                    });
+                (i, run)
+            })
+            .collect();
+
+        let mut runs: Vec<Option<CommandRun>> = vec![None; commands.len()];
+        while let Some((i, run)) = pending.next().await {
+            let failed = !run.success;
+            runs[i] = Some(run);
+            if failed && self.failure_policy == FailurePolicy::CancelGroup {
+                // Dropping the unfinished futures kills their processes
+                break;
+            }
+        }
+
+        runs.into_iter()
+            .zip(commands)
+            .map(|(run, command)| {
+                run.unwrap_or_else(|| CommandRun {
+                    command: command.clone(),
+                    exit_code: None,
+                    success: false,
+                    output: "Cancelled after another command failed".to_string(),
+                    diff: None,
+                })
+            })
+            .collect()
     }
 }
 
@@ -31,6 +253,122 @@ mod tests {
     async fn test_workflow_run_empty() {
         let mut term = TerminalEmulator::new();
         let wf = Workflow::new("test", vec![]);
-        assert!(wf.run(&mut term).await.is_ok());
+        assert!(wf.run(&mut term, &CancellationToken::new()).await.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_workflow_steps_run_in_sequence() {
+        let mut term = TerminalEmulator::new();
+        let wf = Workflow::new("test", vec!["echo one".into(), "echo two".into()]);
+        let runs = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            wf.run(&mut term, &CancellationToken::new()),
+        )
+        .await
+        .expect("workflow should finish")
+        .unwrap();
+        assert!(runs.is_empty());
+
+        // Each step's output arrives before the next step is sent
+        let session = term.get_active_session().unwrap();
+        let lines: Vec<&str> = session.history.iter().map(|l| l.content.as_str()).collect();
+        assert!(lines.ends_with(&["$ echo one", "one", "$ echo two", "two"]));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cancelled_workflow_stops_after_current_step() {
+        let mut term = TerminalEmulator::new();
+        let wf = Workflow::new(
+            "build",
+            vec!["echo one".into(), "sleep 5".into(), "echo three".into()],
+        );
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            canceller.cancel();
+        });
+
+        let started = std::time::Instant::now();
+        let error = wf.run(&mut term, &cancel).await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(4));
+        let cancelled = error.downcast_ref::<WorkflowCancelled>().unwrap();
+        assert_eq!((cancelled.completed, cancelled.total), (1, 3));
+        assert_eq!(error.to_string(), "Workflow cancelled after 1 of 3 steps");
+
+        let session = term.get_active_session().unwrap();
+        let lines: Vec<&str> = session.history.iter().map(|l| l.content.as_str()).collect();
+        assert!(lines.contains(&"one"));
+        assert!(!lines.contains(&"$ echo three"));
+        assert!(!session.is_command_running());
+        assert_eq!(lines.last(), Some(&"Workflow cancelled after 1 of 3 steps"));
+
+        // A new run in the same terminal works, and a token cancelled up
+        // front runs nothing
+        let wf = Workflow::new("after", vec!["echo again".into()]);
+        wf.run(&mut term, &CancellationToken::new()).await.unwrap();
+        let error = wf.run(&mut term, &cancel).await.unwrap_err();
+        let cancelled = error.downcast_ref::<WorkflowCancelled>().unwrap();
+        assert_eq!(cancelled.completed, 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_parallel_steps_overlap() {
+        let mut term = TerminalEmulator::new();
+        let wf = Workflow::new(
+            "setup",
+            vec![WorkflowStep::Parallel(vec![
+                "sleep 0.5 && echo first".into(),
+                "sleep 0.5 && echo second".into(),
+                "sleep 0.5 && echo third".into(),
+            ])],
+        );
+
+        let started = std::time::Instant::now();
+        let runs = wf.run(&mut term, &CancellationToken::new()).await.unwrap();
+        // One after another would take at least 1.5s
+        assert!(started.elapsed() < std::time::Duration::from_millis(1_200));
+
+        let outputs: Vec<&str> = runs.iter().map(|run| run.output.as_str()).collect();
+        assert_eq!(outputs, vec!["first", "second", "third"]);
+        assert!(runs.iter().all(|run| run.success));
+        let session = term.get_active_session().unwrap();
+        let lines: Vec<&str> = session.history.iter().map(|l| l.content.as_str()).collect();
+        assert!(lines.ends_with(&["$ sleep 0.5 && echo third", "third"]));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_parallel_failure_policies() {
+        let group = || {
+            vec![WorkflowStep::Parallel(vec![
+                "exit 3".into(),
+                "sleep 1 && echo slow".into(),
+            ])]
+        };
+
+        let mut term = TerminalEmulator::new();
+        let wf = Workflow::new("wait", group());
+        let error = wf
+            .run(&mut term, &CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "1 of 2 parallel commands failed: exit 3");
+
+        let wf = Workflow::new("continue", group()).with_failure_policy(FailurePolicy::Continue);
+        let runs = wf.run(&mut term, &CancellationToken::new()).await.unwrap();
+        assert_eq!(runs[0].exit_code, Some(3));
+        assert_eq!(runs[1].output, "slow");
+
+        let wf = Workflow::new("cancel", group()).with_failure_policy(FailurePolicy::CancelGroup);
+        let started = std::time::Instant::now();
+        assert!(wf.run(&mut term, &CancellationToken::new()).await.is_err());
+        assert!(started.elapsed() < std::time::Duration::from_millis(900));
+        let session = term.get_active_session().unwrap();
+        let last = session.history.back().unwrap();
+        assert_eq!(last.content, "Cancelled after another command failed");
     }
 }